@@ -56,9 +56,10 @@ impl <R: Read> AsyncBufReader<R> {
         }
     }
 
+    // The returned slice includes the delimiter, as `BufRead::read_until()` does
     pub fn nb_read_until(&mut self, byte: u8, limit: usize) -> Poll<&[u8], io::Error> {
         if let Some(idx) = self.buf.get_ref().iter().position(|&c| c == byte) {
-            return Ok(Async::Ready(self.buf.consume_and_get(idx)));
+            return Ok(Async::Ready(self.buf.consume_and_get(idx + 1)));
         } else {
             self.buf.reserve(limit);
             if let Err(e) = self.fill_buf_no_eof() {
@@ -70,7 +71,7 @@ impl <R: Read> AsyncBufReader<R> {
             }
 
             if let Some(idx) = self.buf.get_ref().iter().position(|&c| c == byte) {
-                Ok(Async::Ready(self.buf.consume_and_get(idx)))
+                Ok(Async::Ready(self.buf.consume_and_get(idx + 1)))
             } else if self.buf.data_size() < limit {
                 Ok(Async::NotReady)
             } else {
@@ -215,6 +216,15 @@ mod test {
         assert_eq!(Async::Ready(b"world!".as_ref()), bufreader.nb_read_exact(6).expect("error!"));
     }
 
+    #[test]
+    fn read_until() {
+        let reader = Cursor::new(b"Hello\r\nworld\r\n");
+        let mut bufreader = AsyncBufReader::with_capacity(16, reader);
+
+        assert_eq!(Async::Ready(b"Hello\r\n".as_ref()), bufreader.nb_read_until(b'\n', 16).expect("error!"));
+        assert_eq!(Async::Ready(b"world\r\n".as_ref()), bufreader.nb_read_until(b'\n', 16).expect("error!"));
+    }
+
     #[test]
     fn read() {
         let reader = Cursor::new(b"Hello, world!");
@@ -10,6 +10,7 @@ use rssh::async::bufreader::AsyncBufReader;
 use rssh::async::bufwriter::AsyncBufWriter;
 use rssh::packet::types::{AlgorithmNegotiation, KexAlgorithm, ServerHostKeyAlgorithm, EncryptionAlgorithm, MacAlgorithm, CompressionAlgorithm};
 
+use std::env;
 use std::net::SocketAddr;
 
 use futures::Future;
@@ -18,7 +19,7 @@ use tokio_core::net::TcpStream;
 use tokio_core::reactor::Core;
 
 fn main() {
-    let addr = "127.0.0.1:3022".parse::<SocketAddr>().unwrap();
+    let addr = env::args().nth(1).unwrap_or("127.0.0.1:2022".to_string()).parse::<SocketAddr>().unwrap();
 
     let mut l = Core::new().unwrap();
     let handle = l.handle();
@@ -37,24 +38,26 @@ fn main() {
             "RSSHS_0.1.0",
             "Hello"
         )
-    }).and_then(|(reader, writer, (v_c, v_s))| {
+    }).and_then(|(reader, writer, (v_c, peer))| {
+        println!("server version: {} {}", peer.proto, peer.software);
         let supported_algorithms = AlgorithmNegotiation {
-            kex_algorithms: vec![KexAlgorithm::CURVE25519_SHA256],
-            server_host_key_algorithms: vec![ServerHostKeyAlgorithm::SSH_RSA],
-            encryption_algorithms_client_to_server: vec![EncryptionAlgorithm::AES256_CTR],
-            encryption_algorithms_server_to_client: vec![EncryptionAlgorithm::AES256_CTR],
-            mac_algorithms_client_to_server: vec![MacAlgorithm::HMAC_SHA2_256],
-            mac_algorithms_server_to_client: vec![MacAlgorithm::HMAC_SHA2_256],
-            compression_algorithms_client_to_server: vec![CompressionAlgorithm::NONE],
-            compression_algorithms_server_to_client: vec![CompressionAlgorithm::NONE],
+            kex_algorithms: vec![KexAlgorithm::MLKEM768_X25519_SHA256, KexAlgorithm::SNTRUP761_X25519_SHA512, KexAlgorithm::CURVE25519_SHA256, KexAlgorithm::ECDH_SHA2_NISTP256, KexAlgorithm::ECDH_SHA2_NISTP384, KexAlgorithm::ECDH_SHA2_NISTP521, KexAlgorithm::DH_GROUP16_SHA512, KexAlgorithm::DH_GROUP14_SHA256, KexAlgorithm::DH_GEX_SHA256],
+            server_host_key_algorithms: vec![ServerHostKeyAlgorithm::SSH_ED25519, ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256, ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384, ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP521, ServerHostKeyAlgorithm::RSA_SHA2_512, ServerHostKeyAlgorithm::RSA_SHA2_256],
+            encryption_algorithms_client_to_server: vec![EncryptionAlgorithm::CHACHA20_POLY1305, EncryptionAlgorithm::AES256_GCM, EncryptionAlgorithm::AES256_CTR],
+            encryption_algorithms_server_to_client: vec![EncryptionAlgorithm::CHACHA20_POLY1305, EncryptionAlgorithm::AES256_GCM, EncryptionAlgorithm::AES256_CTR],
+            mac_algorithms_client_to_server: vec![MacAlgorithm::HMAC_SHA2_256_ETM, MacAlgorithm::HMAC_SHA2_512_ETM, MacAlgorithm::HMAC_SHA2_256],
+            mac_algorithms_server_to_client: vec![MacAlgorithm::HMAC_SHA2_256_ETM, MacAlgorithm::HMAC_SHA2_512_ETM, MacAlgorithm::HMAC_SHA2_256],
+            compression_algorithms_client_to_server: vec![CompressionAlgorithm::ZLIB_OPENSSH, CompressionAlgorithm::NONE],
+            compression_algorithms_server_to_client: vec![CompressionAlgorithm::ZLIB_OPENSSH, CompressionAlgorithm::NONE],
             languages_client_to_server: vec![],
             languages_server_to_client: vec![],
-            first_kex_packet_follows: false,
+            first_kex_packet_follows: true,
             reserved: 0
         };
 
-        rssh::handshake::client_key_exchange(reader, writer, supported_algorithms, v_c, v_s)
-    }).map(|(r, w, ctx)| {
+        futures::done(rssh::handshake::client_key_exchange(reader, writer, supported_algorithms, v_c, peer.ident().to_string()))
+            .flatten()
+    }).map(|(_stream, ctx)| {
         println!("server key verified!");
         println!("ctx: {:?}", ctx);
     }).map_err(|e| {
@@ -6,8 +6,15 @@ extern crate tokio_core;
 
 use rssh::async::bufreader::AsyncBufReader;
 use rssh::async::bufwriter::AsyncBufWriter;
+use rssh::dh;
+use rssh::hostkey::HostKey;
+use rssh::packet::types::{AlgorithmNegotiation, KexAlgorithm, ServerHostKeyAlgorithm, EncryptionAlgorithm, MacAlgorithm, CompressionAlgorithm};
 
+use std::env;
+use std::fs::File;
+use std::io::Read;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use futures::Future;
 use futures::stream::Stream;
@@ -15,8 +22,31 @@ use tokio_core::io::Io;
 use tokio_core::net::TcpListener;
 use tokio_core::reactor::Core;
 
+// Either a 32 byte Ed25519 seed, e.g. `head -c 32 /dev/urandom > host_ed25519_key`, a PKCS#1 RSA
+// private key in DER, e.g. `openssl rsa -in host_rsa_key -outform DER -out host_rsa_key.der`, or a
+// SEC1 ECDSA private key in DER, e.g. `openssl ec -in host_ecdsa_key -outform DER -out host_ecdsa_key.der`
+fn load_host_key(path: &str) -> HostKey {
+    let mut key = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut key)).expect("cannot read the host key");
+    if key.len() == 32 {
+        HostKey::ed25519_from_seed(&key).expect("invalid host key")
+    } else {
+        HostKey::rsa_from_der(&key).or_else(|_| HostKey::ecdsa_from_der(&key)).expect("invalid host key")
+    }
+}
+
 fn main() {
     let addr = "0.0.0.0:2022".parse::<SocketAddr>().unwrap();
+    let key_path = env::args().nth(1).unwrap_or("host_rsa_key.der".to_string());
+    let host_key = Arc::new(load_host_key(&key_path));
+    // Groups for diffie-hellman-group-exchange, e.g. /etc/ssh/moduli
+    let moduli = Arc::new(match env::args().nth(2) {
+        Some(path) => dh::load_moduli(&path).unwrap_or_else(|e| {
+            println!("Cannot use the moduli file {}: {}, falling back to the built-in groups", path, e);
+            dh::default_moduli()
+        }),
+        None => dh::default_moduli()
+    });
 
     let mut l = Core::new().unwrap();
     let handle = l.handle();
@@ -27,6 +57,8 @@ fn main() {
 
     let done = socket.incoming().for_each(move |(socket, addr)| {
         println!("New connection from: {}", addr);
+        let host_key = host_key.clone();
+        let moduli = moduli.clone();
         let pair = futures::lazy(|| futures::finished(socket.split()));
         let msg = pair.and_then(|(reader, writer)| {
             rssh::handshake::version_exchange(
@@ -35,10 +67,33 @@ fn main() {
                 "RSSHS_0.1.0",
                 "Hello"
             )
-        }).map(|(_, _, buf)| {
-            println!("got key exchange: {:?}", buf);
+        }).and_then(move |(reader, writer, (v_s, peer))| {
+            println!("client version: {} {}", peer.proto, peer.software);
+            let supported_algorithms = AlgorithmNegotiation {
+                kex_algorithms: vec![KexAlgorithm::MLKEM768_X25519_SHA256, KexAlgorithm::SNTRUP761_X25519_SHA512, KexAlgorithm::CURVE25519_SHA256, KexAlgorithm::ECDH_SHA2_NISTP256, KexAlgorithm::ECDH_SHA2_NISTP384, KexAlgorithm::ECDH_SHA2_NISTP521, KexAlgorithm::DH_GROUP16_SHA512, KexAlgorithm::DH_GROUP14_SHA256, KexAlgorithm::DH_GEX_SHA256],
+                server_host_key_algorithms: vec![ServerHostKeyAlgorithm::SSH_ED25519, ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256, ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384, ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP521, ServerHostKeyAlgorithm::RSA_SHA2_512, ServerHostKeyAlgorithm::RSA_SHA2_256],
+                encryption_algorithms_client_to_server: vec![EncryptionAlgorithm::CHACHA20_POLY1305, EncryptionAlgorithm::AES256_GCM, EncryptionAlgorithm::AES256_CTR],
+                encryption_algorithms_server_to_client: vec![EncryptionAlgorithm::CHACHA20_POLY1305, EncryptionAlgorithm::AES256_GCM, EncryptionAlgorithm::AES256_CTR],
+                mac_algorithms_client_to_server: vec![MacAlgorithm::HMAC_SHA2_256_ETM, MacAlgorithm::HMAC_SHA2_512_ETM, MacAlgorithm::HMAC_SHA2_256],
+                mac_algorithms_server_to_client: vec![MacAlgorithm::HMAC_SHA2_256_ETM, MacAlgorithm::HMAC_SHA2_512_ETM, MacAlgorithm::HMAC_SHA2_256],
+                compression_algorithms_client_to_server: vec![CompressionAlgorithm::ZLIB_OPENSSH, CompressionAlgorithm::NONE],
+                compression_algorithms_server_to_client: vec![CompressionAlgorithm::ZLIB_OPENSSH, CompressionAlgorithm::NONE],
+                languages_client_to_server: vec![],
+                languages_server_to_client: vec![],
+                first_kex_packet_follows: false,
+                reserved: 0
+            };
+
+            let kex = rssh::handshake::server_key_exchange(reader, writer, supported_algorithms, host_key, peer.ident().to_string(), v_s);
+            futures::done(kex.map(|mut kex| {
+                kex.state_mut().set_moduli(moduli);
+                kex
+            })).flatten()
+        }).map(|(_stream, ctx)| {
+            println!("key exchange finished");
+            println!("ctx: {:?}", ctx);
         }).map_err(|e| {
-            panic!("error: {}", e);
+            println!("error: {}", e);
         });
 
         handle.spawn(msg);
@@ -0,0 +1,741 @@
+use packet::types::{EncryptionAlgorithm, MacAlgorithm};
+use transport::{hton, ntoh};
+
+use crypto::aes::{self, KeySize};
+use crypto::chacha20::ChaCha20;
+use crypto::mac::Mac;
+use crypto::poly1305::Poly1305;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use ring::{aead, constant_time, digest, hmac};
+
+pub trait PacketSealer {
+    fn block_size(&self) -> usize;
+
+    fn tag_len(&self) -> usize;
+
+    // Number of leading bytes which are authenticated but left out of the block alignment
+    fn aad_len(&self) -> usize {
+        0
+    }
+
+    // `pkt` contains the whole packet starting from the length field,
+    // followed by `tag_len()` bytes reserved for the MAC
+    fn seal(&mut self, seq: u32, pkt: &mut [u8]);
+}
+
+pub trait PacketOpener {
+    fn block_size(&self) -> usize;
+
+    fn tag_len(&self) -> usize;
+
+    // Number of bytes required to decode the packet length
+    fn header_len(&self) -> usize;
+
+    // Number of leading bytes which are authenticated but left out of the block alignment
+    fn aad_len(&self) -> usize {
+        0
+    }
+
+    fn decrypt_length(&mut self, seq: u32, header: &mut [u8]) -> u32;
+
+    // `pkt` contains the whole packet including the header passed to
+    // `decrypt_length()` and the MAC
+    fn open(&mut self, seq: u32, pkt: &mut [u8]) -> Result<(), ()>;
+}
+
+pub struct NoCipher;
+
+impl PacketSealer for NoCipher {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn tag_len(&self) -> usize {
+        0
+    }
+
+    fn seal(&mut self, _seq: u32, _pkt: &mut [u8]) {
+    }
+}
+
+impl PacketOpener for NoCipher {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn tag_len(&self) -> usize {
+        0
+    }
+
+    fn header_len(&self) -> usize {
+        8
+    }
+
+    fn decrypt_length(&mut self, _seq: u32, header: &mut [u8]) -> u32 {
+        ntoh(header)
+    }
+
+    fn open(&mut self, _seq: u32, _pkt: &mut [u8]) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+fn process_in_place(cipher: &mut SynchronousStreamCipher, buf: &mut [u8]) {
+    let input = buf.to_vec();
+    cipher.process(&input, buf);
+}
+
+fn sign(mac_key: &hmac::SigningKey, seq: u32, data: &[u8]) -> hmac::Signature {
+    let mut ctx = hmac::SigningContext::with_key(mac_key);
+    ctx.update(&hton(seq));
+    ctx.update(data);
+    ctx.sign()
+}
+
+pub struct EncryptAndMac {
+    cipher: Box<SynchronousStreamCipher>,
+    block_size: usize,
+    mac_key: hmac::SigningKey,
+    mac_len: usize
+}
+
+impl PacketSealer for EncryptAndMac {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn tag_len(&self) -> usize {
+        self.mac_len
+    }
+
+    fn seal(&mut self, seq: u32, pkt: &mut [u8]) {
+        let data_len = pkt.len() - self.mac_len;
+        let tag = sign(&self.mac_key, seq, &pkt[.. data_len]);
+        process_in_place(&mut *self.cipher, &mut pkt[.. data_len]);
+        pkt[data_len ..].copy_from_slice(tag.as_ref());
+    }
+}
+
+impl PacketOpener for EncryptAndMac {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn tag_len(&self) -> usize {
+        self.mac_len
+    }
+
+    fn header_len(&self) -> usize {
+        self.block_size
+    }
+
+    fn decrypt_length(&mut self, _seq: u32, header: &mut [u8]) -> u32 {
+        process_in_place(&mut *self.cipher, header);
+        ntoh(header)
+    }
+
+    fn open(&mut self, seq: u32, pkt: &mut [u8]) -> Result<(), ()> {
+        let data_len = pkt.len() - self.mac_len;
+        process_in_place(&mut *self.cipher, &mut pkt[self.block_size .. data_len]);
+        let tag = sign(&self.mac_key, seq, &pkt[.. data_len]);
+        constant_time::verify_slices_are_equal(tag.as_ref(), &pkt[data_len ..])
+    }
+}
+
+// The packet length stays in plaintext, and the MAC is computed over the ciphertext
+pub struct EncryptThenMac {
+    cipher: Box<SynchronousStreamCipher>,
+    block_size: usize,
+    mac_key: hmac::SigningKey,
+    mac_len: usize
+}
+
+impl PacketSealer for EncryptThenMac {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn tag_len(&self) -> usize {
+        self.mac_len
+    }
+
+    fn aad_len(&self) -> usize {
+        4
+    }
+
+    fn seal(&mut self, seq: u32, pkt: &mut [u8]) {
+        let data_len = pkt.len() - self.mac_len;
+        process_in_place(&mut *self.cipher, &mut pkt[4 .. data_len]);
+        let tag = sign(&self.mac_key, seq, &pkt[.. data_len]);
+        pkt[data_len ..].copy_from_slice(tag.as_ref());
+    }
+}
+
+impl PacketOpener for EncryptThenMac {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn tag_len(&self) -> usize {
+        self.mac_len
+    }
+
+    fn header_len(&self) -> usize {
+        4
+    }
+
+    fn aad_len(&self) -> usize {
+        4
+    }
+
+    fn decrypt_length(&mut self, _seq: u32, header: &mut [u8]) -> u32 {
+        ntoh(header)
+    }
+
+    fn open(&mut self, seq: u32, pkt: &mut [u8]) -> Result<(), ()> {
+        let data_len = pkt.len() - self.mac_len;
+        let tag = sign(&self.mac_key, seq, &pkt[.. data_len]);
+        try!(constant_time::verify_slices_are_equal(tag.as_ref(), &pkt[data_len ..]));
+        process_in_place(&mut *self.cipher, &mut pkt[4 .. data_len]);
+        Ok(())
+    }
+}
+
+pub struct AesGcmSealer {
+    key: aead::SealingKey,
+    nonce: [u8; 12]
+}
+
+pub struct AesGcmOpener {
+    key: aead::OpeningKey,
+    nonce: [u8; 12]
+}
+
+fn gcm_nonce(iv: &[u8]) -> Result<[u8; 12], ()> {
+    if iv.len() != 12 {
+        return Err(());
+    }
+
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(iv);
+    Ok(nonce)
+}
+
+// The last 8 bytes of the nonce are the invocation counter (RFC 5647 section 7.1)
+fn increment_invocation_counter(nonce: &mut [u8; 12]) {
+    for b in nonce[4 ..].iter_mut().rev() {
+        *b = b.wrapping_add(1);
+        if *b != 0 {
+            break;
+        }
+    }
+}
+
+impl AesGcmSealer {
+    pub fn new(key: &[u8], iv: &[u8]) -> Result<AesGcmSealer, ()> {
+        Ok(AesGcmSealer {
+            key: try!(aead::SealingKey::new(&aead::AES_256_GCM, key)),
+            nonce: try!(gcm_nonce(iv))
+        })
+    }
+}
+
+impl AesGcmOpener {
+    pub fn new(key: &[u8], iv: &[u8]) -> Result<AesGcmOpener, ()> {
+        Ok(AesGcmOpener {
+            key: try!(aead::OpeningKey::new(&aead::AES_256_GCM, key)),
+            nonce: try!(gcm_nonce(iv))
+        })
+    }
+}
+
+impl PacketSealer for AesGcmSealer {
+    fn block_size(&self) -> usize {
+        16
+    }
+
+    fn tag_len(&self) -> usize {
+        aead::AES_256_GCM.tag_len()
+    }
+
+    fn aad_len(&self) -> usize {
+        4
+    }
+
+    fn seal(&mut self, _seq: u32, pkt: &mut [u8]) {
+        let tag_len = self.tag_len();
+        {
+            let (len, in_out) = pkt.split_at_mut(4);
+            aead::seal_in_place(&self.key, &self.nonce, in_out, tag_len, len)
+                .expect("no room for the GCM tag");
+        }
+        increment_invocation_counter(&mut self.nonce);
+    }
+}
+
+impl PacketOpener for AesGcmOpener {
+    fn block_size(&self) -> usize {
+        16
+    }
+
+    fn tag_len(&self) -> usize {
+        aead::AES_256_GCM.tag_len()
+    }
+
+    fn header_len(&self) -> usize {
+        4
+    }
+
+    fn aad_len(&self) -> usize {
+        4
+    }
+
+    fn decrypt_length(&mut self, _seq: u32, header: &mut [u8]) -> u32 {
+        ntoh(header)
+    }
+
+    fn open(&mut self, _seq: u32, pkt: &mut [u8]) -> Result<(), ()> {
+        {
+            let (len, in_out) = pkt.split_at_mut(4);
+            try!(aead::open_in_place(&self.key, &self.nonce, 0, in_out, len));
+        }
+        increment_invocation_counter(&mut self.nonce);
+        Ok(())
+    }
+}
+
+// chacha20-poly1305@openssh.com, as described in PROTOCOL.chacha20poly1305 of OpenSSH
+pub struct ChaChaPoly {
+    main_key: [u8; 32],
+    header_key: [u8; 32]
+}
+
+const POLY1305_TAG_LEN: usize = 16;
+
+impl ChaChaPoly {
+    pub fn new(key: &[u8]) -> Result<ChaChaPoly, ()> {
+        if key.len() != 64 {
+            return Err(());
+        }
+
+        let mut main_key = [0u8; 32];
+        let mut header_key = [0u8; 32];
+        main_key.copy_from_slice(&key[.. 32]);
+        header_key.copy_from_slice(&key[32 ..]);
+        Ok(ChaChaPoly {
+            main_key: main_key,
+            header_key: header_key
+        })
+    }
+
+    fn nonce(seq: u32) -> [u8; 8] {
+        let mut nonce = [0u8; 8];
+        nonce[4 ..].copy_from_slice(&hton(seq));
+        nonce
+    }
+
+    fn header_cipher(&self, seq: u32) -> ChaCha20 {
+        ChaCha20::new(&self.header_key, &ChaChaPoly::nonce(seq))
+    }
+
+    // Returns the payload cipher positioned at block 1, and the Poly1305 key taken from block 0
+    fn main_cipher(&self, seq: u32) -> (ChaCha20, Poly1305) {
+        let mut cipher = ChaCha20::new(&self.main_key, &ChaChaPoly::nonce(seq));
+        let mut block = [0u8; 64];
+        process_in_place(&mut cipher, &mut block);
+        (cipher, Poly1305::new(&block[.. 32]))
+    }
+}
+
+impl PacketSealer for ChaChaPoly {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn tag_len(&self) -> usize {
+        POLY1305_TAG_LEN
+    }
+
+    fn aad_len(&self) -> usize {
+        4
+    }
+
+    fn seal(&mut self, seq: u32, pkt: &mut [u8]) {
+        let data_len = pkt.len() - POLY1305_TAG_LEN;
+        process_in_place(&mut self.header_cipher(seq), &mut pkt[.. 4]);
+
+        let (mut cipher, mut poly) = self.main_cipher(seq);
+        process_in_place(&mut cipher, &mut pkt[4 .. data_len]);
+        poly.input(&pkt[.. data_len]);
+        poly.raw_result(&mut pkt[data_len ..]);
+    }
+}
+
+impl PacketOpener for ChaChaPoly {
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn tag_len(&self) -> usize {
+        POLY1305_TAG_LEN
+    }
+
+    fn header_len(&self) -> usize {
+        4
+    }
+
+    fn aad_len(&self) -> usize {
+        4
+    }
+
+    // The encrypted length is part of the authenticated data, so it is decrypted into a copy
+    fn decrypt_length(&mut self, seq: u32, header: &mut [u8]) -> u32 {
+        let mut len = [0u8; 4];
+        self.header_cipher(seq).process(&header[.. 4], &mut len);
+        ntoh(&len)
+    }
+
+    fn open(&mut self, seq: u32, pkt: &mut [u8]) -> Result<(), ()> {
+        let data_len = pkt.len() - POLY1305_TAG_LEN;
+        let (mut cipher, mut poly) = self.main_cipher(seq);
+        let mut tag = [0u8; POLY1305_TAG_LEN];
+        poly.input(&pkt[.. data_len]);
+        poly.raw_result(&mut tag);
+        try!(constant_time::verify_slices_are_equal(&tag, &pkt[data_len ..]));
+
+        process_in_place(&mut self.header_cipher(seq), &mut pkt[.. 4]);
+        process_in_place(&mut cipher, &mut pkt[4 .. data_len]);
+        Ok(())
+    }
+}
+
+fn hmac_algorithm(mac: &MacAlgorithm) -> Result<&'static digest::Algorithm, ()> {
+    match *mac {
+        MacAlgorithm::HMAC_SHA2_256 => Ok(&digest::SHA256),
+        MacAlgorithm::HMAC_SHA2_256_ETM => Ok(&digest::SHA256),
+        MacAlgorithm::HMAC_SHA2_512_ETM => Ok(&digest::SHA512),
+        _ => Err(())
+    }
+}
+
+fn stream_cipher(enc: &EncryptionAlgorithm, iv: &[u8], key: &[u8])
+        -> Result<Box<SynchronousStreamCipher>, ()>
+{
+    match *enc {
+        EncryptionAlgorithm::AES256_CTR => Ok(aes::ctr(KeySize::KeySize256, key, iv)),
+        _ => Err(())
+    }
+}
+
+fn hmac_key(mac: &Option<MacAlgorithm>, key: &[u8]) -> Result<(hmac::SigningKey, usize), ()> {
+    let mac_alg = match *mac {
+        Some(ref mac) => try!(hmac_algorithm(mac)),
+        None => return Err(())
+    };
+
+    Ok((hmac::SigningKey::new(mac_alg, key), mac_alg.output_len))
+}
+
+fn is_etm(mac: &Option<MacAlgorithm>) -> bool {
+    match *mac {
+        Some(ref mac) => mac.is_etm(),
+        None => false
+    }
+}
+
+fn encrypt_and_mac(enc: &EncryptionAlgorithm, mac: &Option<MacAlgorithm>, iv: &[u8], key: &[u8], mac_key_bytes: &[u8])
+        -> Result<EncryptAndMac, ()>
+{
+    let (mac_key, mac_len) = try!(hmac_key(mac, mac_key_bytes));
+    Ok(EncryptAndMac {
+        cipher: try!(stream_cipher(enc, iv, key)),
+        block_size: 16,
+        mac_key: mac_key,
+        mac_len: mac_len
+    })
+}
+
+fn encrypt_then_mac(enc: &EncryptionAlgorithm, mac: &Option<MacAlgorithm>, iv: &[u8], key: &[u8], mac_key_bytes: &[u8])
+        -> Result<EncryptThenMac, ()>
+{
+    let (mac_key, mac_len) = try!(hmac_key(mac, mac_key_bytes));
+    Ok(EncryptThenMac {
+        cipher: try!(stream_cipher(enc, iv, key)),
+        block_size: 16,
+        mac_key: mac_key,
+        mac_len: mac_len
+    })
+}
+
+pub fn sealer(enc: &EncryptionAlgorithm, mac: &Option<MacAlgorithm>, iv: &[u8], key: &[u8], mac_key: &[u8])
+        -> Result<Box<PacketSealer>, ()>
+{
+    match *enc {
+        EncryptionAlgorithm::AES256_GCM => {
+            let sealer = try!(AesGcmSealer::new(key, iv));
+            Ok(Box::new(sealer))
+        },
+        EncryptionAlgorithm::CHACHA20_POLY1305 => {
+            let sealer = try!(ChaChaPoly::new(key));
+            Ok(Box::new(sealer))
+        },
+        _ if is_etm(mac) => {
+            let sealer = try!(encrypt_then_mac(enc, mac, iv, key, mac_key));
+            Ok(Box::new(sealer))
+        },
+        _ => {
+            let sealer = try!(encrypt_and_mac(enc, mac, iv, key, mac_key));
+            Ok(Box::new(sealer))
+        }
+    }
+}
+
+pub fn opener(enc: &EncryptionAlgorithm, mac: &Option<MacAlgorithm>, iv: &[u8], key: &[u8], mac_key: &[u8])
+        -> Result<Box<PacketOpener>, ()>
+{
+    match *enc {
+        EncryptionAlgorithm::AES256_GCM => {
+            let opener = try!(AesGcmOpener::new(key, iv));
+            Ok(Box::new(opener))
+        },
+        EncryptionAlgorithm::CHACHA20_POLY1305 => {
+            let opener = try!(ChaChaPoly::new(key));
+            Ok(Box::new(opener))
+        },
+        _ if is_etm(mac) => {
+            let opener = try!(encrypt_then_mac(enc, mac, iv, key, mac_key));
+            Ok(Box::new(opener))
+        },
+        _ => {
+            let opener = try!(encrypt_and_mac(enc, mac, iv, key, mac_key));
+            Ok(Box::new(opener))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::increment_invocation_counter;
+    use packet::types::{EncryptionAlgorithm, MacAlgorithm};
+    use rustc_serialize::hex::FromHex;
+
+    // 4 byte length, 1 byte padding length, 7 byte payload, 4 byte padding, 32 byte MAC
+    fn plain_packet() -> Vec<u8> {
+        let mut pkt = vec![0, 0, 0, 12, 4];
+        pkt.extend_from_slice(b"payload");
+        pkt.extend_from_slice(&[0u8; 4 + 32]);
+        pkt
+    }
+
+    fn ciphers() -> (Box<PacketSealer>, Box<PacketOpener>) {
+        ctr_ciphers(MacAlgorithm::HMAC_SHA2_256)
+    }
+
+    fn ctr_ciphers(mac: MacAlgorithm) -> (Box<PacketSealer>, Box<PacketOpener>) {
+        let enc = EncryptionAlgorithm::AES256_CTR;
+        let mac_key = vec![3u8; mac.key_len().unwrap()];
+        let mac = Some(mac);
+        let iv = [1u8; 16];
+        let key = [2u8; 32];
+        (
+            sealer(&enc, &mac, &iv, &key, &mac_key).unwrap(),
+            opener(&enc, &mac, &iv, &key, &mac_key).unwrap()
+        )
+    }
+
+    #[test]
+    fn aes256_ctr_hmac_sha2_256_roundtrip() {
+        let (mut sealer, mut opener) = ciphers();
+
+        for seq in 3 .. 5 {
+            let mut pkt = plain_packet();
+            sealer.seal(seq, &mut pkt);
+            assert!(&pkt[5 .. 12] != b"payload");
+
+            let header_len = opener.header_len();
+            assert_eq!(12, opener.decrypt_length(seq, &mut pkt[.. header_len]));
+            assert_eq!(Ok(()), opener.open(seq, &mut pkt));
+            assert_eq!(b"payload", &pkt[5 .. 12]);
+        }
+    }
+
+    #[test]
+    fn aes256_ctr_hmac_sha2_256_rejects_wrong_sequence() {
+        let (mut sealer, mut opener) = ciphers();
+
+        let mut pkt = plain_packet();
+        sealer.seal(0, &mut pkt);
+
+        let header_len = opener.header_len();
+        opener.decrypt_length(1, &mut pkt[.. header_len]);
+        assert_eq!(Err(()), opener.open(1, &mut pkt));
+    }
+
+    // 4 byte length, 1 byte padding length, 7 byte payload, 8 byte padding, MAC
+    fn etm_packet(mac_len: usize) -> Vec<u8> {
+        let mut pkt = vec![0, 0, 0, 16, 8];
+        pkt.extend_from_slice(b"payload");
+        pkt.extend_from_slice(&vec![0u8; 8 + mac_len]);
+        pkt
+    }
+
+    #[test]
+    fn aes256_ctr_etm_roundtrip() {
+        for &(ref mac, mac_len) in [(MacAlgorithm::HMAC_SHA2_256_ETM, 32), (MacAlgorithm::HMAC_SHA2_512_ETM, 64)].iter() {
+            let (mut sealer, mut opener) = ctr_ciphers(mac.clone());
+            assert_eq!(mac_len, sealer.tag_len());
+
+            for seq in 0 .. 3 {
+                let mut pkt = etm_packet(mac_len);
+                sealer.seal(seq, &mut pkt);
+                assert_eq!(&[0, 0, 0, 16], &pkt[.. 4]);
+                assert!(&pkt[5 .. 12] != b"payload");
+
+                assert_eq!(4, opener.header_len());
+                assert_eq!(16, opener.decrypt_length(seq, &mut pkt[.. 4]));
+                assert_eq!(Ok(()), opener.open(seq, &mut pkt));
+                assert_eq!(b"payload", &pkt[5 .. 12]);
+            }
+        }
+    }
+
+    #[test]
+    fn aes256_ctr_etm_rejects_modified_ciphertext() {
+        let (mut sealer, mut opener) = ctr_ciphers(MacAlgorithm::HMAC_SHA2_256_ETM);
+
+        let mut pkt = etm_packet(32);
+        sealer.seal(0, &mut pkt);
+        pkt[6] ^= 1;
+        assert_eq!(Err(()), opener.open(0, &mut pkt));
+    }
+
+    fn gcm_ciphers() -> (Box<PacketSealer>, Box<PacketOpener>) {
+        let enc = EncryptionAlgorithm::AES256_GCM;
+        let iv = [0xffu8; 12];
+        let key = [2u8; 32];
+        (
+            sealer(&enc, &None, &iv, &key, &[]).unwrap(),
+            opener(&enc, &None, &iv, &key, &[]).unwrap()
+        )
+    }
+
+    // 4 byte length, 1 byte padding length, 7 byte payload, 8 byte padding, 16 byte tag
+    fn gcm_packet() -> Vec<u8> {
+        let mut pkt = vec![0, 0, 0, 16, 8];
+        pkt.extend_from_slice(b"payload");
+        pkt.extend_from_slice(&[0u8; 8 + 16]);
+        pkt
+    }
+
+    #[test]
+    fn aes256_gcm_roundtrip() {
+        let (mut sealer, mut opener) = gcm_ciphers();
+
+        for seq in 0 .. 3 {
+            let mut pkt = gcm_packet();
+            sealer.seal(seq, &mut pkt);
+            assert_eq!(&[0, 0, 0, 16], &pkt[.. 4]);
+            assert!(&pkt[5 .. 12] != b"payload");
+
+            assert_eq!(16, opener.decrypt_length(seq, &mut pkt[.. 4]));
+            assert_eq!(Ok(()), opener.open(seq, &mut pkt));
+            assert_eq!(b"payload", &pkt[5 .. 12]);
+        }
+    }
+
+    // Expected output from OpenSSL's AES-256-GCM, with the length as the AAD. The invocation
+    // counter of the second packet wraps around to zero.
+    #[test]
+    fn aes256_gcm_known_answer() {
+        let (mut sealer, _) = gcm_ciphers();
+
+        let mut pkt = gcm_packet();
+        sealer.seal(0, &mut pkt);
+        assert_eq!(
+            "00000010f5bdb103b90122065272a8a1d0bfe5b01e1e9dabf4b73502ef21854e8aa0b979".from_hex().unwrap(),
+            pkt
+        );
+
+        let mut pkt = gcm_packet();
+        sealer.seal(1, &mut pkt);
+        assert_eq!(
+            "00000010ee97c16549913fc9af67db467185489b413590d3c40a01ebad1b88c52e95234f".from_hex().unwrap(),
+            pkt
+        );
+    }
+
+    #[test]
+    fn aes256_gcm_authenticates_length() {
+        let (mut sealer, mut opener) = gcm_ciphers();
+
+        let mut pkt = gcm_packet();
+        sealer.seal(0, &mut pkt);
+        pkt[3] = 17;
+        assert_eq!(Err(()), opener.open(0, &mut pkt));
+    }
+
+    #[test]
+    fn invocation_counter_carries() {
+        let mut nonce = [0u8, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xff, 0xff];
+        increment_invocation_counter(&mut nonce);
+        assert_eq!([0u8, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0], nonce);
+
+        let mut nonce = [0u8, 0, 0, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        increment_invocation_counter(&mut nonce);
+        assert_eq!([0u8, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0], nonce);
+    }
+
+    fn chacha_ciphers() -> (Box<PacketSealer>, Box<PacketOpener>) {
+        let enc = EncryptionAlgorithm::CHACHA20_POLY1305;
+        let key: Vec<u8> = (0 .. 64).collect();
+        (
+            sealer(&enc, &None, &[], &key, &[]).unwrap(),
+            opener(&enc, &None, &[], &key, &[]).unwrap()
+        )
+    }
+
+    // 4 byte length, 1 byte padding length, 7 byte payload, 4 byte padding, 16 byte tag
+    fn chacha_packet() -> Vec<u8> {
+        let mut pkt = vec![0, 0, 0, 12, 4];
+        pkt.extend_from_slice(b"payload");
+        pkt.extend_from_slice(&[0u8; 4 + 16]);
+        pkt
+    }
+
+    #[test]
+    fn chacha20_poly1305_known_answer() {
+        let (mut sealer, _) = chacha_ciphers();
+        let mut pkt = chacha_packet();
+        sealer.seal(7, &mut pkt);
+        assert_eq!(
+            "a39afca62c36743a22ec4b3a6c6dbbf00a39a66c6fa36e0fb55fcc3362555b03".from_hex().unwrap(),
+            pkt
+        );
+    }
+
+    #[test]
+    fn chacha20_poly1305_roundtrip() {
+        let (mut sealer, mut opener) = chacha_ciphers();
+
+        for seq in 0 .. 3 {
+            let mut pkt = chacha_packet();
+            sealer.seal(seq, &mut pkt);
+            assert!(&pkt[.. 4] != &[0, 0, 0, 12]);
+
+            assert_eq!(12, opener.decrypt_length(seq, &mut pkt[.. 4]));
+            assert_eq!(Ok(()), opener.open(seq, &mut pkt));
+            assert_eq!(b"payload", &pkt[5 .. 12]);
+        }
+    }
+
+    #[test]
+    fn chacha20_poly1305_rejects_modified_length() {
+        let (mut sealer, mut opener) = chacha_ciphers();
+
+        let mut pkt = chacha_packet();
+        sealer.seal(0, &mut pkt);
+        pkt[0] ^= 1;
+        assert_eq!(Err(()), opener.open(0, &mut pkt));
+    }
+}
@@ -0,0 +1,137 @@
+use std::cmp;
+
+use flate2::{Compress, Compression, Decompress, Flush, Status};
+
+// Upper bound of a decompressed payload. This is the largest packet OpenSSH accepts.
+pub const MAX_DECOMPRESSED_LEN: usize = 256 * 1024;
+
+// A zlib stream which spans every packet sent in one direction
+pub struct Compressor {
+    inner: Compress
+}
+
+impl Compressor {
+    pub fn new() -> Compressor {
+        Compressor {
+            inner: Compress::new(Compression::Default, true)
+        }
+    }
+
+    pub fn compress(&mut self, payload: &[u8]) -> Result<Vec<u8>, ()> {
+        let mut out = Vec::with_capacity(payload.len() + 64);
+        let total_in = self.inner.total_in();
+
+        loop {
+            let consumed = (self.inner.total_in() - total_in) as usize;
+            if let Status::StreamEnd = self.inner.compress_vec(&payload[consumed ..], &mut out, Flush::Sync) {
+                return Err(());
+            }
+
+            // The flush is complete once every input byte is consumed without filling the output
+            let consumed = (self.inner.total_in() - total_in) as usize;
+            if consumed == payload.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+
+            let additional = cmp::max(out.capacity(), 64);
+            out.reserve(additional);
+        }
+    }
+}
+
+pub struct Decompressor {
+    inner: Decompress,
+    limit: usize
+}
+
+impl Decompressor {
+    pub fn new() -> Decompressor {
+        Decompressor::with_limit(MAX_DECOMPRESSED_LEN)
+    }
+
+    pub fn with_limit(limit: usize) -> Decompressor {
+        Decompressor {
+            inner: Decompress::new(true),
+            limit: limit
+        }
+    }
+
+    pub fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        let mut out = Vec::with_capacity(cmp::min(data.len() * 4 + 64, self.limit + 1));
+        let total_in = self.inner.total_in();
+
+        loop {
+            let consumed = (self.inner.total_in() - total_in) as usize;
+            match self.inner.decompress_vec(&data[consumed ..], &mut out, Flush::Sync) {
+                Ok(Status::StreamEnd) | Err(_) => return Err(()),
+                Ok(_) => ()
+            }
+
+            if out.len() > self.limit {
+                return Err(());
+            }
+
+            let consumed = (self.inner.total_in() - total_in) as usize;
+            if consumed == data.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+
+            // Never grow the buffer past the limit, so a compression bomb fails early. `reserve`
+            // may double the capacity, so only ask for exactly the remaining allowance.
+            let additional = cmp::min(cmp::max(out.capacity(), 64), self.limit + 1 - out.len());
+            out.reserve_exact(additional);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn zlib_stream_roundtrip() {
+        let mut compressor = Compressor::new();
+        let mut decompressor = Decompressor::new();
+
+        for i in 0 .. 4 {
+            let payload: Vec<u8> = b"log line\n".iter().cycle().take(1000 * i + 1).cloned().collect();
+            let compressed = compressor.compress(&payload).unwrap();
+            assert_eq!(payload, decompressor.decompress(&compressed).unwrap());
+        }
+    }
+
+    #[test]
+    fn zlib_stream_uses_shared_context() {
+        let mut compressor = Compressor::new();
+        let payload = vec![b'x'; 4096];
+
+        let first = compressor.compress(&payload).unwrap();
+        let second = compressor.compress(&payload).unwrap();
+        assert!(second.len() < first.len());
+
+        // Packets cannot be decompressed out of order
+        let mut decompressor = Decompressor::new();
+        assert!(decompressor.decompress(&second).is_err());
+    }
+
+    #[test]
+    fn decompressed_size_limit() {
+        let mut compressor = Compressor::new();
+        let compressed = compressor.compress(&vec![0u8; 64 * 1024]).unwrap();
+
+        let mut decompressor = Decompressor::with_limit(16 * 1024);
+        assert!(decompressor.decompress(&compressed).is_err());
+    }
+
+    #[test]
+    fn decompressed_size_at_limit() {
+        let mut compressor = Compressor::new();
+        let payload = vec![0u8; 16 * 1024];
+        let compressed = compressor.compress(&payload).unwrap();
+
+        let mut decompressor = Decompressor::with_limit(16 * 1024);
+        let decompressed = decompressor.decompress(&compressed).unwrap();
+        assert_eq!(payload, decompressed);
+        assert!(decompressed.capacity() <= 16 * 1024 + 1);
+    }
+}
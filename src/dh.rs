@@ -0,0 +1,323 @@
+use modular::Modulus;
+
+use num_bigint::BigUint;
+use rand::Rng;
+
+use std::{fs, io};
+use std::io::Read;
+use std::path::Path;
+
+// MODP groups of RFC 3526, all with generator 2
+const GROUP14_P: &'static str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF"
+);
+
+const GROUP16_P: &'static str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A92108011A723C12A787E6D7",
+    "88719A10BDBA5B2699C327186AF4E23C1A946834B6150BDA2583E9CA2AD44CE8",
+    "DBBBC2DB04DE8EF92E8EFC141FBECAA6287C59474E6BC05D99B2964FA090C3A2",
+    "233BA186515BE7ED1F612970CEE2D7AFB81BDD762170481CD0069127D5B05AA9",
+    "93B4EA988D8FDDC186FFB7DC90A6C08F4DF435C934063199FFFFFFFFFFFFFFFF"
+);
+
+const GROUP18_P: &'static str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A92108011A723C12A787E6D7",
+    "88719A10BDBA5B2699C327186AF4E23C1A946834B6150BDA2583E9CA2AD44CE8",
+    "DBBBC2DB04DE8EF92E8EFC141FBECAA6287C59474E6BC05D99B2964FA090C3A2",
+    "233BA186515BE7ED1F612970CEE2D7AFB81BDD762170481CD0069127D5B05AA9",
+    "93B4EA988D8FDDC186FFB7DC90A6C08F4DF435C93402849236C3FAB4D27C7026",
+    "C1D4DCB2602646DEC9751E763DBA37BDF8FF9406AD9E530EE5DB382F413001AE",
+    "B06A53ED9027D831179727B0865A8918DA3EDBEBCF9B14ED44CE6CBACED4BB1B",
+    "DB7F1447E6CC254B332051512BD7AF426FB8F401378CD2BF5983CA01C64B92EC",
+    "F032EA15D1721D03F482D7CE6E74FEF6D55E702F46980C82B5A84031900B1C9E",
+    "59E7C97FBEC7E8F323A97A7E36CC88BE0F1D45B7FF585AC54BD407B22B4154AA",
+    "CC8F6D7EBF48E1D814CC5ED20F8037E0A79715EEF29BE32806A1D58BB7C5DA76",
+    "F550AA3D8A1FBFF0EB19CCB1A313D55CDA56C9EC2EF29632387FE8D76E3C0468",
+    "043E8F663F4860EE12BF2D5B0B7474D6E694F91E6DBE115974A3926F12FEE5E4",
+    "38777CB6A932DF8CD8BEC4D073B931BA3BC832B68D9DD300741FA7BF8AFC47ED",
+    "2576F6936BA424663AAB639C5AE4F5683423B4742BF1C978238F16CBE39D652D",
+    "E3FDB8BEFC848AD922222E04A4037C0713EB57A81A23F0C73473FC646CEA306B",
+    "4BCBC8862F8385DDFA9D4B7FA2C087E879683303ED5BDD3A062B3CF5B3A278A6",
+    "6D2A13F83F44F82DDF310EE074AB6A364597E899A0255DC164F31CC50846851D",
+    "F9AB48195DED7EA1B1D510BD7EE74D73FAF36BC31ECFA268359046F4EB879F92",
+    "4009438B481C6CD7889A002ED5EE382BC9190DA6FC026E479558E4475677E9AA",
+    "9E3050E2765694DFC81F56E880B96E7160C980DD98EDD3DFFFFFFFFFFFFFFFFF"
+);
+
+// A finite field Diffie-Hellman group: a safe prime p and a generator g
+#[derive(Clone, Debug, PartialEq)]
+pub struct Group {
+    pub p: BigUint,
+    pub g: BigUint
+}
+
+fn modp_group(p: &str) -> Group {
+    Group {
+        p: BigUint::parse_bytes(p.as_bytes(), 16).unwrap(),
+        g: BigUint::from(2u32)
+    }
+}
+
+// 2048-bit MODP group
+pub fn group14() -> Group {
+    modp_group(GROUP14_P)
+}
+
+// 4096-bit MODP group
+pub fn group16() -> Group {
+    modp_group(GROUP16_P)
+}
+
+// 8192-bit MODP group
+pub fn group18() -> Group {
+    modp_group(GROUP18_P)
+}
+
+impl Group {
+    // `p` and `g` are unsigned big-endian integers
+    pub fn from_bytes(p: &[u8], g: &[u8]) -> Group {
+        Group {
+            p: BigUint::from_bytes_be(p),
+            g: BigUint::from_bytes_be(g)
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        self.p.bits()
+    }
+
+    // Only an odd modulus with 1 < g < p-1 is accepted from the peer
+    pub fn is_valid(&self) -> bool {
+        let one = BigUint::from(1u32);
+        self.p.bits() > 1
+            && &self.p % BigUint::from(2u32) == one
+            && self.g > one
+            && self.g < &self.p - &one
+    }
+}
+
+// Parses an OpenSSH moduli file. Each line reads "time type tests tries size generator modulus",
+// where size is the modulus length minus one. Like OpenSSH, malformed lines are skipped, and only a
+// file without any usable group is an error.
+pub fn parse_moduli(text: &str) -> Result<Vec<Group>, ()> {
+    let mut groups = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 7 {
+            continue;
+        }
+
+        let (typ, tests, size) = match (fields[1].parse::<u32>(), fields[2].parse::<u32>(), fields[4].parse::<usize>()) {
+            (Ok(typ), Ok(tests), Ok(size)) => (typ, tests, size),
+            _ => continue
+        };
+        // Only safe primes (type 2) which passed the Miller-Rabin test (flag 0x04) are used
+        if typ != 2 || tests & 0x04 == 0 {
+            continue;
+        }
+
+        let (g, p) = match (BigUint::parse_bytes(fields[5].as_bytes(), 16), BigUint::parse_bytes(fields[6].as_bytes(), 16)) {
+            (Some(g), Some(p)) => (g, p),
+            _ => continue
+        };
+        if p.bits() != size + 1 {
+            continue;
+        }
+        groups.push(Group { p: p, g: g });
+    }
+
+    if groups.is_empty() {
+        Err(())
+    } else {
+        Ok(groups)
+    }
+}
+
+pub fn load_moduli<P: AsRef<Path>>(path: P) -> io::Result<Vec<Group>> {
+    let mut text = String::new();
+    try!(fs::File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+    parse_moduli(&text).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "no usable group in the moduli file"))
+}
+
+// The built-in MODP groups, used when no moduli file is loaded
+pub fn default_moduli() -> Vec<Group> {
+    vec![group14(), group16(), group18()]
+}
+
+// Picks the smallest group of at least n bits, or else the largest one, among those within min and max
+pub fn choose_group(groups: &[Group], min: u32, n: u32, max: u32) -> Option<Group> {
+    let (min, n, max) = (min as usize, n as usize, max as usize);
+    let candidates = groups.iter().filter(|group| group.bits() >= min && group.bits() <= max);
+    let (above, below): (Vec<&Group>, Vec<&Group>) = candidates.partition(|group| group.bits() >= n);
+
+    above.into_iter().min_by_key(|group| group.bits())
+        .or(below.into_iter().max_by_key(|group| group.bits()))
+        .cloned()
+}
+
+pub struct DhPrivateKey {
+    group: Group,
+    modulus: Modulus,
+    // The exponent as big-endian bytes. It never goes through BigUint, whose modpow takes longer
+    // for some exponents than for others.
+    x: Vec<u8>
+}
+
+impl DhPrivateKey {
+    // `exp_bits` should be at least twice the security strength of the group (RFC 8268 section 4)
+    pub fn generate(group: Group, exp_bits: usize, rng: &mut Rng) -> DhPrivateKey {
+        let exp_bits = if exp_bits + 1 < group.bits() {
+            exp_bits
+        } else {
+            group.bits() - 2
+        };
+
+        let mut buf = vec![0u8; (exp_bits + 7) / 8];
+        rng.fill_bytes(&mut buf);
+        // Keep exactly exp_bits bits, with the top one set so that x is never small
+        let excess = buf.len() * 8 - exp_bits;
+        buf[0] &= 0xff >> excess;
+        buf[0] |= 0x80 >> excess;
+
+        DhPrivateKey {
+            modulus: Modulus::new(&group.p),
+            group: group,
+            x: buf
+        }
+    }
+
+    pub fn group(&self) -> &Group {
+        &self.group
+    }
+
+    // e = g^x mod p, as an unsigned big-endian integer as long as p
+    pub fn public_key(&self) -> Vec<u8> {
+        let g = self.modulus.from_be_reduced(&(&self.group.g % &self.group.p).to_bytes_be());
+        self.modulus.to_be(&self.modulus.pow(&g, &self.x))
+    }
+
+    // Returns K = f^x mod p. Values of f outside of 1 < f < p-1 are rejected (RFC 8268 section 4)
+    pub fn agree(&self, peer_pub_key: &[u8]) -> Result<Vec<u8>, ()> {
+        let one = BigUint::from(1u32);
+        let f = BigUint::from_bytes_be(peer_pub_key);
+        if f <= one || f >= &self.group.p - &one {
+            return Err(());
+        }
+        let f = match self.modulus.from_be(&f.to_bytes_be()) {
+            Some(f) => f,
+            None => return Err(())
+        };
+        Ok(self.modulus.to_be(&self.modulus.pow(&f, &self.x)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use num_bigint::BigUint;
+    use rand::OsRng;
+
+    #[test]
+    fn group_sizes() {
+        assert_eq!(2048, group14().bits());
+        assert_eq!(4096, group16().bits());
+        assert_eq!(8192, group18().bits());
+    }
+
+    #[test]
+    fn key_agreement() {
+        let mut rng = OsRng::new().unwrap();
+        let client = DhPrivateKey::generate(group14(), 512, &mut rng);
+        let server = DhPrivateKey::generate(group14(), 512, &mut rng);
+
+        let e = client.public_key();
+        let f = server.public_key();
+        assert_eq!(client.agree(&f).unwrap(), server.agree(&e).unwrap());
+    }
+
+    #[test]
+    fn public_key_matches_modpow() {
+        let mut rng = OsRng::new().unwrap();
+        let key = DhPrivateKey::generate(group14(), 512, &mut rng);
+        let e = group14().g.modpow(&BigUint::from_bytes_be(&key.x), &group14().p);
+        assert_eq!(256, key.public_key().len());
+        assert_eq!(e, BigUint::from_bytes_be(&key.public_key()));
+    }
+
+    #[test]
+    fn public_value_range() {
+        let mut rng = OsRng::new().unwrap();
+        let key = DhPrivateKey::generate(group14(), 512, &mut rng);
+        let p = group14().p;
+        let p_minus_one = &p - &BigUint::from(1u32);
+
+        assert!(key.agree(&[]).is_err());
+        assert!(key.agree(&[1]).is_err());
+        assert!(key.agree(&p_minus_one.to_bytes_be()).is_err());
+        assert!(key.agree(&p.to_bytes_be()).is_err());
+        assert!(key.agree(&[2]).is_ok());
+    }
+
+    #[test]
+    fn moduli_file() {
+        let text = format!("# Time Type Tests Tries Size Generator Modulus\n\
+                            20240101000000 2 6 100 2047 2 {}\n\
+                            20240101000000 1 6 100 2047 2 {}\n", GROUP14_P, GROUP14_P);
+        assert_eq!(vec![group14()], parse_moduli(&text).unwrap());
+
+        // Lines with a wrong size, a missing field or a bad number are skipped
+        let text = format!("20240101000000 2 6 100 4095 2 {}\n\
+                            20240101000000 2 6 100 2047 2\n\
+                            20240101000000 2 6 100 2047 2 {}\n\
+                            20240101000000 2 x 100 2047 2 {}\n", GROUP14_P, GROUP14_P, GROUP14_P);
+        assert_eq!(vec![group14()], parse_moduli(&text).unwrap());
+
+        assert!(parse_moduli(&format!("20240101000000 2 6 100 4095 2 {}\n", GROUP14_P)).is_err());
+        assert!(parse_moduli("20240101000000 2 6 100 2047 2\n").is_err());
+        assert!(parse_moduli("# Time Type Tests Tries Size Generator Modulus\n").is_err());
+    }
+
+    #[test]
+    fn group_choice() {
+        let groups = default_moduli();
+        assert_eq!(Some(group14()), choose_group(&groups, 2048, 2048, 8192));
+        assert_eq!(Some(group16()), choose_group(&groups, 2048, 3072, 8192));
+        // Falls back to the largest group below the preferred size
+        assert_eq!(Some(group16()), choose_group(&groups, 2048, 6144, 6144));
+        assert_eq!(None, choose_group(&groups, 1024, 1024, 1536));
+    }
+}
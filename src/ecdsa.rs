@@ -0,0 +1,639 @@
+use modular::{self, Modulus};
+
+use num_bigint::BigUint;
+use rand::Rng;
+use ring::{digest, hmac};
+
+// NIST curves of FIPS 186-4 appendix D.1.2. All of them have a = -3
+const P256: [&'static str; 5] = [
+    "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff",
+    "5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b",
+    "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551",
+    "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+    "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5"
+];
+
+const P384: [&'static str; 5] = [
+    concat!("fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe",
+            "ffffffff0000000000000000ffffffff"),
+    concat!("b3312fa7e23ee7e4988e056be3f82d19181d9c6efe8141120314088f5013875a",
+            "c656398d8a2ed19d2a85c8edd3ec2aef"),
+    concat!("ffffffffffffffffffffffffffffffffffffffffffffffffc7634d81f4372ddf",
+            "581a0db248b0a77aecec196accc52973"),
+    concat!("aa87ca22be8b05378eb1c71ef320ad746e1d3b628ba79b9859f741e082542a38",
+            "5502f25dbf55296c3a545e3872760ab7"),
+    concat!("3617de4a96262c6f5d9e98bf9292dc29f8f41dbd289a147ce9da3113b5f0b8c0",
+            "0a60b1ce1d7e819d7a431d7c90ea0e5f")
+];
+
+const P521: [&'static str; 5] = [
+    concat!("01ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "ffff"),
+    concat!("0051953eb9618e1c9a1f929a21a0b68540eea2da725b99b315f3b8b489918ef1",
+            "09e156193951ec7e937b1652c0bd3bb1bf073573df883d2c34f1ef451fd46b50",
+            "3f00"),
+    concat!("01ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "fffa51868783bf2f966b7fcc0148f709a5d03bb5c9b8899c47aebb6fb71e9138",
+            "6409"),
+    concat!("00c6858e06b70404e9cd9e3ecb662395b4429c648139053fb521f828af606b4d",
+            "3dbaa14b5e77efe75928fe1dc127a2ffa8de3348b3c1856a429bf97e7e31c2e5",
+            "bd66"),
+    concat!("011839296a789a3bc0045c8a5fb42c7d1bd998f54449579b446817afbd17273e",
+            "662c97ee72995ef42640c550b9013fad0761353c7086a272c24088be94769fd1",
+            "6650")
+];
+
+// A curve y^2 = x^3 - 3x + b over GF(p), whose generator (gx, gy) has prime order n
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve {
+    name: &'static str,
+    p: BigUint,
+    b: BigUint,
+    n: BigUint,
+    gx: BigUint,
+    gy: BigUint,
+    // Constant-time arithmetic modulo p and n, for private scalars
+    field: Modulus,
+    order: Modulus,
+    // b in Montgomery form modulo p
+    b_mont: Vec<u32>
+}
+
+fn nist_curve(name: &'static str, params: &[&'static str; 5]) -> Curve {
+    let hex = |s: &str| BigUint::parse_bytes(s.as_bytes(), 16).unwrap();
+    let (p, b, n) = (hex(params[0]), hex(params[1]), hex(params[2]));
+    let field = Modulus::new(&p);
+    let b_mont = field.from_be(&b.to_bytes_be()).unwrap();
+    Curve {
+        name: name,
+        order: Modulus::new(&n),
+        p: p,
+        b: b,
+        n: n,
+        gx: hex(params[3]),
+        gy: hex(params[4]),
+        field: field,
+        b_mont: b_mont
+    }
+}
+
+pub fn nistp256() -> Curve {
+    nist_curve("nistp256", &P256)
+}
+
+pub fn nistp384() -> Curve {
+    nist_curve("nistp384", &P384)
+}
+
+pub fn nistp521() -> Curve {
+    nist_curve("nistp521", &P521)
+}
+
+// A point in Jacobian coordinates, (X, Y, Z) standing for (X/Z^2, Y/Z^3). None is the point at infinity
+type Point = Option<(BigUint, BigUint, BigUint)>;
+
+// A point in homogeneous projective coordinates, (X, Y, Z) standing for (X/Z, Y/Z), each in Montgomery
+// form modulo p. The identity is (0, 1, 0).
+#[derive(Clone)]
+struct Projective {
+    x: Vec<u32>,
+    y: Vec<u32>,
+    z: Vec<u32>
+}
+
+impl Curve {
+    // The SSH name of the curve (RFC 5656 section 10.1)
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // The length of a coordinate
+    pub fn field_len(&self) -> usize {
+        (self.p.bits() + 7) / 8
+    }
+
+    fn add_mod(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + b) % &self.p
+    }
+
+    fn sub_mod(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + &self.p - b) % &self.p
+    }
+
+    fn mul_mod(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a * b) % &self.p
+    }
+
+    fn double(&self, pt: &Point) -> Point {
+        let (x, y, z) = match *pt {
+            Some((ref x, ref y, ref z)) => (x, y, z),
+            None => return None
+        };
+        if *y == BigUint::from(0u32) {
+            return None;
+        }
+
+        // dbl-2001-b, which relies on a = -3
+        let delta = self.mul_mod(z, z);
+        let gamma = self.mul_mod(y, y);
+        let beta = self.mul_mod(x, &gamma);
+        let alpha = self.mul_mod(&BigUint::from(3u32), &self.mul_mod(&self.sub_mod(x, &delta), &self.add_mod(x, &delta)));
+        let x3 = self.sub_mod(&self.mul_mod(&alpha, &alpha), &self.mul_mod(&BigUint::from(8u32), &beta));
+        let yz = self.add_mod(y, z);
+        let z3 = self.sub_mod(&self.sub_mod(&self.mul_mod(&yz, &yz), &gamma), &delta);
+        let four_beta = self.mul_mod(&BigUint::from(4u32), &beta);
+        let eight_gamma2 = self.mul_mod(&BigUint::from(8u32), &self.mul_mod(&gamma, &gamma));
+        let y3 = self.sub_mod(&self.mul_mod(&alpha, &self.sub_mod(&four_beta, &x3)), &eight_gamma2);
+        Some((x3, y3, z3))
+    }
+
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        let (x1, y1, z1) = match *a {
+            Some((ref x, ref y, ref z)) => (x, y, z),
+            None => return b.clone()
+        };
+        let (x2, y2, z2) = match *b {
+            Some((ref x, ref y, ref z)) => (x, y, z),
+            None => return a.clone()
+        };
+
+        // add-2007-bl
+        let z1z1 = self.mul_mod(z1, z1);
+        let z2z2 = self.mul_mod(z2, z2);
+        let u1 = self.mul_mod(x1, &z2z2);
+        let u2 = self.mul_mod(x2, &z1z1);
+        let s1 = self.mul_mod(y1, &self.mul_mod(z2, &z2z2));
+        let s2 = self.mul_mod(y2, &self.mul_mod(z1, &z1z1));
+        if u1 == u2 {
+            return if s1 == s2 { self.double(a) } else { None };
+        }
+
+        let h = self.sub_mod(&u2, &u1);
+        let h2 = self.add_mod(&h, &h);
+        let i = self.mul_mod(&h2, &h2);
+        let j = self.mul_mod(&h, &i);
+        let s = self.sub_mod(&s2, &s1);
+        let r = self.add_mod(&s, &s);
+        let v = self.mul_mod(&u1, &i);
+        let x3 = self.sub_mod(&self.sub_mod(&self.mul_mod(&r, &r), &j), &self.add_mod(&v, &v));
+        let s1j = self.mul_mod(&s1, &j);
+        let y3 = self.sub_mod(&self.mul_mod(&r, &self.sub_mod(&v, &x3)), &self.add_mod(&s1j, &s1j));
+        let z12 = self.add_mod(z1, z2);
+        let z3 = self.mul_mod(&self.sub_mod(&self.sub_mod(&self.mul_mod(&z12, &z12), &z1z1), &z2z2), &h);
+        Some((x3, y3, z3))
+    }
+
+    // Double-and-add, which leaks the bits of k through timing. Only the public scalars of a signature
+    // verification may go through it; private ones go through mul_ct().
+    fn mul(&self, k: &BigUint, pt: &Point) -> Point {
+        let mut res = None;
+        for i in (0..k.bits()).rev() {
+            res = self.double(&res);
+            if (k >> i) % BigUint::from(2u32) == BigUint::from(1u32) {
+                res = self.add(&res, pt);
+            }
+        }
+        res
+    }
+
+    fn to_affine(&self, pt: &Point) -> Option<(BigUint, BigUint)> {
+        pt.as_ref().map(|&(ref x, ref y, ref z)| {
+            let z_inv = z.modpow(&(&self.p - BigUint::from(2u32)), &self.p);
+            let z_inv2 = self.mul_mod(&z_inv, &z_inv);
+            (self.mul_mod(x, &z_inv2), self.mul_mod(y, &self.mul_mod(&z_inv2, &z_inv)))
+        })
+    }
+
+    fn generator(&self) -> Point {
+        Some((self.gx.clone(), self.gy.clone(), BigUint::from(1u32)))
+    }
+
+    // Only accepts uncompressed points on the curve (RFC 5656 section 3.1)
+    fn decode_point(&self, q: &[u8]) -> Option<Point> {
+        let len = self.field_len();
+        if q.len() != 1 + 2 * len || q[0] != 0x04 {
+            return None;
+        }
+
+        let x = BigUint::from_bytes_be(&q[1 .. 1 + len]);
+        let y = BigUint::from_bytes_be(&q[1 + len ..]);
+        if x >= self.p || y >= self.p {
+            return None;
+        }
+        let x3 = self.mul_mod(&x, &self.mul_mod(&x, &x));
+        let rhs = self.add_mod(&self.sub_mod(&x3, &self.mul_mod(&BigUint::from(3u32), &x)), &self.b);
+        if self.mul_mod(&y, &y) != rhs {
+            return None;
+        }
+        Some(Some((x, y, BigUint::from(1u32))))
+    }
+
+    // The leftmost bits of the message digest, as many as there are in n, as an unsigned big-endian
+    // integer as long as n (bits2int of RFC 6979 section 2.3.2). Shifts bytes only, since the HMAC_DRBG
+    // output that becomes the nonce goes through here too.
+    fn bits2int(&self, digest: &[u8]) -> Vec<u8> {
+        let len = self.order.len();
+        if digest.len() * 8 <= self.n.bits() {
+            let mut out = vec![0u8; len - digest.len()];
+            out.extend_from_slice(digest);
+            return out;
+        }
+
+        let shift = digest.len() * 8 - self.n.bits();
+        let digest = &digest[.. digest.len() - shift / 8];
+        let bits = shift % 8;
+        if bits == 0 {
+            return digest.to_vec();
+        }
+        (0..len).map(|i| {
+            let carry = if i > 0 { digest[i - 1] << (8 - bits) } else { 0 };
+            (digest[i] >> bits) | carry
+        }).collect()
+    }
+
+    fn inv_n(&self, k: &BigUint) -> BigUint {
+        k.modpow(&(&self.n - BigUint::from(2u32)), &self.n)
+    }
+
+    fn identity(&self) -> Projective {
+        let zero = vec![0u32; self.field.one().len()];
+        Projective { x: zero.clone(), y: self.field.one(), z: zero }
+    }
+
+    // An affine point with coordinates below p
+    fn projective(&self, x: &BigUint, y: &BigUint) -> Projective {
+        let f = &self.field;
+        Projective {
+            x: f.from_be(&x.to_bytes_be()).unwrap(),
+            y: f.from_be(&y.to_bytes_be()).unwrap(),
+            z: f.one()
+        }
+    }
+
+    fn generator_ct(&self) -> Projective {
+        self.projective(&self.gx, &self.gy)
+    }
+
+    // The complete addition formulas of Renes, Costello and Batina for a = -3 (algorithm 4 of
+    // https://eprint.iacr.org/2015/1060), which also double and handle the identity without branching
+    fn add_ct(&self, a: &Projective, b: &Projective) -> Projective {
+        let f = &self.field;
+        let xx = f.mul(&a.x, &b.x);
+        let yy = f.mul(&a.y, &b.y);
+        let zz = f.mul(&a.z, &b.z);
+        let xy = f.sub(&f.mul(&f.add(&a.x, &a.y), &f.add(&b.x, &b.y)), &f.add(&xx, &yy));
+        let yz = f.sub(&f.mul(&f.add(&a.y, &a.z), &f.add(&b.y, &b.z)), &f.add(&yy, &zz));
+        let xz = f.sub(&f.mul(&f.add(&a.x, &a.z), &f.add(&b.x, &b.z)), &f.add(&xx, &zz));
+
+        let bzz = f.sub(&xz, &f.mul(&self.b_mont, &zz));
+        let bzz3 = f.add(&f.add(&bzz, &bzz), &bzz);
+        let yy_m = f.sub(&yy, &bzz3);
+        let yy_p = f.add(&yy, &bzz3);
+        let zz3 = f.add(&f.add(&zz, &zz), &zz);
+        let bxz = f.sub(&f.mul(&self.b_mont, &xz), &f.add(&zz3, &xx));
+        let bxz3 = f.add(&f.add(&bxz, &bxz), &bxz);
+        let xx3_m = f.sub(&f.add(&f.add(&xx, &xx), &xx), &zz3);
+
+        Projective {
+            x: f.sub(&f.mul(&yy_p, &xy), &f.mul(&yz, &bxz3)),
+            y: f.add(&f.mul(&yy_p, &yy_m), &f.mul(&xx3_m, &bxz3)),
+            z: f.add(&f.mul(&yy_m, &yz), &f.mul(&xy, &xx3_m))
+        }
+    }
+
+    // k * pt for an unsigned big-endian k, in fixed 4-bit windows read from the table by
+    // modular::lookup(). Only the length of k decides which operations run.
+    fn mul_ct(&self, k: &[u8], pt: &Projective) -> Projective {
+        let mut table = vec![self.identity(), pt.clone()];
+        for i in 2..16 {
+            let entry = self.add_ct(&table[i - 1], pt);
+            table.push(entry);
+        }
+        let flat: Vec<Vec<u32>> = table.iter().map(|p| [&p.x[..], &p.y[..], &p.z[..]].concat()).collect();
+
+        let n = pt.x.len();
+        let mut acc = self.identity();
+        for &byte in k {
+            for &window in &[byte >> 4, byte & 0x0f] {
+                for _ in 0..4 {
+                    acc = self.add_ct(&acc, &acc);
+                }
+                let entry = modular::lookup(&flat, window as u32);
+                acc = self.add_ct(&acc, &Projective {
+                    x: entry[..n].to_vec(),
+                    y: entry[n .. 2 * n].to_vec(),
+                    z: entry[2 * n ..].to_vec()
+                });
+            }
+        }
+        acc
+    }
+
+    // The affine x and y as unsigned big-endian integers as long as p. The identity comes out as (0, 0).
+    fn to_affine_ct(&self, pt: &Projective) -> (Vec<u8>, Vec<u8>) {
+        let f = &self.field;
+        let z_inv = f.inv(&pt.z);
+        (f.to_be(&f.mul(&pt.x, &z_inv)), f.to_be(&f.mul(&pt.y, &z_inv)))
+    }
+}
+
+pub struct EcdsaPrivateKey {
+    curve: Curve,
+    // As an unsigned big-endian integer as long as n
+    d: Vec<u8>,
+    public_key: Vec<u8>
+}
+
+// Also the ephemeral key of ECDH (RFC 5656 section 4) on curves that ring doesn't have
+impl EcdsaPrivateKey {
+    pub fn generate(curve: Curve, rng: &mut Rng) -> EcdsaPrivateKey {
+        let mut d = vec![0u8; curve.order.len()];
+        let excess = d.len() * 8 - curve.n.bits();
+        loop {
+            rng.fill_bytes(&mut d);
+            d[0] &= 0xff >> excess;
+            if let Ok(key) = EcdsaPrivateKey::from_bytes(curve.clone(), &d) {
+                return key;
+            }
+        }
+    }
+
+    // `d` is the private scalar as an unsigned big-endian integer, which has to be in 1..n-1
+    pub fn from_bytes(curve: Curve, d: &[u8]) -> Result<EcdsaPrivateKey, ()> {
+        let d_mont = match curve.order.from_be(d) {
+            Some(d) => d,
+            None => return Err(())
+        };
+        if curve.order.is_zero(&d_mont) {
+            return Err(());
+        }
+        let d = curve.order.to_be(&d_mont);
+
+        let (x, y) = curve.to_affine_ct(&curve.mul_ct(&d, &curve.generator_ct()));
+        let mut public_key = vec![0x04];
+        public_key.extend_from_slice(&x);
+        public_key.extend_from_slice(&y);
+        Ok(EcdsaPrivateKey {
+            curve: curve,
+            d: d,
+            public_key: public_key
+        })
+    }
+
+    pub fn curve(&self) -> &Curve {
+        &self.curve
+    }
+
+    // The public point Q in the uncompressed SEC1 encoding
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    // The x coordinate of d * Q as an unsigned big-endian integer as long as p, after checking that
+    // the peer's public point Q is on the curve
+    pub fn agree(&self, q: &[u8]) -> Result<Vec<u8>, ()> {
+        let curve = &self.curve;
+        let q = match curve.decode_point(q) {
+            Some(Some((x, y, _))) => curve.projective(&x, &y),
+            _ => return Err(())
+        };
+        let pt = curve.mul_ct(&self.d, &q);
+        if curve.field.is_zero(&pt.z) {
+            return Err(());
+        }
+        Ok(curve.to_affine_ct(&pt).0)
+    }
+
+    // Signs the message digest, made with `hash`, returning r and s as unsigned big-endian integers as
+    // long as n. The nonce is derived from the key and the digest as in RFC 6979 section 3.2, with
+    // HMAC-`hash`, so nothing depends on the quality of a random number generator.
+    pub fn sign(&self, hash: &'static digest::Algorithm, digest: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let curve = &self.curve;
+        let order = &curve.order;
+        let z = order.from_be_reduced(&curve.bits2int(digest));
+        let d = order.from_be(&self.d).unwrap();
+
+        let mut v = vec![0x01; hash.output_len];
+        let mut k = hmac::SigningKey::new(hash, &vec![0x00; hash.output_len]);
+        for &round in &[0x00, 0x01] {
+            let mut ctx = hmac::SigningContext::with_key(&k);
+            ctx.update(&v);
+            ctx.update(&[round]);
+            ctx.update(&self.d);
+            ctx.update(&order.to_be(&z));
+            k = hmac::SigningKey::new(hash, ctx.sign().as_ref());
+            v = hmac::sign(&k, &v).as_ref().to_vec();
+        }
+
+        loop {
+            let mut t = Vec::with_capacity(order.len() + hash.output_len);
+            while t.len() < order.len() {
+                v = hmac::sign(&k, &v).as_ref().to_vec();
+                t.extend_from_slice(&v);
+            }
+            let nonce = curve.bits2int(&t);
+
+            if let Some(nonce_mont) = order.from_be(&nonce) {
+                let (x, _) = curve.to_affine_ct(&curve.mul_ct(&nonce, &curve.generator_ct()));
+                // x < p < 2n
+                let r = order.from_be_reduced(&x);
+                let s = order.mul(&order.inv(&nonce_mont), &order.add(&z, &order.mul(&r, &d)));
+                if !order.is_zero(&nonce_mont) && !order.is_zero(&r) && !order.is_zero(&s) {
+                    return (order.to_be(&r), order.to_be(&s));
+                }
+            }
+
+            let mut ctx = hmac::SigningContext::with_key(&k);
+            ctx.update(&v);
+            ctx.update(&[0x00]);
+            k = hmac::SigningKey::new(hash, ctx.sign().as_ref());
+            v = hmac::sign(&k, &v).as_ref().to_vec();
+        }
+    }
+}
+
+// Verifies the signature (r, s) of the message digest with the public point `q`
+pub fn verify(curve: &Curve, q: &[u8], digest: &[u8], r: &[u8], s: &[u8]) -> Result<(), ()> {
+    let q = match curve.decode_point(q) {
+        Some(q) => q,
+        None => return Err(())
+    };
+    let (r, s) = (BigUint::from_bytes_be(r), BigUint::from_bytes_be(s));
+    let zero = BigUint::from(0u32);
+    if r == zero || s == zero || r >= curve.n || s >= curve.n {
+        return Err(());
+    }
+
+    let w = curve.inv_n(&s);
+    let u1 = (BigUint::from_bytes_be(&curve.bits2int(digest)) * &w) % &curve.n;
+    let u2 = (&r * &w) % &curve.n;
+    let pt = curve.add(&curve.mul(&u1, &curve.generator()), &curve.mul(&u2, &q));
+    match curve.to_affine(&pt) {
+        Some((ref x, _)) if x % &curve.n == r => Ok(()),
+        _ => Err(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{OsRng, Rng};
+    use ring::digest;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    // The uncompressed SEC1 encoding, 0x04 || x || y
+    fn encode_point(curve: &Curve, x: &BigUint, y: &BigUint) -> Vec<u8> {
+        let len = curve.field_len();
+        let mut out = vec![0u8; 1 + 2 * len];
+        out[0] = 0x04;
+        for &(c, offset) in &[(x, 1), (y, 1 + len)] {
+            let bytes = c.to_bytes_be();
+            out[offset + len - bytes.len() .. offset + len].copy_from_slice(&bytes);
+        }
+        out
+    }
+
+    #[test]
+    fn generator_order() {
+        for curve in &[nistp256(), nistp384(), nistp521()] {
+            let g = curve.generator();
+            let (x, y) = curve.to_affine(&g).unwrap();
+            assert!(curve.decode_point(&encode_point(curve, &x, &y)).is_some());
+            assert_eq!(None, curve.mul(&curve.n, &g));
+        }
+    }
+
+    #[test]
+    fn constant_time_mul() {
+        let mut rng = OsRng::new().unwrap();
+        for curve in &[nistp256(), nistp384(), nistp521()] {
+            let mut k = vec![0u8; curve.order.len()];
+            for _ in 0..4 {
+                rng.fill_bytes(&mut k);
+                k[0] = 0;
+                let (x, y) = curve.to_affine(&curve.mul(&BigUint::from_bytes_be(&k), &curve.generator())).unwrap();
+                let pt = curve.to_affine_ct(&curve.mul_ct(&k, &curve.generator_ct()));
+                assert_eq!(encode_point(curve, &x, &y)[1..].to_vec(), [pt.0, pt.1].concat());
+            }
+
+            let n = curve.n.to_bytes_be();
+            let pt = curve.mul_ct(&n, &curve.generator_ct());
+            assert!(curve.field.is_zero(&pt.z));
+        }
+    }
+
+    // RFC 6979 appendix A.2.5 for P-256, and the same message and method for the other curves
+    #[test]
+    fn deterministic_signatures() {
+        let vectors = vec![
+            (nistp256(), &digest::SHA256,
+             "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721",
+             concat!("0460fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb67903fe1008b8bc99a41ae9e95628bc64",
+                     "f2f1b20c2d7e9f5177a3c294d4462299"),
+             "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716",
+             "f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8"),
+            (nistp384(), &digest::SHA384,
+             "6b9d3dad2e1b8c1c05b19875b6659f4de23c3b667bf297ba9aa47740787137d896d5724e4c70a825f872c9ea60d2edf5",
+             concat!("04ec3a4e415b4e19a4568618029f427fa5da9a8bc4ae92e02e06aae5286b300c64def8f0ea9055866064a254515480bc13",
+                     "8015d9b72d7d57244ea8ef9ac0c621896708a59367f9dfb9f54ca84b3f1c9db1288b231c3ae0d4fe7344fd2533264720"),
+             "94edbb92a5ecb8aad4736e56c691916b3f88140666ce9fa73d64c4ea95ad133c81a648152e44acf96e36dd1e80fabe46",
+             "99ef4aeb15f178cea1fe40db2603138f130e740a19624526203b6351d0a3a94fa329c145786e679e7b82c71a38628ac8"),
+            (nistp521(), &digest::SHA512,
+             concat!("00fad06daa62ba3b25d2fb40133da757205de67f5bb0018fee8c86e1b68c7e75caa896eb32f1f47c70855836a6d16fcc14",
+                     "66f6d8fbec67db89ec0c08b0e996b83538"),
+             concat!("0401894550d0785932e00eaa23b694f213f8c3121f86dc97a04e5a7167db4e5bcd371123d46e45db6b5d5370a7f20fb633",
+                     "155d38ffa16d2bd761dcac474b9a2f5023a400493101c962cd4d2fddf782285e64584139c2f91b47f87ff82354d6630f74",
+                     "6a28a0db25741b5b34a828008b22acc23f924faafbd4d33f81ea66956dfeaa2bfdfcf5"),
+             concat!("00c328fafcbd79dd77850370c46325d987cb525569fb63c5d3bc53950e6d4c5f174e25a1ee9017b5d450606add152b5349",
+                     "31d7d4e8455cc91f9b15bf05ec36e377fa"),
+             concat!("00617cce7cf5064806c467f678d3b4080d6f1cc50af26ca209417308281b68af282623eaa63e5b5c0723d8b8c37ff0777b",
+                     "1a20f8ccb1dccc43997f1ee0e44da4a67a"))
+        ];
+        for (curve, hash, d, q, r, s) in vectors {
+            let key = EcdsaPrivateKey::from_bytes(curve, &d.from_hex().unwrap()).unwrap();
+            assert_eq!(q, key.public_key().to_hex());
+            let digest = digest::digest(hash, b"sample");
+            let (sig_r, sig_s) = key.sign(hash, digest.as_ref());
+            assert_eq!((r.to_string(), s.to_string()), (sig_r.to_hex(), sig_s.to_hex()));
+            assert!(verify(key.curve(), key.public_key(), digest.as_ref(), &sig_r, &sig_s).is_ok());
+        }
+    }
+
+    #[test]
+    fn key_agreement() {
+        let mut rng = OsRng::new().unwrap();
+        for curve in vec![nistp256(), nistp521()] {
+            let a = EcdsaPrivateKey::generate(curve.clone(), &mut rng);
+            let b = EcdsaPrivateKey::generate(curve.clone(), &mut rng);
+            let k = a.agree(b.public_key()).unwrap();
+            assert_eq!(curve.field_len(), k.len());
+            assert_eq!(k, b.agree(a.public_key()).unwrap());
+
+            let mut q = b.public_key().to_vec();
+            q[1] ^= 1;
+            assert!(a.agree(&q).is_err());
+        }
+    }
+
+    #[test]
+    fn private_key_range() {
+        let curve = nistp256();
+        let n = curve.n.to_bytes_be();
+        assert!(EcdsaPrivateKey::from_bytes(curve.clone(), &n).is_err());
+        assert!(EcdsaPrivateKey::from_bytes(curve.clone(), &[0; 32]).is_err());
+        assert!(EcdsaPrivateKey::from_bytes(curve.clone(), &[1; 33]).is_err());
+        let key = EcdsaPrivateKey::from_bytes(curve.clone(), &[1]).unwrap();
+        assert_eq!(encode_point(&curve, &curve.gx, &curve.gy), key.public_key());
+    }
+
+    // Signed by OpenSSL over a digest of 0x5a bytes, with SHA-256, SHA-384 and SHA-512
+    #[test]
+    fn signatures_verified() {
+        let vectors = vec![
+            (nistp256(), 32,
+             concat!("041b924694bef508279644228fed5d32c0cab6eedb4faa12ce3b48be3191c96b0667939bd50e2858b1d2560a79b078beed",
+                     "03a06196bcfdae5a4d5c2f43de3a7e89"),
+             "452e4c66e301087b7c6a4db505b0b636706a18dfa3aaf6ce493c003ac0a5df9a",
+             "1a24a00d7c8cffb0cc2eb567c3a2607e584cdd423fc7bf599b441beb4da55e98"),
+            (nistp384(), 48,
+             concat!("04a1a145fda81f00ac13f3acd9f2cb86537b53f8120f4212a39100f9817d1103",
+                     "a4af90d0299adaccbdec537183141ee47011bae5e2593a0725d647c3c4f30199",
+                     "1beea9f65b9ae995f7e417fb957efff1dc841700fbf662d10aa384009b2cc799",
+                     "e4"),
+             concat!("22b97e6d15a0f493b782d76398b4a25575cbbe90f81a517f99607257d5c6c0da",
+                     "bc83659676f2f38b7a1cd2699da0f697"),
+             concat!("2c5ba1bc1203a6f1692855a544fd91fa78d805d3803945ce687efe5931f20b64",
+                     "90f56ee636a9d3dd81e5ead677cc03e9")),
+            (nistp521(), 64,
+             concat!("040098ef5b848900b8374b64d5058d33271c93d1cd8354e66983e15dc014c097",
+                     "8def64908a1cd9ff6c3f8f9c37b9e2b096b5982d36f898079e99cd2e14b0c5e0",
+                     "cdeda201992c8b4c32ee1663323d670c9990f4aad3f0e97450284ce5f77f952f",
+                     "47b54daec86f1f854a5133537d26e7ab674fd4f053ef6244b743f9a4271a2d61",
+                     "ea0eef5e5f"),
+             concat!("0beb8470ca49c166d38848796a6f7d56badca3ccc44ac9f0574f3add6f7f5b7f",
+                     "304fee0189d2b873ca080f597356296a5d1835a2aa0ec05ee7086c8edcea8d97",
+                     "5e"),
+             concat!("7c425ed25682932af6322ae0dae98a2354e6cba2e303468f2bd6020f5bc429b6",
+                     "3da6b8bc530b00d9272b03fb3e58f9344adf21e83e137d667d27334668971856",
+                     "47"))
+        ];
+        for (curve, digest_len, q, r, s) in vectors {
+            let (q, r, s) = (q.from_hex().unwrap(), r.from_hex().unwrap(), s.from_hex().unwrap());
+            let digest = vec![0x5a; digest_len];
+            assert!(verify(&curve, &q, &digest, &r, &s).is_ok());
+            assert!(verify(&curve, &q, &[0x5b; 64], &r, &s).is_err());
+            assert!(verify(&curve, &q, &digest, &s, &r).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let curve = nistp256();
+        let mut q = encode_point(&curve, &curve.gx, &curve.gy);
+        assert!(curve.decode_point(&q).is_some());
+        q[64] ^= 1;
+        assert!(curve.decode_point(&q).is_none());
+        assert!(curve.decode_point(&q[..64]).is_none());
+    }
+}
@@ -1,28 +1,46 @@
 use async::bufreader::AsyncBufReader;
 use async::bufwriter::AsyncBufWriter;
+use cipher::{self, PacketOpener, PacketSealer};
+use dh;
+use ecdsa;
+use hostkey::{self, HostKey};
+use mlkem;
 use packet::types::*;
 use packet::{deserialize, serialize, serialize_msg};
-use transport::{AsyncPacketState, ClearTransport, PacketWriteRequest, TransportError, hton};
+use sntrup761;
+use transport::{AsyncPacketState, ClearTransport, NewKeys, PacketWriteRequest, TransportError, hton};
 
-use std::{fmt, io, str};
+use std::{fmt, io, mem, str};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io::{Read, Write};
+use std::sync::Arc;
+use futures;
 use futures::{Async, Future, Poll};
 use rand::{OsRng, Rng};
 use ring::{agreement, digest, rand, signature};
 use ring::digest::Context;
-use tokio_core::io::{flush, read_until, write_all};
+use tokio_core::io::{flush, write_all};
 use untrusted;
 
-use ::{SSH_MSG_KEXINIT, SSH_MSG_NEWKEYS, SSH_MSG_KEXDH_INIT, SSH_MSG_KEXDH_REPLY};
+use ::{SSH_MSG_EXT_INFO, SSH_MSG_KEXINIT, SSH_MSG_NEWKEYS, SSH_MSG_KEXDH_INIT, SSH_MSG_KEXDH_REPLY};
+use ::{SSH_MSG_KEX_DH_GEX_GROUP, SSH_MSG_KEX_DH_GEX_INIT, SSH_MSG_KEX_DH_GEX_REPLY, SSH_MSG_KEX_DH_GEX_REQUEST};
 
 #[derive(Debug)]
 pub enum HandshakeError {
     IoError(io::Error),
     InvalidHeader,
+    InvalidMac,
+    CompressionError,
+    PacketTooLarge(u32),
     InvalidVersionExchange,
     InvalidAlgorithmNegotiation(String),
     InvalidKexReply(String),
+    InvalidExtInfo(String),
+    InvalidMessage(u8),
+    UnexpectedMessage(u8),
+    Disconnected(DisconnectReason, String),
+    Closed(DisconnectReason),
     KexFailed,
     ServerKeyNotVerified,
     ExpectedNewKeys,
@@ -44,12 +62,28 @@ impl fmt::Display for HandshakeError {
                 e.fmt(f),
             HandshakeError::InvalidHeader =>
                 write!(f, "InvalidHeader"),
+            HandshakeError::InvalidMac =>
+                write!(f, "InvalidMac"),
+            HandshakeError::CompressionError =>
+                write!(f, "CompressionError"),
+            HandshakeError::PacketTooLarge(pkt_len) =>
+                write!(f, "PacketTooLarge({})", pkt_len),
             HandshakeError::InvalidVersionExchange =>
                 write!(f, "InvalidVersionExchange"),
             HandshakeError::InvalidAlgorithmNegotiation(ref msg) =>
                 write!(f, "InvalidAlgorithmNegotiation({})", msg),
             HandshakeError::InvalidKexReply(ref msg) =>
                 write!(f, "InvalidKexReply({})", msg),
+            HandshakeError::InvalidExtInfo(ref msg) =>
+                write!(f, "InvalidExtInfo({})", msg),
+            HandshakeError::InvalidMessage(msg_type) =>
+                write!(f, "InvalidMessage({})", msg_type),
+            HandshakeError::UnexpectedMessage(msg_type) =>
+                write!(f, "UnexpectedMessage({})", msg_type),
+            HandshakeError::Disconnected(reason, ref description) =>
+                write!(f, "Disconnected({:?}, {})", reason, description),
+            HandshakeError::Closed(reason) =>
+                write!(f, "Closed({:?})", reason),
             HandshakeError::KexFailed =>
                 write!(f, "KexFailed"),
             HandshakeError::ServerKeyNotVerified =>
@@ -77,6 +111,58 @@ impl TransportError for HandshakeError {
         HandshakeError::InvalidHeader
     }
 
+    fn invalid_mac() -> HandshakeError {
+        HandshakeError::InvalidMac
+    }
+
+    fn compression_error() -> HandshakeError {
+        HandshakeError::CompressionError
+    }
+
+    fn packet_too_large(pkt_len: u32) -> HandshakeError {
+        HandshakeError::PacketTooLarge(pkt_len)
+    }
+
+    fn invalid_message(msg_type: u8) -> HandshakeError {
+        HandshakeError::InvalidMessage(msg_type)
+    }
+
+    fn disconnected(reason: DisconnectReason, description: String) -> HandshakeError {
+        HandshakeError::Disconnected(reason, description)
+    }
+
+    fn closed(reason: DisconnectReason) -> HandshakeError {
+        HandshakeError::Closed(reason)
+    }
+
+    fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        match *self {
+            // The connection is already gone
+            HandshakeError::IoError(_) | HandshakeError::Disconnected(..) | HandshakeError::Closed(_) =>
+                None,
+            HandshakeError::InvalidMac =>
+                Some(DisconnectReason::MacError),
+            HandshakeError::CompressionError =>
+                Some(DisconnectReason::CompressionError),
+            HandshakeError::InvalidVersionExchange =>
+                Some(DisconnectReason::ProtocolVersionNotSupported),
+            HandshakeError::InvalidAlgorithmNegotiation(_) | HandshakeError::KexFailed =>
+                Some(DisconnectReason::KeyExchangeFailed),
+            HandshakeError::ServerKeyNotVerified | HandshakeError::UnknownCertType(_) =>
+                Some(DisconnectReason::HostKeyNotVerifiable),
+            HandshakeError::InvalidHeader
+                | HandshakeError::PacketTooLarge(_)
+                | HandshakeError::InvalidKexReply(_)
+                | HandshakeError::InvalidExtInfo(_)
+                | HandshakeError::InvalidMessage(_)
+                | HandshakeError::UnexpectedMessage(_)
+                | HandshakeError::ExpectedNewKeys
+                | HandshakeError::Unspecified
+                | HandshakeError::Panic(_) =>
+                Some(DisconnectReason::ProtocolError)
+        }
+    }
+
     fn panic(msg: &'static str) -> HandshakeError {
         HandshakeError::Panic(msg.into())
     }
@@ -88,26 +174,268 @@ pub struct NegotiatedAlgorithm {
     pub server_host_key_algorithms: ServerHostKeyAlgorithm,
     pub encryption_algorithms_client_to_server: EncryptionAlgorithm,
     pub encryption_algorithms_server_to_client: EncryptionAlgorithm,
-    pub mac_algorithms_client_to_server: MacAlgorithm,
-    pub mac_algorithms_server_to_client: MacAlgorithm,
+    pub mac_algorithms_client_to_server: Option<MacAlgorithm>,
+    pub mac_algorithms_server_to_client: Option<MacAlgorithm>,
     pub compression_algorithms_client_to_server: CompressionAlgorithm,
     pub compression_algorithms_server_to_client: CompressionAlgorithm,
     pub languages_client_to_server: Option<Language>,
     pub languages_server_to_client: Option<Language>
 }
 
+pub struct SessionKeys {
+    pub iv_client_to_server: Vec<u8>,
+    pub iv_server_to_client: Vec<u8>,
+    pub enc_key_client_to_server: Vec<u8>,
+    pub enc_key_server_to_client: Vec<u8>,
+    pub mac_key_client_to_server: Vec<u8>,
+    pub mac_key_server_to_client: Vec<u8>
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key material itself
+        write!(f, "SessionKeys {{ iv: ({}, {}), enc_key: ({}, {}), mac_key: ({}, {}) }}",
+               self.iv_client_to_server.len(),
+               self.iv_server_to_client.len(),
+               self.enc_key_client_to_server.len(),
+               self.enc_key_server_to_client.len(),
+               self.mac_key_client_to_server.len(),
+               self.mac_key_server_to_client.len())
+    }
+}
+
+// What the server side needs for the next key exchange in the session
+#[derive(Clone)]
+struct ServerParams {
+    host_key: Arc<HostKey>,
+    moduli: Arc<Vec<dh::Group>>
+}
+
+impl fmt::Debug for ServerParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServerParams {{ host_key: {:?}, moduli: {} groups }}", self.host_key, self.moduli.len())
+    }
+}
+
 #[derive(Debug)]
 pub struct SecureContext {
     neg_algorithm: NegotiatedAlgorithm,
-    session_id: Vec<u8>
+    session_id: Vec<u8>,
+    keys: SessionKeys,
+    // Kept to run another key exchange on the same session
+    v_c: String,
+    v_s: String,
+    // Our own KEXINIT lists, on the client or the server side
+    local_algorithms: AlgorithmNegotiation,
+    // Modulus sizes the client asks for in diffie-hellman-group-exchange
+    gex_request: GexRequest,
+    strict: bool,
+    extensions: HashMap<String, Extension>,
+    // Set on the server side
+    server: Option<ServerParams>
+}
+
+impl SecureContext {
+    pub fn algorithms(&self) -> &NegotiatedAlgorithm {
+        &self.neg_algorithm
+    }
+
+    pub fn session_id(&self) -> &[u8] {
+        &self.session_id
+    }
+
+    pub fn is_server(&self) -> bool {
+        self.server.is_some()
+    }
+
+    // Whether both sides agreed on strict key exchange in the first key exchange
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    // Extensions the server announced with SSH_MSG_EXT_INFO
+    pub fn extensions(&self) -> &HashMap<String, Extension> {
+        &self.extensions
+    }
+
+    pub fn server_sig_algs(&self) -> Option<&[ServerHostKeyAlgorithm]> {
+        match self.extensions.get("server-sig-algs") {
+            Some(&Extension::ServerSigAlgs(ref algs)) => Some(algs),
+            _ => None
+        }
+    }
+
+    pub fn set_ext_info(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
+        let extensions = try!(parse_ext_info(msg));
+        self.extensions.extend(extensions);
+        Ok(())
+    }
+
+    pub fn keys(&self) -> &SessionKeys {
+        &self.keys
+    }
+
+    pub fn client_sealer(&self) -> Result<Box<PacketSealer>, HandshakeError> {
+        let enc = &self.neg_algorithm.encryption_algorithms_client_to_server;
+        let mac = &self.neg_algorithm.mac_algorithms_client_to_server;
+        cipher::sealer(enc, mac,
+                       &self.keys.iv_client_to_server,
+                       &self.keys.enc_key_client_to_server,
+                       &self.keys.mac_key_client_to_server)
+            .map_err(|_| unsupported_algorithm(enc.as_ref()))
+    }
+
+    pub fn client_opener(&self) -> Result<Box<PacketOpener>, HandshakeError> {
+        let enc = &self.neg_algorithm.encryption_algorithms_server_to_client;
+        let mac = &self.neg_algorithm.mac_algorithms_server_to_client;
+        cipher::opener(enc, mac,
+                       &self.keys.iv_server_to_client,
+                       &self.keys.enc_key_server_to_client,
+                       &self.keys.mac_key_server_to_client)
+            .map_err(|_| unsupported_algorithm(enc.as_ref()))
+    }
+
+    pub fn server_sealer(&self) -> Result<Box<PacketSealer>, HandshakeError> {
+        let enc = &self.neg_algorithm.encryption_algorithms_server_to_client;
+        let mac = &self.neg_algorithm.mac_algorithms_server_to_client;
+        cipher::sealer(enc, mac,
+                       &self.keys.iv_server_to_client,
+                       &self.keys.enc_key_server_to_client,
+                       &self.keys.mac_key_server_to_client)
+            .map_err(|_| unsupported_algorithm(enc.as_ref()))
+    }
+
+    pub fn server_opener(&self) -> Result<Box<PacketOpener>, HandshakeError> {
+        let enc = &self.neg_algorithm.encryption_algorithms_client_to_server;
+        let mac = &self.neg_algorithm.mac_algorithms_client_to_server;
+        cipher::opener(enc, mac,
+                       &self.keys.iv_client_to_server,
+                       &self.keys.enc_key_client_to_server,
+                       &self.keys.mac_key_client_to_server)
+            .map_err(|_| unsupported_algorithm(enc.as_ref()))
+    }
+}
+
+// Strict key exchange allows nothing but the key exchange itself until the first NEWKEYS.
+// `strict` is None until the peer's KEXINIT arrives, so the message is remembered in `pending` until then
+fn check_strict_transport_msg(initial: bool, strict: Option<bool>, pending: &mut Option<u8>, msg_type: u8)
+        -> Result<(), HandshakeError>
+{
+    if initial {
+        match strict {
+            Some(true) => return Err(HandshakeError::UnexpectedMessage(msg_type)),
+            Some(false) => (),
+            None => if pending.is_none() {
+                *pending = Some(msg_type);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_strict_pending(strict: Option<bool>, pending: Option<u8>) -> Result<(), HandshakeError> {
+    match pending {
+        Some(msg_type) if strict == Some(true) => Err(HandshakeError::UnexpectedMessage(msg_type)),
+        _ => Ok(())
+    }
 }
 
 pub struct ClientKeyExchange {
-    st: ClientKex
+    st: ClientKex,
+    // Carried over from the previous context when re-keying
+    extensions: HashMap<String, Extension>,
+    initial: bool,
+    // A generic message arrived before the peer's KEXINIT, which strict key exchange forbids
+    unexpected_before_kexinit: Option<u8>
+}
+
+impl ClientKeyExchange {
+    fn new(neg: AlgorithmNegotiation, v_c: String, v_s: String, session_id: Option<Vec<u8>>, strict: bool)
+            -> Result<ClientKeyExchange, HandshakeError>
+    {
+        let mut neg = neg;
+        let initial = session_id.is_none();
+        // Strict key exchange and extension negotiation are only offered in the first key exchange
+        if initial {
+            for alg in &[KexAlgorithm::EXT_INFO_C, KexAlgorithm::KEX_STRICT_C] {
+                if !neg.kex_algorithms.contains(alg) {
+                    neg.kex_algorithms.push(alg.clone());
+                }
+            }
+        } else {
+            neg.kex_algorithms.retain(|alg| !alg.is_pseudo());
+        }
+
+        // Our guess is always our own preferred algorithm, sent right after KEXINIT. Group exchange
+        // starts with a request instead of a key share, so it is never guessed.
+        let preferred = neg.kex_algorithms.iter().find(|alg| !alg.is_pseudo()).cloned();
+        if preferred.as_ref().map_or(false, is_gex) {
+            neg.first_kex_packet_follows = false;
+        }
+        let guess = match preferred {
+            Some(ref alg) if neg.first_kex_packet_follows => Some(try!(KeyShare::generate(alg))),
+            _ => None
+        };
+
+        let mut rng = try!(OsRng::new());
+        let i_c = try!(build_kexinit_payload(&neg, &mut rng));
+        let st = AlgorithmExchangeState {
+            v_c: v_c,
+            v_s: v_s,
+            neg: neg,
+            i_c: i_c,
+            session_id: session_id,
+            strict: strict,
+            gex_request: GexRequest::default(),
+            guess: guess,
+            written: false,
+            guess_written: false,
+            guess_ok: false,
+            ignore_next: false,
+            res: None
+        };
+
+        Ok(ClientKeyExchange {
+            st: ClientKex::AlgorithmExchange(st),
+            extensions: HashMap::new(),
+            initial: initial,
+            unexpected_before_kexinit: None
+        })
+    }
+
+    // None until the peer's KEXINIT arrives
+    fn strict(&self) -> Option<bool> {
+        match self.st {
+            ClientKex::AlgorithmExchange(ref st) => st.res.as_ref().map(|_| st.strict),
+            ClientKex::GroupExchange(ref st) => Some(st.strict),
+            ClientKex::KeyExchange(ref st) => Some(st.strict),
+            ClientKex::Agreed(ref st) => st.ctx.as_ref().map(|ctx| ctx.strict)
+        }
+    }
+
+    // Starts a key exchange inside an established session. The session identifier never changes.
+    pub fn rekey(ctx: &SecureContext) -> Result<ClientKeyExchange, HandshakeError> {
+        let mut kex = try!(ClientKeyExchange::new(ctx.local_algorithms.clone(),
+                                                  ctx.v_c.clone(),
+                                                  ctx.v_s.clone(),
+                                                  Some(ctx.session_id.clone()),
+                                                  ctx.strict));
+        kex.extensions = ctx.extensions.clone();
+        kex.set_gex_request(ctx.gex_request);
+        Ok(kex)
+    }
+
+    // Modulus sizes to ask for if diffie-hellman-group-exchange-sha256 gets negotiated
+    pub fn set_gex_request(&mut self, req: GexRequest) {
+        if let ClientKex::AlgorithmExchange(ref mut st) = self.st {
+            st.gex_request = req;
+        }
+    }
 }
 
 pub enum ClientKex {
     AlgorithmExchange(AlgorithmExchangeState),
+    GroupExchange(GroupExchangeState),
     KeyExchange(KeyExchangeState),
     Agreed(Agreed)
 }
@@ -119,6 +447,13 @@ impl Future for ClientKeyExchange {
     fn poll(&mut self) -> Poll<SecureContext, HandshakeError> {
         let next_st = match self.st {
             ClientKex::AlgorithmExchange(ref mut st) => {
+                if let Async::Ready(next) = try!(st.poll()) {
+                    next
+                } else {
+                    return Ok(Async::NotReady);
+                }
+            },
+            ClientKex::GroupExchange(ref mut st) => {
                 if let Async::Ready(kex) = try!(st.poll()) {
                     ClientKex::KeyExchange(kex)
                 } else {
@@ -133,7 +468,10 @@ impl Future for ClientKeyExchange {
                 }
             },
             ClientKex::Agreed(ref mut st) => match try!(st.poll()) {
-                Async::Ready(ctx) => return Ok(Async::Ready(ctx)),
+                Async::Ready(mut ctx) => {
+                    ctx.extensions = mem::replace(&mut self.extensions, HashMap::new());
+                    return Ok(Async::Ready(ctx));
+                },
                 Async::NotReady => return Ok(Async::NotReady)
             }
         };
@@ -147,22 +485,27 @@ impl AsyncPacketState for ClientKeyExchange {
     fn wants_read(&self) -> bool {
         match self.st {
             ClientKex::AlgorithmExchange(ref st) => st.wants_read(),
+            ClientKex::GroupExchange(ref st) => st.wants_read(),
             ClientKex::KeyExchange(ref st) => st.wants_read(),
             ClientKex::Agreed(ref st) => st.wants_read(),
         }
     }
 
     fn on_read(&mut self, msg: &[u8]) -> Result<(), Self::Error> {
-        match self.st {
+        try!(match self.st {
             ClientKex::AlgorithmExchange(ref mut st) => st.on_read(msg),
+            ClientKex::GroupExchange(ref mut st) => st.on_read(msg),
             ClientKex::KeyExchange(ref mut st) => st.on_read(msg),
             ClientKex::Agreed(ref mut st) => st.on_read(msg)
-        }
+        });
+
+        check_strict_pending(self.strict(), self.unexpected_before_kexinit)
     }
 
     fn write_packet(&self) -> Option<PacketWriteRequest> {
         match self.st {
             ClientKex::AlgorithmExchange(ref st) => st.write_packet(),
+            ClientKex::GroupExchange(ref st) => st.write_packet(),
             ClientKex::KeyExchange(ref st) => st.write_packet(),
             ClientKex::Agreed(ref st) => st.write_packet(),
         }
@@ -171,280 +514,1578 @@ impl AsyncPacketState for ClientKeyExchange {
     fn on_flush(&mut self) -> Result<(), Self::Error> {
         match self.st {
             ClientKex::AlgorithmExchange(ref mut st) => st.on_flush(),
+            ClientKex::GroupExchange(ref mut st) => st.on_flush(),
             ClientKex::KeyExchange(ref mut st) => st.on_flush(),
             ClientKex::Agreed(ref mut st) => st.on_flush(),
         }
     }
+
+    fn take_sealer(&mut self) -> Option<NewKeys<Box<PacketSealer>>> {
+        match self.st {
+            ClientKex::Agreed(ref mut st) => st.take_sealer(),
+            _ => None
+        }
+    }
+
+    fn take_opener(&mut self) -> Option<NewKeys<Box<PacketOpener>>> {
+        match self.st {
+            ClientKex::Agreed(ref mut st) => st.take_opener(),
+            _ => None
+        }
+    }
+
+    fn is_known(&self, msg_type: u8) -> bool {
+        msg_type >= SSH_MSG_KEXINIT && msg_type <= 49
+    }
+
+    fn on_transport_msg(&mut self, msg_type: u8) -> Result<(), HandshakeError> {
+        let strict = self.strict();
+        check_strict_transport_msg(self.initial, strict, &mut self.unexpected_before_kexinit, msg_type)
+    }
 }
 
 pub struct AlgorithmExchangeState {
     v_c: String,
     v_s: String,
+    neg: AlgorithmNegotiation,
     i_c: Vec<u8>,
+    session_id: Option<Vec<u8>>,
+    strict: bool,
+    gex_request: GexRequest,
+    guess: Option<KeyShare>,
     written: bool,
+    guess_written: bool,
+    guess_ok: bool,
+    ignore_next: bool,
     res: Option<(NegotiatedAlgorithm, Context)>
 }
 
-fn digest_bytes(ctx: &mut Context, bytes: &[u8]) -> Result<(), HandshakeError> {
-    let len: u32 = match TryFrom::try_from(bytes.len()) {
-        Ok(l) => l,
-        Err(_) => return Err(HandshakeError::KexFailed)
-    };
+// Our ephemeral half of the key agreement, for the negotiated key exchange method
+pub enum KeyShare {
+    Ecdh {
+        curve: &'static agreement::Algorithm,
+        priv_key: agreement::EphemeralPrivateKey,
+        pub_key: Vec<u8>
+    },
+    // ECDH on a curve that ring doesn't have, with the constant-time arithmetic of ecdsa.rs
+    EcdhNist {
+        priv_key: ecdsa::EcdsaPrivateKey
+    },
+    Dh {
+        priv_key: dh::DhPrivateKey,
+        // e or f as an mpint
+        pub_key: Vec<u8>
+    },
+    // A KEM key pair combined with X25519, only ever generated by the client
+    Hybrid {
+        hash: &'static digest::Algorithm,
+        kem_key: KemPrivateKey,
+        priv_key: agreement::EphemeralPrivateKey,
+        // The KEM public key followed by the X25519 public key
+        pub_key: Vec<u8>
+    }
+}
 
-    ctx.update(&hton(len));
-    ctx.update(bytes);
-    Ok(())
+// The post-quantum half of a hybrid key share
+pub enum KemPrivateKey {
+    MlKem768(mlkem::PrivateKey),
+    Sntrup761(sntrup761::PrivateKey)
 }
 
-impl Future for AlgorithmExchangeState {
-    type Item = KeyExchangeState;
-    type Error = HandshakeError;
+impl KemPrivateKey {
+    fn generate(alg: &KexAlgorithm, rng: &mut Rng) -> Option<KemPrivateKey> {
+        match *alg {
+            KexAlgorithm::MLKEM768_X25519_SHA256 => Some(KemPrivateKey::MlKem768(mlkem::PrivateKey::generate(rng))),
+            KexAlgorithm::SNTRUP761_X25519_SHA512 => Some(KemPrivateKey::Sntrup761(sntrup761::PrivateKey::generate(rng))),
+            _ => None
+        }
+    }
 
-    fn poll(&mut self) -> Poll<KeyExchangeState, HandshakeError> {
-        match self.res.take() {
-            Some((neg, ctx)) => {
-                let ring_rng = rand::SystemRandom::new();
-                let keygen = agreement::EphemeralPrivateKey::generate(
-                    &agreement::X25519,
-                    &ring_rng);
-                if let Ok(priv_key) = keygen {
-                    let mut key = [0u8; agreement::PUBLIC_KEY_MAX_LEN];
-                    priv_key.compute_public_key(&mut key[..priv_key.public_key_len()]).unwrap();
-                    let pub_key = &key[..priv_key.public_key_len()];
-                    Ok(Async::Ready(KeyExchangeState {
-                        neg: neg,
-                        keyshare: Some((ctx, priv_key)),
-                        e: pub_key.to_vec(),
-                        written: false,
-                        res: None
-                    }))
-                } else {
-                    Err(HandshakeError::KexFailed)
-                }
-            }
-            None => Ok(Async::NotReady)
+    fn public_key(&self) -> &[u8] {
+        match *self {
+            KemPrivateKey::MlKem768(ref key) => key.public_key(),
+            KemPrivateKey::Sntrup761(ref key) => key.public_key()
         }
     }
-}
 
-impl AsyncPacketState for AlgorithmExchangeState {
-    fn wants_read(&self) -> bool {
-        self.res.is_none()
+    fn ciphertext_len(&self) -> usize {
+        match *self {
+            KemPrivateKey::MlKem768(_) => mlkem::CIPHERTEXT_LEN,
+            KemPrivateKey::Sntrup761(_) => sntrup761::CIPHERTEXT_LEN
+        }
     }
 
-    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
-        if msg.len() == 0 || msg[0] != SSH_MSG_KEXINIT {
-            return Err(HandshakeError::InvalidAlgorithmNegotiation(
-                    "SSH_MSG_KEXINIT not received".to_string()
-            ));
+    fn decapsulate(&self, c: &[u8]) -> Result<[u8; 32], ()> {
+        match *self {
+            KemPrivateKey::MlKem768(ref key) => key.decapsulate(c),
+            KemPrivateKey::Sntrup761(ref key) => key.decapsulate(c)
         }
+    }
+}
 
-        match deserialize::<AlgorithmNegotiation>(&msg[17..]) {
-            Err(e) => Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string())),
-            Ok(_neg) => {
-                // XXX: Actually implement algorithm implementation
-                let algorithms = NegotiatedAlgorithm {
-                    kex_algorithms: KexAlgorithm::CURVE25519_SHA256,
-                    server_host_key_algorithms: ServerHostKeyAlgorithm::SSH_RSA,
-                    encryption_algorithms_client_to_server: EncryptionAlgorithm::AES256_GCM,
-                    encryption_algorithms_server_to_client: EncryptionAlgorithm::AES256_GCM,
-                    mac_algorithms_client_to_server: MacAlgorithm::HMAC_SHA2_256,
-                    mac_algorithms_server_to_client: MacAlgorithm::HMAC_SHA2_256,
-                    compression_algorithms_client_to_server: CompressionAlgorithm::NONE,
-                    compression_algorithms_server_to_client: CompressionAlgorithm::NONE,
-                    languages_client_to_server: None,
-                    languages_server_to_client: None
-                };
+const X25519_KEY_LEN: usize = 32;
 
-                // XXX: Hash algorithm must be determined from NegotiatedAlgorithm
-                let mut ctx = Context::new(&digest::SHA256);
-                try!(digest_bytes(&mut ctx, self.v_c.as_bytes()));
-                try!(digest_bytes(&mut ctx, self.v_s.as_bytes()));
-                try!(digest_bytes(&mut ctx, &self.i_c));
-                try!(digest_bytes(&mut ctx, msg));
+// The KEM public key length of a hybrid method and its encapsulation, None for other methods
+fn kex_kem(alg: &KexAlgorithm) -> Option<(usize, fn(&[u8], &mut Rng) -> Result<(Vec<u8>, [u8; 32]), ()>)> {
+    match *alg {
+        KexAlgorithm::MLKEM768_X25519_SHA256 => Some((mlkem::PUBLIC_KEY_LEN, mlkem::encapsulate)),
+        KexAlgorithm::SNTRUP761_X25519_SHA512 => Some((sntrup761::PUBLIC_KEY_LEN, sntrup761::encapsulate)),
+        _ => None
+    }
+}
 
-                self.res = Some((algorithms, ctx));
+// K = HASH(kem_secret || x25519_secret), encoded as a string rather than an mpint
+fn hybrid_secret(hash: &'static digest::Algorithm, kem_secret: &[u8], ecdh_secret: &[u8]) -> Vec<u8> {
+    let mut ctx = Context::new(hash);
+    ctx.update(kem_secret);
+    ctx.update(ecdh_secret);
+    ctx.finish().as_ref().to_vec()
+}
 
-                Ok(())
-            }
-        }
+fn kex_curve(alg: &KexAlgorithm) -> Option<&'static agreement::Algorithm> {
+    match *alg {
+        KexAlgorithm::CURVE25519_SHA256 => Some(&agreement::X25519),
+        KexAlgorithm::ECDH_SHA2_NISTP256 => Some(&agreement::ECDH_P256),
+        KexAlgorithm::ECDH_SHA2_NISTP384 => Some(&agreement::ECDH_P384),
+        _ => None
     }
+}
 
-    fn write_packet(&self) -> Option<PacketWriteRequest> {
-        if self.written {
-            None
-        } else {
-            Some(PacketWriteRequest {
-                payload: self.i_c.clone(),
-                flush: true
-            })
-        }
+fn kex_nist_curve(alg: &KexAlgorithm) -> Option<ecdsa::Curve> {
+    match *alg {
+        KexAlgorithm::ECDH_SHA2_NISTP521 => Some(ecdsa::nistp521()),
+        _ => None
     }
+}
 
-    fn on_flush(&mut self) -> Result<(), HandshakeError> {
-        self.written = true;
-        Ok(())
+fn kex_group(alg: &KexAlgorithm) -> Option<dh::Group> {
+    match *alg {
+        KexAlgorithm::DH_GROUP14_SHA256 => Some(dh::group14()),
+        KexAlgorithm::DH_GROUP16_SHA512 => Some(dh::group16()),
+        KexAlgorithm::DH_GROUP18_SHA512 => Some(dh::group18()),
+        _ => None
     }
 }
 
-pub struct KeyExchangeState {
-    neg: NegotiatedAlgorithm,
-    keyshare: Option<(Context, agreement::EphemeralPrivateKey)>,
-    e: Vec<u8>,
-    written: bool,
-    res: Option<Vec<u8>>
+// The exchange hash and the key derivation use the hash of the key exchange method (RFC 5656 section 6.2.1)
+fn kex_hash(alg: &KexAlgorithm) -> Result<&'static digest::Algorithm, HandshakeError> {
+    match *alg {
+        KexAlgorithm::CURVE25519_SHA256 => Ok(&digest::SHA256),
+        KexAlgorithm::ECDH_SHA2_NISTP256 => Ok(&digest::SHA256),
+        KexAlgorithm::ECDH_SHA2_NISTP384 => Ok(&digest::SHA384),
+        KexAlgorithm::ECDH_SHA2_NISTP521 => Ok(&digest::SHA512),
+        KexAlgorithm::DH_GROUP14_SHA256 => Ok(&digest::SHA256),
+        KexAlgorithm::DH_GROUP16_SHA512 => Ok(&digest::SHA512),
+        KexAlgorithm::DH_GROUP18_SHA512 => Ok(&digest::SHA512),
+        KexAlgorithm::DH_GEX_SHA256 => Ok(&digest::SHA256),
+        KexAlgorithm::MLKEM768_X25519_SHA256 => Ok(&digest::SHA256),
+        KexAlgorithm::SNTRUP761_X25519_SHA512 => Ok(&digest::SHA512),
+        ref alg => Err(unsupported_algorithm(alg.as_ref()))
+    }
 }
 
-impl Future for KeyExchangeState {
-    type Item = Agreed;
-    type Error = HandshakeError;
-
-    fn poll(&mut self) -> Poll<Agreed, HandshakeError> {
-        if !self.written {
-            return Ok(Async::NotReady);
-        }
+fn is_gex(alg: &KexAlgorithm) -> bool {
+    *alg == KexAlgorithm::DH_GEX_SHA256
+}
 
-        match self.res.take() {
-            Some(session_id) => {
-                let ssh_ctx = SecureContext {
-                    neg_algorithm: self.neg.clone(),
-                    session_id: session_id
-                };
-                Ok(Async::Ready(Agreed {
-                    ctx: Some(ssh_ctx),
-                    new_key_sent: false,
-                    new_key_received: false
-                }))
-            },
-            None => Ok(Async::NotReady)
-        }
+// Group exchange sends e and f in its own init and reply messages (RFC 4419 section 3)
+fn kex_messages(alg: &KexAlgorithm) -> (u8, u8) {
+    if is_gex(alg) {
+        (SSH_MSG_KEX_DH_GEX_INIT, SSH_MSG_KEX_DH_GEX_REPLY)
+    } else {
+        (SSH_MSG_KEXDH_INIT, SSH_MSG_KEXDH_REPLY)
     }
 }
 
-impl AsyncPacketState for KeyExchangeState {
-    fn wants_read(&self) -> bool {
-        self.res.is_none()
+fn kex_message_not_received(msg_type: u8) -> HandshakeError {
+    let name = match msg_type {
+        SSH_MSG_KEXDH_INIT => "SSH_MSG_KEXDH_INIT",
+        SSH_MSG_KEXDH_REPLY => "SSH_MSG_KEXDH_REPLY",
+        SSH_MSG_KEX_DH_GEX_INIT => "SSH_MSG_KEX_DH_GEX_INIT",
+        _ => "SSH_MSG_KEX_DH_GEX_REPLY"
+    };
+    HandshakeError::InvalidAlgorithmNegotiation(format!("{} not received", name))
+}
+
+// Validates the group sent by the server against the sizes we asked for
+fn check_gex_group(req: &GexRequest, group: &GexGroup) -> Result<dh::Group, HandshakeError> {
+    let negative = |n: &[u8]| n.first().map_or(true, |&b| b & 0x80 != 0);
+    if negative(&group.p[..]) || negative(&group.g[..]) {
+        return Err(HandshakeError::KexFailed);
     }
 
-    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
-        if msg.len() == 0 || msg[0] != SSH_MSG_KEXDH_REPLY {
-            return Err(HandshakeError::InvalidAlgorithmNegotiation(
-                    "SSH_MSG_KEXDH_REPLY not received".to_string()
-            ));
+    let dh_group = dh::Group::from_bytes(from_mpint(&group.p), from_mpint(&group.g));
+    let bits = dh_group.bits() as u32;
+    if !dh_group.is_valid() || bits < req.min || bits > req.max {
+        return Err(HandshakeError::KexFailed);
+    }
+    Ok(dh_group)
+}
+
+// min || n || max || p || g, hashed between K_S and e
+fn gex_hash_params(req: &GexRequest, group: &GexGroup) -> Vec<u8> {
+    let mut params = serialize(req).unwrap();
+    params.extend(serialize(group).unwrap());
+    params
+}
+
+fn generate_ephemeral(curve: &'static agreement::Algorithm)
+        -> Result<(agreement::EphemeralPrivateKey, Vec<u8>), HandshakeError>
+{
+    let ring_rng = rand::SystemRandom::new();
+    let priv_key = match agreement::EphemeralPrivateKey::generate(curve, &ring_rng) {
+        Ok(k) => k,
+        Err(_) => return Err(HandshakeError::KexFailed)
+    };
+
+    // Curve25519 keys are 32 raw bytes, NIST curve points use the uncompressed
+    // SEC1 encoding (RFC 5656 section 4)
+    let mut key = [0u8; agreement::PUBLIC_KEY_MAX_LEN];
+    priv_key.compute_public_key(&mut key[..priv_key.public_key_len()]).unwrap();
+    let pub_key = key[..priv_key.public_key_len()].to_vec();
+    Ok((priv_key, pub_key))
+}
+
+impl KeyShare {
+    pub fn generate(alg: &KexAlgorithm) -> Result<KeyShare, HandshakeError> {
+        if let Some(group) = kex_group(alg) {
+            return KeyShare::generate_dh(alg, group);
         }
 
-        match deserialize::<KexReply>(&msg[1..]) {
-            Err(e) => Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string())),
-            Ok(reply) =>
-                if let Some((mut hash_ctx, priv_key)) = self.keyshare.take() {
-                    let pub_key = {
-                        let &ServerKey::SSH_RSA { ref e, ref n } = &reply.server_key;
-                        (
-                            untrusted::Input::from(from_mpint(n)),
-                            untrusted::Input::from(from_mpint(e))
-                        )
-                    };
-                    let k_s = serialize(&reply.server_key).unwrap();
-                    try!(digest_bytes(&mut hash_ctx, &k_s));
-                    try!(digest_bytes(&mut hash_ctx, &self.e));
-                    try!(digest_bytes(&mut hash_ctx, &reply.f));
-                    let server_pub_key = untrusted::Input::from(&reply.f);
-                    let k = try!(agreement::agree_ephemeral(priv_key,
-                                                            &agreement::X25519,
-                                                            server_pub_key,
-                                                            HandshakeError::KexFailed,
-                                                            |shared_secret| { Ok(into_mpint(shared_secret)) }
-                    ));
-                    try!(digest_bytes(&mut hash_ctx, &k));
-                    let hash = hash_ctx.finish();
-                    let h = untrusted::Input::from(&hash.as_ref());
-                    let Signature::SSH_RSA { signature: ref sgn } = reply.signature;
-                    let sgn = untrusted::Input::from(sgn);
-                    match signature::primitive::verify_rsa(&signature::RSA_PKCS1_2048_8192_SHA1,
-                                                pub_key, h, sgn) {
-                        Err(_) => {
-                            Err(HandshakeError::ServerKeyNotVerified)
-                        },
-                        Ok(()) => {
-                            self.res = Some(hash.as_ref().to_vec());
-                            Ok(())
-                        }
-                    }
-                } else {
-                    panic!("Got key reply twice");
-                }
+        let mut rng = try!(OsRng::new());
+        if let Some(kem_key) = KemPrivateKey::generate(alg, &mut rng) {
+            let (priv_key, x25519_key) = try!(generate_ephemeral(&agreement::X25519));
+            let mut pub_key = kem_key.public_key().to_vec();
+            pub_key.extend(x25519_key);
+            return Ok(KeyShare::Hybrid {
+                hash: try!(kex_hash(alg)),
+                kem_key: kem_key,
+                priv_key: priv_key,
+                pub_key: pub_key
+            });
+        }
+
+        if let Some(curve) = kex_nist_curve(alg) {
+            return Ok(KeyShare::EcdhNist { priv_key: ecdsa::EcdsaPrivateKey::generate(curve, &mut rng) });
         }
+
+        let curve = match kex_curve(alg) {
+            Some(curve) => curve,
+            None => return Err(unsupported_algorithm(alg.as_ref()))
+        };
+        let (priv_key, pub_key) = try!(generate_ephemeral(curve));
+        Ok(KeyShare::Ecdh {
+            curve: curve,
+            priv_key: priv_key,
+            pub_key: pub_key
+        })
     }
 
-    fn write_packet(&self) -> Option<PacketWriteRequest> {
-        if self.written {
-            None
-        } else {
-            let payload = serialize_msg(SSH_MSG_KEXDH_INIT, &KexInit { e: self.e.clone() }).unwrap();
+    // A key share in `group`, which is fixed by the method or sent by the server in group exchange
+    pub fn generate_dh(alg: &KexAlgorithm, group: dh::Group) -> Result<KeyShare, HandshakeError> {
+        // The private exponent has twice as many bits as the exchange hash
+        let exp_bits = try!(kex_hash(alg)).output_len * 16;
+        let mut rng = try!(OsRng::new());
+        Ok(KeyShare::from_group(group, exp_bits, &mut rng))
+    }
 
-            Some(PacketWriteRequest {
-                payload: payload,
-                flush: true
-            })
+    pub fn from_group(group: dh::Group, exp_bits: usize, rng: &mut Rng) -> KeyShare {
+        let priv_key = dh::DhPrivateKey::generate(group, exp_bits, rng);
+        let pub_key = into_mpint(&priv_key.public_key());
+        KeyShare::Dh {
+            priv_key: priv_key,
+            pub_key: pub_key
         }
     }
 
-    fn on_flush(&mut self) -> Result<(), HandshakeError> {
-        self.written = true;
-        Ok(())
+    pub fn public_key(&self) -> &[u8] {
+        match *self {
+            KeyShare::Ecdh { ref pub_key, .. } => pub_key,
+            KeyShare::EcdhNist { ref priv_key } => priv_key.public_key(),
+            KeyShare::Dh { ref pub_key, .. } => pub_key,
+            KeyShare::Hybrid { ref pub_key, .. } => pub_key
+        }
     }
-}
 
-pub struct Agreed {
-    ctx: Option<SecureContext>,
-    new_key_received: bool,
+    // Returns the shared secret K as an mpint, or as a string for the hybrid methods,
+    // after validating the peer's public key
+    pub fn agree(self, peer_pub_key: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        match self {
+            KeyShare::Ecdh { curve, priv_key, .. } =>
+                agreement::agree_ephemeral(priv_key,
+                                           curve,
+                                           untrusted::Input::from(peer_pub_key),
+                                           HandshakeError::KexFailed,
+                                           |shared_secret| { Ok(into_mpint(shared_secret)) }),
+            KeyShare::EcdhNist { priv_key } => {
+                match priv_key.agree(peer_pub_key) {
+                    Ok(k) => Ok(into_mpint(&k)),
+                    Err(_) => Err(HandshakeError::KexFailed)
+                }
+            },
+            KeyShare::Dh { priv_key, .. } => {
+                // A negative mpint is never a valid public value
+                if peer_pub_key.first().map_or(false, |&b| b & 0x80 != 0) {
+                    return Err(HandshakeError::KexFailed);
+                }
+                match priv_key.agree(from_mpint(peer_pub_key)) {
+                    Ok(k) => Ok(into_mpint(&k)),
+                    Err(_) => Err(HandshakeError::KexFailed)
+                }
+            },
+            KeyShare::Hybrid { hash, kem_key, priv_key, .. } => {
+                // The KEM ciphertext followed by the X25519 public key
+                let ct_len = kem_key.ciphertext_len();
+                if peer_pub_key.len() != ct_len + X25519_KEY_LEN {
+                    return Err(HandshakeError::KexFailed);
+                }
+                let (ct, x25519_key) = peer_pub_key.split_at(ct_len);
+                let kem_secret = try!(kem_key.decapsulate(ct));
+                agreement::agree_ephemeral(priv_key,
+                                           &agreement::X25519,
+                                           untrusted::Input::from(x25519_key),
+                                           HandshakeError::KexFailed,
+                                           |shared_secret| { Ok(hybrid_secret(hash, &kem_secret, shared_secret)) })
+            }
+        }
+    }
+
+    // The server's f and the shared secret K for the client's e. The server half of a
+    // hybrid method is an encapsulation to e, so it can't be generated ahead of e.
+    pub fn respond(alg: &KexAlgorithm, group: Option<dh::Group>, e: &[u8]) -> Result<(Vec<u8>, Vec<u8>), HandshakeError> {
+        let (pk_len, encapsulate) = match kex_kem(alg) {
+            Some(kem) => kem,
+            None => {
+                let keyshare = match group {
+                    Some(group) => try!(KeyShare::generate_dh(alg, group)),
+                    None => try!(KeyShare::generate(alg))
+                };
+                let f = keyshare.public_key().to_vec();
+                let k = try!(keyshare.agree(e));
+                return Ok((f, k));
+            }
+        };
+
+        if e.len() != pk_len + X25519_KEY_LEN {
+            return Err(HandshakeError::KexFailed);
+        }
+        let (kem_pub_key, x25519_key) = e.split_at(pk_len);
+        let mut rng = try!(OsRng::new());
+        let (mut f, kem_secret) = match encapsulate(kem_pub_key, &mut rng) {
+            Ok(res) => res,
+            Err(_) => return Err(HandshakeError::KexFailed)
+        };
+        let (priv_key, pub_key) = try!(generate_ephemeral(&agreement::X25519));
+        f.extend(pub_key);
+        let hash = try!(kex_hash(alg));
+        let k = try!(agreement::agree_ephemeral(priv_key,
+                                                &agreement::X25519,
+                                                untrusted::Input::from(x25519_key),
+                                                HandshakeError::KexFailed,
+                                                |shared_secret| { Ok(hybrid_secret(hash, &kem_secret, shared_secret)) }));
+        Ok((f, k))
+    }
+}
+
+// Both sides guess their own preferred algorithms, so a guess is right only when
+// the preferred kex and host key algorithms are the same on both sides (RFC 4253 section 7)
+fn guessed_right(client: &AlgorithmNegotiation, server: &AlgorithmNegotiation) -> bool {
+    client.kex_algorithms.first() == server.kex_algorithms.first()
+        && client.server_host_key_algorithms.first() == server.server_host_key_algorithms.first()
+}
+
+// Checks the signature of the exchange hash with the host key the server sent. Both have to
+// be of the negotiated host key algorithm, so a server can't fall back to SHA-1 signatures
+// unless the client offered ssh-rsa.
+fn verify_server_signature(alg: &ServerHostKeyAlgorithm, server_key: &ServerKey, h: &[u8], sgn: &Signature)
+        -> Result<(), HandshakeError>
+{
+    let msg = untrusted::Input::from(h);
+    let verified = match (alg, server_key, sgn) {
+        (&ServerHostKeyAlgorithm::SSH_RSA, &ServerKey::SSH_RSA { ref e, ref n }, &Signature::SSH_RSA { signature: ref sgn }) => {
+            let pub_key = (untrusted::Input::from(from_mpint(n)), untrusted::Input::from(from_mpint(e)));
+            signature::primitive::verify_rsa(&signature::RSA_PKCS1_2048_8192_SHA1,
+                                             pub_key, msg, untrusted::Input::from(sgn))
+        },
+        (&ServerHostKeyAlgorithm::RSA_SHA2_256, &ServerKey::SSH_RSA { ref e, ref n }, &Signature::RSA_SHA2_256 { signature: ref sgn }) => {
+            let pub_key = (untrusted::Input::from(from_mpint(n)), untrusted::Input::from(from_mpint(e)));
+            signature::primitive::verify_rsa(&signature::RSA_PKCS1_2048_8192_SHA256,
+                                             pub_key, msg, untrusted::Input::from(sgn))
+        },
+        (&ServerHostKeyAlgorithm::RSA_SHA2_512, &ServerKey::SSH_RSA { ref e, ref n }, &Signature::RSA_SHA2_512 { signature: ref sgn }) => {
+            let pub_key = (untrusted::Input::from(from_mpint(n)), untrusted::Input::from(from_mpint(e)));
+            signature::primitive::verify_rsa(&signature::RSA_PKCS1_2048_8192_SHA512,
+                                             pub_key, msg, untrusted::Input::from(sgn))
+        },
+        (&ServerHostKeyAlgorithm::SSH_ED25519, &ServerKey::SSH_ED25519 { ref key }, &Signature::SSH_ED25519 { signature: ref sgn }) =>
+            signature::verify(&signature::ED25519, untrusted::Input::from(key), msg, untrusted::Input::from(sgn)),
+        (&ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256, &ServerKey::ECDSA_SHA2_NISTP256 { ref curve, ref q }, &Signature::ECDSA_SHA2_NISTP256 { signature: ref sgn })
+        | (&ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384, &ServerKey::ECDSA_SHA2_NISTP384 { ref curve, ref q }, &Signature::ECDSA_SHA2_NISTP384 { signature: ref sgn })
+        | (&ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP521, &ServerKey::ECDSA_SHA2_NISTP521 { ref curve, ref q }, &Signature::ECDSA_SHA2_NISTP521 { signature: ref sgn }) =>
+            return verify_ecdsa(alg, curve, q, h, sgn),
+        _ =>
+            return Err(HandshakeError::InvalidKexReply(
+                    format!("expected a {} host key and signature", alg.as_ref())
+            ))
+    };
+    verified.map_err(|_| HandshakeError::ServerKeyNotVerified)
+}
+
+// The key blob names its curve again, which has to be the curve of the algorithm
+fn verify_ecdsa(alg: &ServerHostKeyAlgorithm, curve_name: &str, q: &[u8], h: &[u8], sgn: &EcdsaSignature)
+        -> Result<(), HandshakeError>
+{
+    let (curve, digest_alg) = match hostkey::ecdsa_curve(alg) {
+        Some(params) => params,
+        None => return Err(unsupported_algorithm(alg.as_ref()))
+    };
+    if curve_name != curve.name() {
+        return Err(HandshakeError::InvalidKexReply(format!("unexpected curve {}", curve_name)));
+    }
+    // r and s are positive mpints
+    if sgn.r.first().map_or(true, |&b| b & 0x80 != 0) || sgn.s.first().map_or(true, |&b| b & 0x80 != 0) {
+        return Err(HandshakeError::ServerKeyNotVerified);
+    }
+
+    let digest = digest::digest(digest_alg, h);
+    ecdsa::verify(&curve, q, digest.as_ref(), from_mpint(&sgn.r), from_mpint(&sgn.s))
+        .map_err(|_| HandshakeError::ServerKeyNotVerified)
+}
+
+fn digest_bytes(ctx: &mut Context, bytes: &[u8]) -> Result<(), HandshakeError> {
+    let len: u32 = match TryFrom::try_from(bytes.len()) {
+        Ok(l) => l,
+        Err(_) => return Err(HandshakeError::KexFailed)
+    };
+
+    ctx.update(&hton(len));
+    ctx.update(bytes);
+    Ok(())
+}
+
+fn derive_key(hash_alg: &'static digest::Algorithm, k: &[u8], h: &[u8], x: u8, session_id: &[u8], len: usize)
+        -> Result<Vec<u8>, HandshakeError>
+{
+    // K1 = HASH(K || H || X || session_id)
+    let mut ctx = Context::new(hash_alg);
+    try!(digest_bytes(&mut ctx, k));
+    ctx.update(h);
+    ctx.update(&[x]);
+    ctx.update(session_id);
+    let mut key = ctx.finish().as_ref().to_vec();
+
+    // Kn = HASH(K || H || K1 || ... || Kn-1)
+    while key.len() < len {
+        let mut ctx = Context::new(hash_alg);
+        try!(digest_bytes(&mut ctx, k));
+        ctx.update(h);
+        ctx.update(&key);
+        key.extend_from_slice(ctx.finish().as_ref());
+    }
+
+    key.truncate(len);
+    Ok(key)
+}
+
+pub fn parse_ext_info(msg: &[u8]) -> Result<HashMap<String, Extension>, HandshakeError> {
+    if msg.len() == 0 || msg[0] != SSH_MSG_EXT_INFO {
+        return Err(HandshakeError::InvalidExtInfo("SSH_MSG_EXT_INFO not received".to_string()));
+    }
+
+    let info = match deserialize::<ExtInfo>(&msg[1..]) {
+        Ok(info) => info,
+        Err(e) => return Err(HandshakeError::InvalidExtInfo(e.to_string()))
+    };
+
+    let mut extensions = HashMap::new();
+    for ext in info.extensions {
+        match Extension::parse(&ext.name, &ext.value) {
+            Ok(parsed) => { extensions.insert(ext.name, parsed); },
+            Err(e) => return Err(HandshakeError::InvalidExtInfo(format!("{}: {}", ext.name, e)))
+        }
+    }
+    Ok(extensions)
+}
+
+fn unsupported_algorithm(name: &str) -> HandshakeError {
+    HandshakeError::InvalidAlgorithmNegotiation(format!("unsupported algorithm {}", name))
+}
+
+fn supported_compression(alg: &CompressionAlgorithm) -> Result<CompressionAlgorithm, HandshakeError> {
+    match *alg {
+        CompressionAlgorithm::Unknown(ref name) => Err(unsupported_algorithm(name)),
+        ref alg => Ok(alg.clone())
+    }
+}
+
+fn enc_key_len(alg: &EncryptionAlgorithm) -> Result<(usize, usize), HandshakeError> {
+    match (alg.iv_len(), alg.key_len()) {
+        (Some(iv_len), Some(key_len)) => Ok((iv_len, key_len)),
+        _ => Err(unsupported_algorithm(alg.as_ref()))
+    }
+}
+
+fn mac_key_len(alg: &Option<MacAlgorithm>) -> Result<usize, HandshakeError> {
+    match *alg {
+        None => Ok(0),
+        Some(ref mac) => match mac.key_len() {
+            Some(len) => Ok(len),
+            None => Err(unsupported_algorithm(mac.as_ref()))
+        }
+    }
+}
+
+pub fn derive_session_keys(neg: &NegotiatedAlgorithm,
+                           hash_alg: &'static digest::Algorithm,
+                           k: &[u8],
+                           h: &[u8],
+                           session_id: &[u8])
+        -> Result<SessionKeys, HandshakeError>
+{
+    let (iv_c2s_len, enc_c2s_len) = try!(enc_key_len(&neg.encryption_algorithms_client_to_server));
+    let (iv_s2c_len, enc_s2c_len) = try!(enc_key_len(&neg.encryption_algorithms_server_to_client));
+    let mac_c2s_len = try!(mac_key_len(&neg.mac_algorithms_client_to_server));
+    let mac_s2c_len = try!(mac_key_len(&neg.mac_algorithms_server_to_client));
+
+    Ok(SessionKeys {
+        iv_client_to_server: try!(derive_key(hash_alg, k, h, b'A', session_id, iv_c2s_len)),
+        iv_server_to_client: try!(derive_key(hash_alg, k, h, b'B', session_id, iv_s2c_len)),
+        enc_key_client_to_server: try!(derive_key(hash_alg, k, h, b'C', session_id, enc_c2s_len)),
+        enc_key_server_to_client: try!(derive_key(hash_alg, k, h, b'D', session_id, enc_s2c_len)),
+        mac_key_client_to_server: try!(derive_key(hash_alg, k, h, b'E', session_id, mac_c2s_len)),
+        mac_key_server_to_client: try!(derive_key(hash_alg, k, h, b'F', session_id, mac_s2c_len))
+    })
+}
+
+impl Future for AlgorithmExchangeState {
+    type Item = ClientKex;
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<ClientKex, HandshakeError> {
+        // The peer may send its KEXINIT first when it starts a re-key
+        if !self.written || (self.guess.is_some() && !self.guess_written) {
+            return Ok(Async::NotReady);
+        }
+
+        match self.res.take() {
+            Some((neg, ctx)) => {
+                if is_gex(&neg.kex_algorithms) {
+                    return Ok(Async::Ready(ClientKex::GroupExchange(GroupExchangeState {
+                        v_c: self.v_c.clone(),
+                        v_s: self.v_s.clone(),
+                        client_algorithms: self.neg.clone(),
+                        session_id: self.session_id.take(),
+                        strict: self.strict,
+                        neg: neg,
+                        hash_ctx: Some(ctx),
+                        request: self.gex_request,
+                        written: false,
+                        ignore_next: self.ignore_next,
+                        group: None
+                    })));
+                }
+
+                // A wrong guess of ours is ignored by the server, so the key exchange starts over
+                let (keyshare, written) = match self.guess.take() {
+                    Some(keyshare) if self.guess_ok => (keyshare, true),
+                    _ => (try!(KeyShare::generate(&neg.kex_algorithms)), false)
+                };
+                let e = keyshare.public_key().to_vec();
+                Ok(Async::Ready(ClientKex::KeyExchange(KeyExchangeState {
+                    v_c: self.v_c.clone(),
+                    v_s: self.v_s.clone(),
+                    client_algorithms: self.neg.clone(),
+                    session_id: self.session_id.take(),
+                    strict: self.strict,
+                    neg: neg,
+                    gex_request: self.gex_request,
+                    gex_params: Vec::new(),
+                    keyshare: Some((ctx, keyshare)),
+                    e: e,
+                    written: written,
+                    ignore_next: self.ignore_next,
+                    res: None
+                })))
+            }
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl AsyncPacketState for AlgorithmExchangeState {
+    fn wants_read(&self) -> bool {
+        self.res.is_none()
+    }
+
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
+        if msg.len() < 17 || msg[0] != SSH_MSG_KEXINIT {
+            return Err(HandshakeError::InvalidAlgorithmNegotiation(
+                    "SSH_MSG_KEXINIT not received".to_string()
+            ));
+        }
+
+        match deserialize::<AlgorithmNegotiation>(&msg[17..]) {
+            Err(e) => Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string())),
+            Ok(server_neg) => {
+                let algorithms = try!(negotiate(&self.neg, &server_neg));
+                if self.session_id.is_none() {
+                    self.strict = self.neg.kex_algorithms.contains(&KexAlgorithm::KEX_STRICT_C)
+                        && server_neg.kex_algorithms.contains(&KexAlgorithm::KEX_STRICT_S);
+                }
+                self.guess_ok = guessed_right(&self.neg, &server_neg);
+                // The packet following a wrong guess of the server must be ignored
+                self.ignore_next = server_neg.first_kex_packet_follows && !self.guess_ok;
+
+                let mut ctx = Context::new(try!(kex_hash(&algorithms.kex_algorithms)));
+                try!(digest_bytes(&mut ctx, self.v_c.as_bytes()));
+                try!(digest_bytes(&mut ctx, self.v_s.as_bytes()));
+                try!(digest_bytes(&mut ctx, &self.i_c));
+                try!(digest_bytes(&mut ctx, msg));
+
+                self.res = Some((algorithms, ctx));
+
+                Ok(())
+            }
+        }
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        if !self.written {
+            Some(PacketWriteRequest {
+                payload: self.i_c.clone(),
+                flush: self.guess.is_none()
+            })
+        } else if !self.guess_written {
+            self.guess.as_ref().map(|keyshare| PacketWriteRequest {
+                payload: serialize_msg(SSH_MSG_KEXDH_INIT, &KexInit { e: keyshare.public_key().to_vec() }).unwrap(),
+                flush: true
+            })
+        } else {
+            None
+        }
+    }
+
+    fn on_flush(&mut self) -> Result<(), HandshakeError> {
+        if !self.written {
+            self.written = true;
+        } else {
+            self.guess_written = true;
+        }
+        Ok(())
+    }
+}
+
+// Asks the server for a group before the key exchange proper (RFC 4419 section 3)
+pub struct GroupExchangeState {
+    v_c: String,
+    v_s: String,
+    client_algorithms: AlgorithmNegotiation,
+    session_id: Option<Vec<u8>>,
+    strict: bool,
+    neg: NegotiatedAlgorithm,
+    hash_ctx: Option<Context>,
+    request: GexRequest,
+    written: bool,
+    ignore_next: bool,
+    // The group sent by the server, with the parameters it adds to the exchange hash
+    group: Option<(dh::Group, Vec<u8>)>
+}
+
+impl Future for GroupExchangeState {
+    type Item = KeyExchangeState;
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<KeyExchangeState, HandshakeError> {
+        if !self.written {
+            return Ok(Async::NotReady);
+        }
+
+        match self.group.take() {
+            Some((group, gex_params)) => {
+                let keyshare = try!(KeyShare::generate_dh(&self.neg.kex_algorithms, group));
+                let e = keyshare.public_key().to_vec();
+                let ctx = match self.hash_ctx.take() {
+                    Some(ctx) => ctx,
+                    None => panic!("Called GroupExchangeState::poll() twice")
+                };
+                Ok(Async::Ready(KeyExchangeState {
+                    v_c: self.v_c.clone(),
+                    v_s: self.v_s.clone(),
+                    client_algorithms: self.client_algorithms.clone(),
+                    session_id: self.session_id.take(),
+                    strict: self.strict,
+                    neg: self.neg.clone(),
+                    gex_request: self.request,
+                    gex_params: gex_params,
+                    keyshare: Some((ctx, keyshare)),
+                    e: e,
+                    written: false,
+                    ignore_next: false,
+                    res: None
+                }))
+            },
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl AsyncPacketState for GroupExchangeState {
+    fn wants_read(&self) -> bool {
+        self.group.is_none()
+    }
+
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
+        if self.ignore_next {
+            self.ignore_next = false;
+            return Ok(());
+        }
+
+        if msg.len() == 0 || msg[0] != SSH_MSG_KEX_DH_GEX_GROUP {
+            return Err(HandshakeError::InvalidAlgorithmNegotiation(
+                    "SSH_MSG_KEX_DH_GEX_GROUP not received".to_string()
+            ));
+        }
+
+        let group = match deserialize::<GexGroup>(&msg[1..]) {
+            Ok(group) => group,
+            Err(e) => return Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string()))
+        };
+        let dh_group = try!(check_gex_group(&self.request, &group));
+        self.group = Some((dh_group, gex_hash_params(&self.request, &group)));
+        Ok(())
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        if self.written {
+            None
+        } else {
+            Some(PacketWriteRequest {
+                payload: serialize_msg(SSH_MSG_KEX_DH_GEX_REQUEST, &self.request).unwrap(),
+                flush: true
+            })
+        }
+    }
+
+    fn on_flush(&mut self) -> Result<(), HandshakeError> {
+        self.written = true;
+        Ok(())
+    }
+}
+
+pub struct KeyExchangeState {
+    v_c: String,
+    v_s: String,
+    client_algorithms: AlgorithmNegotiation,
+    session_id: Option<Vec<u8>>,
+    strict: bool,
+    neg: NegotiatedAlgorithm,
+    gex_request: GexRequest,
+    // Empty unless the group came from a group exchange
+    gex_params: Vec<u8>,
+    keyshare: Option<(Context, KeyShare)>,
+    e: Vec<u8>,
+    written: bool,
+    ignore_next: bool,
+    res: Option<(Vec<u8>, digest::Digest)>
+}
+
+impl Future for KeyExchangeState {
+    type Item = Agreed;
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<Agreed, HandshakeError> {
+        if !self.written {
+            return Ok(Async::NotReady);
+        }
+
+        match self.res.take() {
+            Some((k, hash)) => {
+                let h = hash.as_ref();
+                // The exchange hash of the first key exchange identifies the whole session
+                let session_id = self.session_id.take().unwrap_or(h.to_vec());
+                let keys = try!(derive_session_keys(&self.neg, hash.algorithm(), &k, h, &session_id));
+                let ssh_ctx = SecureContext {
+                    neg_algorithm: self.neg.clone(),
+                    session_id: session_id,
+                    keys: keys,
+                    v_c: self.v_c.clone(),
+                    v_s: self.v_s.clone(),
+                    local_algorithms: self.client_algorithms.clone(),
+                    gex_request: self.gex_request,
+                    strict: self.strict,
+                    extensions: HashMap::new(),
+                    server: None
+                };
+                let sealer = NewKeys {
+                    cipher: try!(ssh_ctx.client_sealer()),
+                    compression: try!(supported_compression(&self.neg.compression_algorithms_client_to_server)),
+                    reset_seq: self.strict
+                };
+                let opener = NewKeys {
+                    cipher: try!(ssh_ctx.client_opener()),
+                    compression: try!(supported_compression(&self.neg.compression_algorithms_server_to_client)),
+                    reset_seq: self.strict
+                };
+                Ok(Async::Ready(Agreed {
+                    ctx: Some(ssh_ctx),
+                    sealer: Some(sealer),
+                    opener: Some(opener),
+                    new_key_sent: false,
+                    new_key_received: false
+                }))
+            },
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl AsyncPacketState for KeyExchangeState {
+    fn wants_read(&self) -> bool {
+        self.res.is_none()
+    }
+
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
+        if self.ignore_next {
+            self.ignore_next = false;
+            return Ok(());
+        }
+
+        let (_, reply_msg) = kex_messages(&self.neg.kex_algorithms);
+        if msg.len() == 0 || msg[0] != reply_msg {
+            return Err(kex_message_not_received(reply_msg));
+        }
+
+        match deserialize::<KexReply>(&msg[1..]) {
+            Err(e) => Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string())),
+            Ok(reply) =>
+                if let Some((mut hash_ctx, keyshare)) = self.keyshare.take() {
+                    let k_s = serialize(&reply.server_key).unwrap();
+                    try!(digest_bytes(&mut hash_ctx, &k_s));
+                    hash_ctx.update(&self.gex_params);
+                    try!(digest_bytes(&mut hash_ctx, &self.e));
+                    try!(digest_bytes(&mut hash_ctx, &reply.f));
+                    let k = try!(keyshare.agree(&reply.f));
+                    try!(digest_bytes(&mut hash_ctx, &k));
+                    let hash = hash_ctx.finish();
+                    try!(verify_server_signature(&self.neg.server_host_key_algorithms,
+                                                 &reply.server_key, hash.as_ref(), &reply.signature));
+                    self.res = Some((k, hash));
+                    Ok(())
+                } else {
+                    Err(HandshakeError::UnexpectedMessage(reply_msg))
+                }
+        }
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        if self.written {
+            None
+        } else {
+            let (init_msg, _) = kex_messages(&self.neg.kex_algorithms);
+            let payload = serialize_msg(init_msg, &KexInit { e: self.e.clone() }).unwrap();
+
+            Some(PacketWriteRequest {
+                payload: payload,
+                flush: true
+            })
+        }
+    }
+
+    fn on_flush(&mut self) -> Result<(), HandshakeError> {
+        self.written = true;
+        Ok(())
+    }
+}
+
+pub struct Agreed {
+    ctx: Option<SecureContext>,
+    sealer: Option<NewKeys<Box<PacketSealer>>>,
+    opener: Option<NewKeys<Box<PacketOpener>>>,
+    new_key_received: bool,
     new_key_sent: bool
 }
 
-impl Future for Agreed {
+impl Future for Agreed {
+    type Item = SecureContext;
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<SecureContext, HandshakeError> {
+        if self.new_key_sent && self.new_key_received {
+            match self.ctx.take() {
+                Some(ctx) => Ok(Async::Ready(ctx)),
+                None => panic!("Called Agreed::poll() twice")
+            }
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl AsyncPacketState for Agreed {
+    fn wants_read(&self) -> bool {
+        !self.new_key_received
+    }
+
+    fn on_read(&mut self, buf: &[u8]) -> Result<(), HandshakeError> {
+        if buf == &[SSH_MSG_NEWKEYS] {
+            self.new_key_received = true;
+            Ok(())
+        } else {
+            Err(HandshakeError::ExpectedNewKeys)
+        }
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        if self.new_key_sent {
+            None
+        } else {
+            Some(PacketWriteRequest { payload: vec![SSH_MSG_NEWKEYS], flush: true })
+        }
+    }
+
+    fn on_flush(&mut self) -> Result<(), Self::Error> {
+        self.new_key_sent = true;
+        Ok(())
+    }
+
+    fn take_sealer(&mut self) -> Option<NewKeys<Box<PacketSealer>>> {
+        if self.new_key_sent {
+            self.sealer.take()
+        } else {
+            None
+        }
+    }
+
+    fn take_opener(&mut self) -> Option<NewKeys<Box<PacketOpener>>> {
+        if self.new_key_received {
+            self.opener.take()
+        } else {
+            None
+        }
+    }
+}
+
+pub struct ServerKeyExchange {
+    st: ServerKex,
+    initial: bool,
+    // A generic message arrived before the peer's KEXINIT, which strict key exchange forbids
+    unexpected_before_kexinit: Option<u8>
+}
+
+impl ServerKeyExchange {
+    fn new(neg: AlgorithmNegotiation, host_key: Arc<HostKey>, v_c: String, v_s: String, session_id: Option<Vec<u8>>, strict: bool)
+            -> Result<ServerKeyExchange, HandshakeError>
+    {
+        let mut neg = neg;
+        let initial = session_id.is_none();
+        if initial {
+            if !neg.kex_algorithms.contains(&KexAlgorithm::KEX_STRICT_S) {
+                neg.kex_algorithms.push(KexAlgorithm::KEX_STRICT_S);
+            }
+        } else {
+            neg.kex_algorithms.retain(|alg| !alg.is_pseudo());
+        }
+        // Only the configured algorithms our host key can sign with are offered, and the client always
+        // sends the first key exchange packet
+        let key_algorithms = host_key.algorithms();
+        neg.server_host_key_algorithms.retain(|alg| key_algorithms.contains(alg));
+        neg.first_kex_packet_follows = false;
+
+        let mut rng = try!(OsRng::new());
+        let i_s = try!(build_kexinit_payload(&neg, &mut rng));
+        let st = ServerAlgorithmExchangeState {
+            v_c: v_c,
+            v_s: v_s,
+            neg: neg,
+            i_s: i_s,
+            host_key: host_key,
+            moduli: Arc::new(dh::default_moduli()),
+            session_id: session_id,
+            strict: strict,
+            written: false,
+            ignore_next: false,
+            res: None
+        };
+
+        Ok(ServerKeyExchange {
+            st: ServerKex::AlgorithmExchange(st),
+            initial: initial,
+            unexpected_before_kexinit: None
+        })
+    }
+
+    // None until the peer's KEXINIT arrives
+    fn strict(&self) -> Option<bool> {
+        match self.st {
+            ServerKex::AlgorithmExchange(ref st) => st.res.as_ref().map(|_| st.strict),
+            ServerKex::GroupExchange(ref st) => Some(st.strict),
+            ServerKex::KeyExchange(ref st) => Some(st.strict),
+            ServerKex::Agreed(ref st) => st.ctx.as_ref().map(|ctx| ctx.strict)
+        }
+    }
+
+    // Starts a key exchange inside an established session, with the same host key and moduli. The session
+    // identifier never changes.
+    pub fn rekey(ctx: &SecureContext) -> Result<ServerKeyExchange, HandshakeError> {
+        let server = match ctx.server {
+            Some(ref server) => server,
+            None => return Err(HandshakeError::Panic("ServerKeyExchange::rekey() needs a server context".to_string()))
+        };
+        let mut kex = try!(ServerKeyExchange::new(ctx.local_algorithms.clone(),
+                                                  server.host_key.clone(),
+                                                  ctx.v_c.clone(),
+                                                  ctx.v_s.clone(),
+                                                  Some(ctx.session_id.clone()),
+                                                  ctx.strict));
+        kex.set_moduli(server.moduli.clone());
+        Ok(kex)
+    }
+
+    // The groups offered in diffie-hellman-group-exchange, e.g. from `dh::load_moduli()`
+    pub fn set_moduli(&mut self, moduli: Arc<Vec<dh::Group>>) {
+        if let ServerKex::AlgorithmExchange(ref mut st) = self.st {
+            st.moduli = moduli;
+        }
+    }
+}
+
+pub enum ServerKex {
+    AlgorithmExchange(ServerAlgorithmExchangeState),
+    GroupExchange(ServerGroupExchangeState),
+    KeyExchange(ServerKeyExchangeState),
+    Agreed(Agreed)
+}
+
+impl Future for ServerKeyExchange {
     type Item = SecureContext;
     type Error = HandshakeError;
 
     fn poll(&mut self) -> Poll<SecureContext, HandshakeError> {
-        if self.new_key_sent && self.new_key_received {
-            match self.ctx.take() {
-                Some(ctx) => Ok(Async::Ready(ctx)),
-                None => panic!("Called Agreed::poll() twice")
+        let next_st = match self.st {
+            ServerKex::AlgorithmExchange(ref mut st) => {
+                if let Async::Ready(next) = try!(st.poll()) {
+                    next
+                } else {
+                    return Ok(Async::NotReady);
+                }
+            },
+            ServerKex::GroupExchange(ref mut st) => {
+                if let Async::Ready(kex) = try!(st.poll()) {
+                    ServerKex::KeyExchange(kex)
+                } else {
+                    return Ok(Async::NotReady);
+                }
+            },
+            ServerKex::KeyExchange(ref mut st) => {
+                if let Async::Ready(agreed) = try!(st.poll()) {
+                    ServerKex::Agreed(agreed)
+                } else {
+                    return Ok(Async::NotReady);
+                }
+            },
+            ServerKex::Agreed(ref mut st) => return st.poll()
+        };
+
+        self.st = next_st;
+        Ok(Async::NotReady)
+    }
+}
+
+impl AsyncPacketState for ServerKeyExchange {
+    fn wants_read(&self) -> bool {
+        match self.st {
+            ServerKex::AlgorithmExchange(ref st) => st.wants_read(),
+            ServerKex::GroupExchange(ref st) => st.wants_read(),
+            ServerKex::KeyExchange(ref st) => st.wants_read(),
+            ServerKex::Agreed(ref st) => st.wants_read(),
+        }
+    }
+
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), Self::Error> {
+        try!(match self.st {
+            ServerKex::AlgorithmExchange(ref mut st) => st.on_read(msg),
+            ServerKex::GroupExchange(ref mut st) => st.on_read(msg),
+            ServerKex::KeyExchange(ref mut st) => st.on_read(msg),
+            ServerKex::Agreed(ref mut st) => st.on_read(msg)
+        });
+
+        check_strict_pending(self.strict(), self.unexpected_before_kexinit)
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        match self.st {
+            ServerKex::AlgorithmExchange(ref st) => st.write_packet(),
+            ServerKex::GroupExchange(ref st) => st.write_packet(),
+            ServerKex::KeyExchange(ref st) => st.write_packet(),
+            ServerKex::Agreed(ref st) => st.write_packet(),
+        }
+    }
+
+    fn on_flush(&mut self) -> Result<(), Self::Error> {
+        match self.st {
+            ServerKex::AlgorithmExchange(ref mut st) => st.on_flush(),
+            ServerKex::GroupExchange(ref mut st) => st.on_flush(),
+            ServerKex::KeyExchange(ref mut st) => st.on_flush(),
+            ServerKex::Agreed(ref mut st) => st.on_flush(),
+        }
+    }
+
+    fn take_sealer(&mut self) -> Option<NewKeys<Box<PacketSealer>>> {
+        match self.st {
+            ServerKex::Agreed(ref mut st) => st.take_sealer(),
+            _ => None
+        }
+    }
+
+    fn take_opener(&mut self) -> Option<NewKeys<Box<PacketOpener>>> {
+        match self.st {
+            ServerKex::Agreed(ref mut st) => st.take_opener(),
+            _ => None
+        }
+    }
+
+    fn is_known(&self, msg_type: u8) -> bool {
+        msg_type >= SSH_MSG_KEXINIT && msg_type <= 49
+    }
+
+    fn on_transport_msg(&mut self, msg_type: u8) -> Result<(), HandshakeError> {
+        let strict = self.strict();
+        check_strict_transport_msg(self.initial, strict, &mut self.unexpected_before_kexinit, msg_type)
+    }
+}
+
+pub struct ServerAlgorithmExchangeState {
+    v_c: String,
+    v_s: String,
+    neg: AlgorithmNegotiation,
+    i_s: Vec<u8>,
+    host_key: Arc<HostKey>,
+    moduli: Arc<Vec<dh::Group>>,
+    session_id: Option<Vec<u8>>,
+    strict: bool,
+    written: bool,
+    ignore_next: bool,
+    res: Option<(NegotiatedAlgorithm, Context)>
+}
+
+impl Future for ServerAlgorithmExchangeState {
+    type Item = ServerKex;
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<ServerKex, HandshakeError> {
+        if !self.written {
+            return Ok(Async::NotReady);
+        }
+
+        match self.res.take() {
+            Some((neg, ctx)) => {
+                if is_gex(&neg.kex_algorithms) {
+                    return Ok(Async::Ready(ServerKex::GroupExchange(ServerGroupExchangeState {
+                        v_c: self.v_c.clone(),
+                        v_s: self.v_s.clone(),
+                        server_algorithms: self.neg.clone(),
+                        host_key: self.host_key.clone(),
+                        moduli: self.moduli.clone(),
+                        session_id: self.session_id.take(),
+                        strict: self.strict,
+                        neg: neg,
+                        hash_ctx: Some(ctx),
+                        ignore_next: self.ignore_next,
+                        reply: None,
+                        written: false
+                    })));
+                }
+
+                Ok(Async::Ready(ServerKex::KeyExchange(ServerKeyExchangeState {
+                    v_c: self.v_c.clone(),
+                    v_s: self.v_s.clone(),
+                    server_algorithms: self.neg.clone(),
+                    host_key: self.host_key.clone(),
+                    moduli: self.moduli.clone(),
+                    session_id: self.session_id.take(),
+                    strict: self.strict,
+                    neg: neg,
+                    group: None,
+                    gex_params: Vec::new(),
+                    hash_ctx: Some(ctx),
+                    ignore_next: self.ignore_next,
+                    reply: None,
+                    written: false
+                })))
+            },
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl AsyncPacketState for ServerAlgorithmExchangeState {
+    fn wants_read(&self) -> bool {
+        self.res.is_none()
+    }
+
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
+        if msg.len() < 17 || msg[0] != SSH_MSG_KEXINIT {
+            return Err(HandshakeError::InvalidAlgorithmNegotiation(
+                    "SSH_MSG_KEXINIT not received".to_string()
+            ));
+        }
+
+        match deserialize::<AlgorithmNegotiation>(&msg[17..]) {
+            Err(e) => Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string())),
+            Ok(client_neg) => {
+                let algorithms = try!(negotiate(&client_neg, &self.neg));
+                if self.session_id.is_none() {
+                    self.strict = client_neg.kex_algorithms.contains(&KexAlgorithm::KEX_STRICT_C)
+                        && self.neg.kex_algorithms.contains(&KexAlgorithm::KEX_STRICT_S);
+                }
+                // The packet following a wrong guess of the client must be ignored
+                self.ignore_next = client_neg.first_kex_packet_follows && !guessed_right(&client_neg, &self.neg);
+
+                let mut ctx = Context::new(try!(kex_hash(&algorithms.kex_algorithms)));
+                try!(digest_bytes(&mut ctx, self.v_c.as_bytes()));
+                try!(digest_bytes(&mut ctx, self.v_s.as_bytes()));
+                try!(digest_bytes(&mut ctx, msg));
+                try!(digest_bytes(&mut ctx, &self.i_s));
+
+                self.res = Some((algorithms, ctx));
+
+                Ok(())
             }
+        }
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        if self.written {
+            None
         } else {
-            Ok(Async::NotReady)
+            Some(PacketWriteRequest {
+                payload: self.i_s.clone(),
+                flush: true
+            })
+        }
+    }
+
+    fn on_flush(&mut self) -> Result<(), HandshakeError> {
+        self.written = true;
+        Ok(())
+    }
+}
+
+// Picks a group of the size requested by the client and sends it
+pub struct ServerGroupExchangeState {
+    v_c: String,
+    v_s: String,
+    server_algorithms: AlgorithmNegotiation,
+    host_key: Arc<HostKey>,
+    moduli: Arc<Vec<dh::Group>>,
+    session_id: Option<Vec<u8>>,
+    strict: bool,
+    neg: NegotiatedAlgorithm,
+    hash_ctx: Option<Context>,
+    ignore_next: bool,
+    // SSH_MSG_KEX_DH_GEX_GROUP payload, the chosen group and the parameters it adds to the exchange hash
+    reply: Option<(Vec<u8>, dh::Group, Vec<u8>)>,
+    written: bool
+}
+
+impl Future for ServerGroupExchangeState {
+    type Item = ServerKeyExchangeState;
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<ServerKeyExchangeState, HandshakeError> {
+        if !self.written {
+            return Ok(Async::NotReady);
+        }
+
+        match self.reply.take() {
+            Some((_, group, gex_params)) => Ok(Async::Ready(ServerKeyExchangeState {
+                v_c: self.v_c.clone(),
+                v_s: self.v_s.clone(),
+                server_algorithms: self.server_algorithms.clone(),
+                host_key: self.host_key.clone(),
+                moduli: self.moduli.clone(),
+                session_id: self.session_id.take(),
+                strict: self.strict,
+                neg: self.neg.clone(),
+                group: Some(group),
+                gex_params: gex_params,
+                hash_ctx: self.hash_ctx.take(),
+                ignore_next: false,
+                reply: None,
+                written: false
+            })),
+            None => Ok(Async::NotReady)
         }
     }
 }
 
-impl AsyncPacketState for Agreed {
+impl AsyncPacketState for ServerGroupExchangeState {
     fn wants_read(&self) -> bool {
-        !self.new_key_received
+        self.reply.is_none()
     }
 
-    fn on_read(&mut self, buf: &[u8]) -> Result<(), HandshakeError> {
-        if buf == &[SSH_MSG_NEWKEYS] {
-            self.new_key_received = true;
-            Ok(())
-        } else {
-            Err(HandshakeError::ExpectedNewKeys)
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
+        if self.ignore_next {
+            self.ignore_next = false;
+            return Ok(());
+        }
+
+        if msg.len() == 0 || msg[0] != SSH_MSG_KEX_DH_GEX_REQUEST {
+            return Err(HandshakeError::InvalidAlgorithmNegotiation(
+                    "SSH_MSG_KEX_DH_GEX_REQUEST not received".to_string()
+            ));
+        }
+
+        let req = match deserialize::<GexRequest>(&msg[1..]) {
+            Ok(req) => req,
+            Err(e) => return Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string()))
+        };
+        if req.min > req.n || req.n > req.max {
+            return Err(HandshakeError::KexFailed);
+        }
+
+        let group = match dh::choose_group(&self.moduli, req.min, req.n, req.max) {
+            Some(group) => group,
+            None => return Err(HandshakeError::KexFailed)
+        };
+        let gex_group = GexGroup {
+            p: into_mpint(&group.p.to_bytes_be()),
+            g: into_mpint(&group.g.to_bytes_be())
+        };
+        let payload = serialize_msg(SSH_MSG_KEX_DH_GEX_GROUP, &gex_group).unwrap();
+        self.reply = Some((payload, group, gex_hash_params(&req, &gex_group)));
+        Ok(())
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        match self.reply {
+            Some((ref payload, _, _)) if !self.written => Some(PacketWriteRequest {
+                payload: payload.clone(),
+                flush: true
+            }),
+            _ => None
+        }
+    }
+
+    fn on_flush(&mut self) -> Result<(), HandshakeError> {
+        self.written = true;
+        Ok(())
+    }
+}
+
+pub struct ServerKeyExchangeState {
+    v_c: String,
+    v_s: String,
+    server_algorithms: AlgorithmNegotiation,
+    host_key: Arc<HostKey>,
+    moduli: Arc<Vec<dh::Group>>,
+    session_id: Option<Vec<u8>>,
+    strict: bool,
+    neg: NegotiatedAlgorithm,
+    // Set by a group exchange, together with the parameters it adds to the exchange hash
+    group: Option<dh::Group>,
+    gex_params: Vec<u8>,
+    hash_ctx: Option<Context>,
+    ignore_next: bool,
+    // SSH_MSG_KEXDH_REPLY payload, shared secret and exchange hash
+    reply: Option<(Vec<u8>, Vec<u8>, digest::Digest)>,
+    written: bool
+}
+
+impl Future for ServerKeyExchangeState {
+    type Item = Agreed;
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<Agreed, HandshakeError> {
+        if !self.written {
+            return Ok(Async::NotReady);
+        }
+
+        match self.reply.take() {
+            Some((_, k, hash)) => {
+                let h = hash.as_ref();
+                let session_id = self.session_id.take().unwrap_or(h.to_vec());
+                let keys = try!(derive_session_keys(&self.neg, hash.algorithm(), &k, h, &session_id));
+                let ssh_ctx = SecureContext {
+                    neg_algorithm: self.neg.clone(),
+                    session_id: session_id,
+                    keys: keys,
+                    v_c: self.v_c.clone(),
+                    v_s: self.v_s.clone(),
+                    local_algorithms: self.server_algorithms.clone(),
+                    gex_request: GexRequest::default(),
+                    strict: self.strict,
+                    extensions: HashMap::new(),
+                    server: Some(ServerParams {
+                        host_key: self.host_key.clone(),
+                        moduli: self.moduli.clone()
+                    })
+                };
+                let sealer = NewKeys {
+                    cipher: try!(ssh_ctx.server_sealer()),
+                    compression: try!(supported_compression(&self.neg.compression_algorithms_server_to_client)),
+                    reset_seq: self.strict
+                };
+                let opener = NewKeys {
+                    cipher: try!(ssh_ctx.server_opener()),
+                    compression: try!(supported_compression(&self.neg.compression_algorithms_client_to_server)),
+                    reset_seq: self.strict
+                };
+                Ok(Async::Ready(Agreed {
+                    ctx: Some(ssh_ctx),
+                    sealer: Some(sealer),
+                    opener: Some(opener),
+                    new_key_sent: false,
+                    new_key_received: false
+                }))
+            },
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+impl AsyncPacketState for ServerKeyExchangeState {
+    fn wants_read(&self) -> bool {
+        self.reply.is_none()
+    }
+
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), HandshakeError> {
+        if self.ignore_next {
+            self.ignore_next = false;
+            return Ok(());
+        }
+
+        let (init_msg, reply_msg) = kex_messages(&self.neg.kex_algorithms);
+        if msg.len() == 0 || msg[0] != init_msg {
+            return Err(kex_message_not_received(init_msg));
         }
+
+        let init = match deserialize::<KexInit>(&msg[1..]) {
+            Ok(init) => init,
+            Err(e) => return Err(HandshakeError::InvalidAlgorithmNegotiation(e.to_string()))
+        };
+
+        let mut hash_ctx = match self.hash_ctx.take() {
+            Some(ctx) => ctx,
+            None => return Err(HandshakeError::UnexpectedMessage(init_msg))
+        };
+        let (f, k) = try!(KeyShare::respond(&self.neg.kex_algorithms, self.group.take(), &init.e));
+
+        let k_s = serialize(self.host_key.public_key()).unwrap();
+        try!(digest_bytes(&mut hash_ctx, &k_s));
+        hash_ctx.update(&self.gex_params);
+        try!(digest_bytes(&mut hash_ctx, &init.e));
+        try!(digest_bytes(&mut hash_ctx, &f));
+        try!(digest_bytes(&mut hash_ctx, &k));
+        let hash = hash_ctx.finish();
+
+        let signature = match self.host_key.sign(&self.neg.server_host_key_algorithms, hash.as_ref()) {
+            Ok(sgn) => sgn,
+            Err(_) => return Err(HandshakeError::KexFailed)
+        };
+        let reply = KexReply {
+            server_key: self.host_key.public_key().clone(),
+            f: f,
+            signature: signature
+        };
+        let payload = serialize_msg(reply_msg, &reply).unwrap();
+        self.reply = Some((payload, k, hash));
+        Ok(())
     }
 
     fn write_packet(&self) -> Option<PacketWriteRequest> {
-        if self.new_key_sent {
-            None
-        } else {
-            Some(PacketWriteRequest { payload: vec![SSH_MSG_NEWKEYS], flush: true })
+        match self.reply {
+            Some((ref payload, _, _)) if !self.written => Some(PacketWriteRequest {
+                payload: payload.clone(),
+                flush: true
+            }),
+            _ => None
         }
     }
 
-    fn on_flush(&mut self) -> Result<(), Self::Error> {
-        self.new_key_sent = true;
+    fn on_flush(&mut self) -> Result<(), HandshakeError> {
+        self.written = true;
         Ok(())
     }
 }
 
+fn negotiate_name<T>(category: &str, client: &[T], server: &[T]) -> Result<T, HandshakeError>
+    where T: PartialEq + Clone
+{
+    match client.iter().find(|alg| server.contains(alg)) {
+        Some(alg) => Ok(alg.clone()),
+        None => Err(HandshakeError::InvalidAlgorithmNegotiation(
+                format!("no matching algorithm for {}", category)
+        ))
+    }
+}
+
+fn negotiate_kex(client: &[KexAlgorithm], server: &[KexAlgorithm]) -> Result<KexAlgorithm, HandshakeError> {
+    let client: Vec<KexAlgorithm> = client.iter().filter(|alg| !alg.is_pseudo()).cloned().collect();
+    negotiate_name("kex_algorithms", &client, server)
+}
+
+fn negotiate_mac(category: &str, enc: &EncryptionAlgorithm, client: &[MacAlgorithm], server: &[MacAlgorithm])
+        -> Result<Option<MacAlgorithm>, HandshakeError>
+{
+    // AEAD ciphers authenticate the packet by themselves, so the MAC list is ignored
+    if enc.is_aead() {
+        Ok(None)
+    } else {
+        negotiate_name(category, client, server).map(Some)
+    }
+}
+
+pub fn negotiate(client: &AlgorithmNegotiation, server: &AlgorithmNegotiation)
+        -> Result<NegotiatedAlgorithm, HandshakeError>
+{
+    let kex = try!(negotiate_kex(&client.kex_algorithms, &server.kex_algorithms));
+    let host_key = try!(negotiate_name("server_host_key_algorithms",
+                                       &client.server_host_key_algorithms,
+                                       &server.server_host_key_algorithms));
+    let enc_c2s = try!(negotiate_name("encryption_algorithms_client_to_server",
+                                      &client.encryption_algorithms_client_to_server,
+                                      &server.encryption_algorithms_client_to_server));
+    let enc_s2c = try!(negotiate_name("encryption_algorithms_server_to_client",
+                                      &client.encryption_algorithms_server_to_client,
+                                      &server.encryption_algorithms_server_to_client));
+    let mac_c2s = try!(negotiate_mac("mac_algorithms_client_to_server",
+                                     &enc_c2s,
+                                     &client.mac_algorithms_client_to_server,
+                                     &server.mac_algorithms_client_to_server));
+    let mac_s2c = try!(negotiate_mac("mac_algorithms_server_to_client",
+                                     &enc_s2c,
+                                     &client.mac_algorithms_server_to_client,
+                                     &server.mac_algorithms_server_to_client));
+    let comp_c2s = try!(negotiate_name("compression_algorithms_client_to_server",
+                                       &client.compression_algorithms_client_to_server,
+                                       &server.compression_algorithms_client_to_server));
+    let comp_s2c = try!(negotiate_name("compression_algorithms_server_to_client",
+                                       &client.compression_algorithms_server_to_client,
+                                       &server.compression_algorithms_server_to_client));
+
+    // Language negotiation failure is not fatal (RFC 4253 section 7.1)
+    let lang_c2s = negotiate_name("languages_client_to_server",
+                                  &client.languages_client_to_server,
+                                  &server.languages_client_to_server).ok();
+    let lang_s2c = negotiate_name("languages_server_to_client",
+                                  &client.languages_server_to_client,
+                                  &server.languages_server_to_client).ok();
+
+    Ok(NegotiatedAlgorithm {
+        kex_algorithms: kex,
+        server_host_key_algorithms: host_key,
+        encryption_algorithms_client_to_server: enc_c2s,
+        encryption_algorithms_server_to_client: enc_s2c,
+        mac_algorithms_client_to_server: mac_c2s,
+        mac_algorithms_server_to_client: mac_s2c,
+        compression_algorithms_client_to_server: comp_c2s,
+        compression_algorithms_server_to_client: comp_s2c,
+        languages_client_to_server: lang_c2s,
+        languages_server_to_client: lang_s2c
+    })
+}
+
 pub fn build_kexinit_payload(neg: &AlgorithmNegotiation, rng: &mut Rng) -> Result<Vec<u8>, HandshakeError> {
     let kexinit = serialize(neg).unwrap();
     let mut payload = Vec::new();
@@ -457,61 +2098,188 @@ pub fn build_kexinit_payload(neg: &AlgorithmNegotiation, rng: &mut Rng) -> Resul
     Ok(payload)
 }
 
+// RFC 4253 section 4.2 limits the identification string to 255 bytes including CR LF
+const MAX_VERSION_LEN: usize = 255;
+const MAX_PRE_BANNER_LINE_LEN: usize = 8192;
+const MAX_PRE_BANNER_LINES: usize = 1024;
+const MAX_PRE_BANNER_BYTES: usize = 64 * 1024;
+
+fn is_printable(b: u8) -> bool {
+    b >= 0x20 && b < 0x7f
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerVersion {
+    pub proto: String,
+    pub software: String,
+    pub comments: Option<String>,
+    ident: String
+}
+
+impl PeerVersion {
+    pub fn parse(line: &[u8]) -> Result<PeerVersion, HandshakeError> {
+        if line.len() > MAX_VERSION_LEN {
+            return Err(HandshakeError::InvalidVersionExchange);
+        }
+
+        // Some implementations terminate the line with LF only
+        let line = if line.ends_with(b"\r\n") {
+            &line[.. line.len() - 2]
+        } else if line.ends_with(b"\n") {
+            &line[.. line.len() - 1]
+        } else {
+            line
+        };
+
+        let ident = match str::from_utf8(line) {
+            Ok(s) if s.starts_with("SSH-") && s.bytes().all(is_printable) => s,
+            _ => return Err(HandshakeError::InvalidVersionExchange)
+        };
+
+        let (proto, rest) = match ident[4 ..].find('-') {
+            Some(idx) => (&ident[4 .. 4 + idx], &ident[5 + idx ..]),
+            None => return Err(HandshakeError::InvalidVersionExchange)
+        };
+
+        // 1.99 announces a server compatible with both protocol versions
+        if proto != "2.0" && proto != "1.99" {
+            return Err(HandshakeError::InvalidVersionExchange);
+        }
+
+        let (software, comments) = match rest.find(' ') {
+            Some(idx) => (&rest[.. idx], Some(rest[idx + 1 ..].to_string())),
+            None => (rest, None)
+        };
+
+        if software.is_empty() {
+            return Err(HandshakeError::InvalidVersionExchange);
+        }
+
+        Ok(PeerVersion {
+            proto: proto.to_string(),
+            software: software.to_string(),
+            comments: comments,
+            ident: ident.to_string()
+        })
+    }
+
+    // The identification string without CR LF, as it goes into the exchange hash
+    pub fn ident(&self) -> &str {
+        &self.ident
+    }
+}
+
+// Reads the peer identification string, skipping the lines a server may send before it
+pub struct ReadVersion<R> {
+    reader: Option<AsyncBufReader<R>>,
+    lines: usize,
+    bytes: usize
+}
+
+impl <R> ReadVersion<R> {
+    pub fn new(reader: AsyncBufReader<R>) -> ReadVersion<R> {
+        ReadVersion {
+            reader: Some(reader),
+            lines: 0,
+            bytes: 0
+        }
+    }
+}
+
+impl <R: Read> Future for ReadVersion<R> {
+    type Item = (AsyncBufReader<R>, PeerVersion);
+    type Error = HandshakeError;
+
+    fn poll(&mut self) -> Poll<Self::Item, HandshakeError> {
+        loop {
+            let line = match self.reader {
+                Some(ref mut reader) => match try!(reader.nb_read_until(b'\n', MAX_PRE_BANNER_LINE_LEN)) {
+                    Async::Ready(line) => line.to_vec(),
+                    Async::NotReady => return Ok(Async::NotReady)
+                },
+                None => panic!("Called ReadVersion::poll() after completion")
+            };
+
+            if line.starts_with(b"SSH-") {
+                let version = try!(PeerVersion::parse(&line));
+                return Ok(Async::Ready((self.reader.take().unwrap(), version)));
+            }
+
+            self.lines += 1;
+            self.bytes += line.len();
+            if self.lines > MAX_PRE_BANNER_LINES || self.bytes > MAX_PRE_BANNER_BYTES {
+                return Err(HandshakeError::InvalidVersionExchange);
+            }
+        }
+    }
+}
+
+fn valid_version_field(s: &str, allow_space: bool) -> bool {
+    s.bytes().all(|b| is_printable(b) && (allow_space || (b != b' ' && b != b'-')))
+}
+
 pub fn version_exchange<R, W>(reader: AsyncBufReader<R>, writer: AsyncBufWriter<W>, version_string: &str, comment: &str)
-        -> Box<Future<Item=(AsyncBufReader<R>, AsyncBufWriter<W>, (String, String)), Error=HandshakeError>>
+        -> Box<Future<Item=(AsyncBufReader<R>, AsyncBufWriter<W>, (String, PeerVersion)), Error=HandshakeError>>
     where R: Read + Send + 'static, W: Write + Send + 'static
 {
-    let v_c = format!("SSH-2.0-{} {}", version_string, comment);
+    if version_string.is_empty() || !valid_version_field(version_string, false) || !valid_version_field(comment, true) {
+        return futures::failed(HandshakeError::InvalidVersionExchange).boxed();
+    }
+
+    let v_c = if comment.is_empty() {
+        format!("SSH-2.0-{}", version_string)
+    } else {
+        format!("SSH-2.0-{} {}", version_string, comment)
+    };
 
     let mut buf = Vec::with_capacity(256);
     buf.write(v_c.as_bytes()).unwrap();
     buf.write(b"\r\n").unwrap();
-    if buf.len() > 255 {
-        panic!("version string and comment too long");
+    if buf.len() > MAX_VERSION_LEN {
+        return futures::failed(HandshakeError::InvalidVersionExchange).boxed();
     }
 
     let w = write_all(writer, buf).and_then(|(writer, _)| {
         flush(writer)
     }).map_err(|e| e.into());
-    
-    let r = read_until(reader, b'\n', Vec::with_capacity(256)).map_err(|e| e.into()).and_then(|(reader, buf)| {
-        if buf.starts_with(b"SSH-2.0-") && buf.ends_with(b"\r\n") {
-            match str::from_utf8(&buf[.. buf.len() - 2]) {
-                Ok(v_s) => {
-                    Ok((reader, (v_c, v_s.into())))
-                },
-                Err(_) => Err(HandshakeError::InvalidVersionExchange)
-            }
-        } else {
-            Err(HandshakeError::InvalidVersionExchange)
-        }
-    });
+
+    let r = ReadVersion::new(reader).map(|(reader, v_s)| (reader, (v_c, v_s)));
 
     w.join(r).map(|(writer, (reader, pair))| (reader, writer, pair)).boxed()
 }
 
 pub fn client_key_exchange<R, W>(reader: AsyncBufReader<R>, writer: AsyncBufWriter<W>, neg: AlgorithmNegotiation, v_c: String, v_s: String)
-        -> ClearTransport<R, W, OsRng, ClientKeyExchange>
+        -> Result<ClearTransport<R, W, OsRng, ClientKeyExchange>, HandshakeError>
     where R: Read, W: Write
-{   
-    let mut rng = OsRng::new().unwrap();
-    let i_c = build_kexinit_payload(&neg, &mut rng).unwrap();
-    let st = AlgorithmExchangeState {
-        v_c: v_c,
-        v_s: v_s,
-        i_c: i_c,
-        written: false,
-        res: None
-    };
-    let kex = ClientKex::AlgorithmExchange(st);
+{
+    let rng = try!(OsRng::new());
+    let kex = try!(ClientKeyExchange::new(neg, v_c, v_s, None, false));
+
+    Ok(ClearTransport::new(reader, writer, rng, kex))
+}
 
-    ClearTransport::new(reader, writer, rng, ClientKeyExchange { st: kex })
+pub fn server_key_exchange<R, W>(reader: AsyncBufReader<R>,
+                                 writer: AsyncBufWriter<W>,
+                                 neg: AlgorithmNegotiation,
+                                 host_key: Arc<HostKey>,
+                                 v_c: String,
+                                 v_s: String)
+        -> Result<ClearTransport<R, W, OsRng, ServerKeyExchange>, HandshakeError>
+    where R: Read, W: Write
+{
+    let rng = try!(OsRng::new());
+    let kex = try!(ServerKeyExchange::new(neg, host_key, v_c, v_s, None, false));
+
+    Ok(ClearTransport::new(reader, writer, rng, kex))
 }
 
-fn into_mpint(buf: &[u8]) -> Vec<u8> {
-    if buf.len() == 0 {
-        Vec::new()
-    } else if buf[0] <= 0x7f {
+pub fn into_mpint(buf: &[u8]) -> Vec<u8> {
+    let buf = match buf.iter().position(|&b| b != 0) {
+        Some(idx) => &buf[idx..],
+        None => return Vec::new()
+    };
+
+    if buf[0] <= 0x7f {
         buf.into()
     } else {
         let mut v = Vec::with_capacity(buf.len() + 1);
@@ -528,3 +2296,758 @@ fn from_mpint(data: &[u8]) -> &[u8] {
         data
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use super::{check_gex_group, derive_key, guessed_right, into_mpint, kex_hash, verify_server_signature};
+    use dh;
+    use hostkey::HostKey;
+    use mlkem;
+    use packet::{deserialize, serialize_msg};
+    use sntrup761;
+    use transport::AsyncPacketState;
+    use futures::Future;
+    use rand::OsRng;
+    use ring::digest;
+    use rustc_serialize::hex::FromHex;
+    use async::bufreader::AsyncBufReader;
+    use futures::Async;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use ::{SSH_MSG_KEXINIT, SSH_MSG_KEXDH_INIT, SSH_MSG_KEXDH_REPLY};
+    use ::{SSH_MSG_KEX_DH_GEX_GROUP, SSH_MSG_KEX_DH_GEX_INIT, SSH_MSG_KEX_DH_GEX_REQUEST};
+
+    fn client_algorithms() -> AlgorithmNegotiation {
+        AlgorithmNegotiation {
+            kex_algorithms: vec![KexAlgorithm::CURVE25519_SHA256, KexAlgorithm::ECDH_SHA2_NISTP256],
+            server_host_key_algorithms: vec![ServerHostKeyAlgorithm::SSH_RSA],
+            encryption_algorithms_client_to_server: vec![EncryptionAlgorithm::AES256_GCM, EncryptionAlgorithm::AES256_CTR],
+            encryption_algorithms_server_to_client: vec![EncryptionAlgorithm::AES256_CTR, EncryptionAlgorithm::AES256_GCM],
+            mac_algorithms_client_to_server: vec![MacAlgorithm::HMAC_SHA2_256],
+            mac_algorithms_server_to_client: vec![MacAlgorithm::HMAC_SHA2_256],
+            compression_algorithms_client_to_server: vec![CompressionAlgorithm::NONE],
+            compression_algorithms_server_to_client: vec![CompressionAlgorithm::NONE],
+            languages_client_to_server: vec![],
+            languages_server_to_client: vec![],
+            first_kex_packet_follows: false,
+            reserved: 0
+        }
+    }
+
+    fn server_algorithms() -> AlgorithmNegotiation {
+        AlgorithmNegotiation {
+            kex_algorithms: vec![KexAlgorithm::ECDH_SHA2_NISTP256, KexAlgorithm::CURVE25519_SHA256],
+            server_host_key_algorithms: vec![ServerHostKeyAlgorithm::SSH_ED25519, ServerHostKeyAlgorithm::SSH_RSA],
+            encryption_algorithms_client_to_server: vec![EncryptionAlgorithm::AES256_CTR, EncryptionAlgorithm::AES256_GCM],
+            encryption_algorithms_server_to_client: vec![EncryptionAlgorithm::AES256_GCM, EncryptionAlgorithm::AES256_CTR],
+            mac_algorithms_client_to_server: vec![MacAlgorithm::HMAC_SHA2_256],
+            mac_algorithms_server_to_client: vec![MacAlgorithm::HMAC_SHA2_256],
+            compression_algorithms_client_to_server: vec![CompressionAlgorithm::NONE],
+            compression_algorithms_server_to_client: vec![CompressionAlgorithm::NONE],
+            languages_client_to_server: vec![Language::EN],
+            languages_server_to_client: vec![],
+            first_kex_packet_follows: false,
+            reserved: 0
+        }
+    }
+
+    // RFC 8032 section 7.1, test 1
+    pub fn test_host_key() -> Arc<HostKey> {
+        let seed = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60".from_hex().unwrap();
+        Arc::new(HostKey::ed25519_from_seed(&seed).unwrap())
+    }
+
+    // Writes the next packet of `st` to `out`, reads what it wants from `input` and polls it, the way
+    // the transport drives a state. Returns whether anything happened.
+    fn step<S>(st: &mut S, out: &mut VecDeque<Vec<u8>>, input: &mut VecDeque<Vec<u8>>, res: &mut Option<S::Item>)
+            -> Result<bool, HandshakeError>
+        where S: AsyncPacketState<Error=HandshakeError>
+    {
+        if res.is_some() {
+            return Ok(false);
+        }
+
+        let mut progress = false;
+        if let Some(req) = st.write_packet() {
+            st.on_write(&req);
+            out.push_back(req.payload);
+            try!(st.on_flush());
+            st.take_sealer();
+            progress = true;
+        }
+        while st.wants_read() {
+            match input.pop_front() {
+                Some(msg) => {
+                    try!(st.on_read(&msg));
+                    st.take_opener();
+                    progress = true;
+                },
+                None => break
+            }
+        }
+        if let Async::Ready(item) = try!(st.poll()) {
+            *res = Some(item);
+            progress = true;
+        }
+        Ok(progress)
+    }
+
+    // Runs two states against each other in memory until neither makes progress, and returns the
+    // items of the states which finished
+    pub fn run_pair<A, B>(a: &mut A, b: &mut B) -> Result<(Option<A::Item>, Option<B::Item>), HandshakeError>
+        where A: AsyncPacketState<Error=HandshakeError>, B: AsyncPacketState<Error=HandshakeError>
+    {
+        let (mut a_to_b, mut b_to_a) = (VecDeque::new(), VecDeque::new());
+        let (mut a_res, mut b_res) = (None, None);
+        loop {
+            let a_progress = try!(step(a, &mut a_to_b, &mut b_to_a, &mut a_res));
+            let b_progress = try!(step(b, &mut b_to_a, &mut a_to_b, &mut b_res));
+            if !a_progress && !b_progress {
+                return Ok((a_res, b_res));
+            }
+        }
+    }
+
+    fn run_kex(client: AlgorithmNegotiation, server: AlgorithmNegotiation) -> (SecureContext, SecureContext) {
+        let (v_c, v_s) = ("SSH-2.0-client".to_string(), "SSH-2.0-server".to_string());
+        let mut client = ClientKeyExchange::new(client, v_c.clone(), v_s.clone(), None, false).unwrap();
+        let mut server = ServerKeyExchange::new(server, test_host_key(), v_c, v_s, None, false).unwrap();
+        match run_pair(&mut client, &mut server).unwrap() {
+            (Some(client_ctx), Some(server_ctx)) => (client_ctx, server_ctx),
+            _ => panic!("key exchange did not finish")
+        }
+    }
+
+    // The client and the server contexts of a key exchange signed with `test_host_key()`
+    pub fn connect() -> (SecureContext, SecureContext) {
+        let mut client = client_algorithms();
+        client.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::SSH_ED25519];
+        run_kex(client, server_algorithms())
+    }
+
+    #[test]
+    fn derive_key_single_block() {
+        let k = "00800102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e".from_hex().unwrap();
+        let h = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f".from_hex().unwrap();
+        let session_id = "404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f".from_hex().unwrap();
+        let key = derive_key(&digest::SHA256, &k, &h, b'A', &session_id, 16).unwrap();
+        assert_eq!("5a3cac731a443c0401f9baf349986cdc".from_hex().unwrap(), key);
+    }
+
+    #[test]
+    fn derive_key_extended() {
+        let k = "00800102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e".from_hex().unwrap();
+        let h = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f".from_hex().unwrap();
+        let session_id = "404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f".from_hex().unwrap();
+        let key = derive_key(&digest::SHA256, &k, &h, b'C', &session_id, 64).unwrap();
+        assert_eq!(
+            "2c53880169f30972010cabeee0aaeba6c2406b4bfbe3d64c0d3313d6f967120c\
+             cccd733cbeb609620137413362b9abcf5280c17a869fa39532d699ab9573c70b".from_hex().unwrap(),
+            key
+        );
+    }
+
+    #[test]
+    fn mpint_strips_leading_zeros() {
+        assert_eq!(Vec::<u8>::new(), into_mpint(&[0, 0]));
+        assert_eq!(vec![0x7f, 0x01], into_mpint(&[0, 0x7f, 0x01]));
+        assert_eq!(vec![0, 0x80, 0x01], into_mpint(&[0, 0, 0x80, 0x01]));
+    }
+
+    #[test]
+    fn negotiate_client_preference() {
+        let neg = negotiate(&client_algorithms(), &server_algorithms()).expect("negotiation failed");
+        assert_eq!(KexAlgorithm::CURVE25519_SHA256, neg.kex_algorithms);
+        assert_eq!(ServerHostKeyAlgorithm::SSH_RSA, neg.server_host_key_algorithms);
+        assert_eq!(EncryptionAlgorithm::AES256_GCM, neg.encryption_algorithms_client_to_server);
+        assert_eq!(EncryptionAlgorithm::AES256_CTR, neg.encryption_algorithms_server_to_client);
+        assert_eq!(None, neg.mac_algorithms_client_to_server);
+        assert_eq!(Some(MacAlgorithm::HMAC_SHA2_256), neg.mac_algorithms_server_to_client);
+        assert_eq!(CompressionAlgorithm::NONE, neg.compression_algorithms_client_to_server);
+        assert_eq!(None, neg.languages_client_to_server);
+        assert_eq!(None, neg.languages_server_to_client);
+    }
+
+    #[test]
+    fn negotiate_aead_ignores_mac() {
+        let client = client_algorithms();
+        let mut server = server_algorithms();
+        server.encryption_algorithms_client_to_server = vec![EncryptionAlgorithm::AES256_GCM];
+        server.mac_algorithms_client_to_server = vec![];
+        let neg = negotiate(&client, &server).expect("negotiation failed");
+        assert_eq!(EncryptionAlgorithm::AES256_GCM, neg.encryption_algorithms_client_to_server);
+        assert_eq!(None, neg.mac_algorithms_client_to_server);
+    }
+
+    #[test]
+    fn negotiate_failure_names_category() {
+        let client = client_algorithms();
+        let mut server = server_algorithms();
+        server.mac_algorithms_server_to_client = vec![MacAlgorithm::Unknown("hmac-sha1".to_string())];
+        match negotiate(&client, &server) {
+            Err(HandshakeError::InvalidAlgorithmNegotiation(msg)) =>
+                assert!(msg.contains("mac_algorithms_server_to_client")),
+            x => panic!("expected negotiation failure, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn guess_requires_same_preference() {
+        let client = client_algorithms();
+        let mut server = server_algorithms();
+        assert!(!guessed_right(&client, &server));
+
+        server.kex_algorithms = vec![KexAlgorithm::CURVE25519_SHA256];
+        assert!(!guessed_right(&client, &server));
+
+        server.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::SSH_RSA];
+        assert!(guessed_right(&client, &server));
+    }
+
+    fn run_guessed_kex(server: &AlgorithmNegotiation) -> ClientKeyExchange {
+        let mut client = client_algorithms();
+        client.first_kex_packet_follows = true;
+        let mut kex = ClientKeyExchange::new(client, "SSH-2.0-client".into(), "SSH-2.0-server".into(), None, false).unwrap();
+
+        let kexinit = kex.write_packet().unwrap();
+        assert_eq!(SSH_MSG_KEXINIT, kexinit.payload[0]);
+        assert!(!kexinit.flush);
+        kex.on_flush().unwrap();
+
+        let guess = kex.write_packet().unwrap();
+        assert_eq!(SSH_MSG_KEXDH_INIT, guess.payload[0]);
+        kex.on_flush().unwrap();
+        assert!(kex.write_packet().is_none());
+
+        let i_s = build_kexinit_payload(server, &mut OsRng::new().unwrap()).unwrap();
+        kex.on_read(&i_s).unwrap();
+        assert!(kex.poll().unwrap().is_not_ready());
+        kex
+    }
+
+    #[test]
+    fn right_guess_is_not_resent() {
+        let mut server = client_algorithms();
+        server.first_kex_packet_follows = true;
+        let kex = run_guessed_kex(&server);
+        assert!(kex.write_packet().is_none());
+    }
+
+    #[test]
+    fn wrong_guess_is_resent() {
+        let kex = run_guessed_kex(&server_algorithms());
+        assert_eq!(SSH_MSG_KEXDH_INIT, kex.write_packet().unwrap().payload[0]);
+    }
+
+    #[test]
+    fn wrong_server_guess_is_ignored() {
+        let mut server = server_algorithms();
+        server.first_kex_packet_follows = true;
+        let mut kex = run_guessed_kex(&server);
+        assert!(kex.wants_read());
+        kex.on_read(&[SSH_MSG_KEXDH_REPLY, 0xff]).unwrap();
+        assert!(kex.wants_read());
+    }
+
+    #[test]
+    fn negotiate_skips_pseudo_algorithms() {
+        let mut client = client_algorithms();
+        let mut server = server_algorithms();
+        client.kex_algorithms.insert(0, KexAlgorithm::KEX_STRICT_S);
+        server.kex_algorithms.insert(0, KexAlgorithm::KEX_STRICT_S);
+        let neg = negotiate(&client, &server).expect("negotiation failed");
+        assert_eq!(KexAlgorithm::CURVE25519_SHA256, neg.kex_algorithms);
+    }
+
+    fn strict_after_kexinit(server_kex: Vec<KexAlgorithm>) -> bool {
+        let mut kex = ClientKeyExchange::new(client_algorithms(), "SSH-2.0-client".into(), "SSH-2.0-server".into(), None, false).unwrap();
+        let kexinit = kex.write_packet().unwrap();
+        let marker = b"kex-strict-c-v00@openssh.com";
+        assert!(kexinit.payload.windows(marker.len()).any(|w| w == marker));
+        kex.on_flush().unwrap();
+
+        let mut server = server_algorithms();
+        server.kex_algorithms = server_kex;
+        let i_s = build_kexinit_payload(&server, &mut OsRng::new().unwrap()).unwrap();
+        kex.on_read(&i_s).unwrap();
+        assert!(kex.poll().unwrap().is_not_ready());
+        match kex.st {
+            ClientKex::KeyExchange(ref st) => st.strict,
+            _ => panic!("key exchange did not start")
+        }
+    }
+
+    #[test]
+    fn strict_kex_detected() {
+        assert!(strict_after_kexinit(vec![KexAlgorithm::CURVE25519_SHA256, KexAlgorithm::KEX_STRICT_S]));
+        assert!(!strict_after_kexinit(vec![KexAlgorithm::CURVE25519_SHA256]));
+    }
+
+    #[test]
+    fn ext_info_parsed() {
+        let msg = b"\x07\x00\x00\x00\x03\
+                    \x00\x00\x00\x0fserver-sig-algs\x00\x00\x00\x18ssh-ed25519,rsa-sha2-256\
+                    \x00\x00\x00\x0fno-flow-control\x00\x00\x00\x01p\
+                    \x00\x00\x00\x11delay-compression\
+                    \x00\x00\x00\x1c\x00\x00\x00\x10zlib@openssh.com\x00\x00\x00\x04none";
+        let extensions = parse_ext_info(msg).unwrap();
+        assert_eq!(3, extensions.len());
+        assert_eq!(
+            Some(&Extension::ServerSigAlgs(vec![
+                ServerHostKeyAlgorithm::SSH_ED25519,
+                ServerHostKeyAlgorithm::RSA_SHA2_256
+            ])),
+            extensions.get("server-sig-algs")
+        );
+        assert_eq!(Some(&Extension::NoFlowControl("p".to_string())), extensions.get("no-flow-control"));
+        assert_eq!(
+            Some(&Extension::DelayCompression(vec![CompressionAlgorithm::ZLIB_OPENSSH],
+                                              vec![CompressionAlgorithm::NONE])),
+            extensions.get("delay-compression")
+        );
+    }
+
+    #[test]
+    fn ext_info_truncated() {
+        match parse_ext_info(b"\x07\x00\x00\x00\x01\x00\x00\x00\x0fserver-sig-algs") {
+            Err(HandshakeError::InvalidExtInfo(_)) => (),
+            x => panic!("expected InvalidExtInfo, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn peer_version_parsed() {
+        let v = PeerVersion::parse(b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n").unwrap();
+        assert_eq!("2.0", v.proto);
+        assert_eq!("OpenSSH_9.6p1", v.software);
+        assert_eq!(Some("Ubuntu-3ubuntu13".to_string()), v.comments);
+        assert_eq!("SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13", v.ident());
+
+        let v = PeerVersion::parse(b"SSH-1.99-Cisco-1.25\n").unwrap();
+        assert_eq!("1.99", v.proto);
+        assert_eq!("Cisco-1.25", v.software);
+        assert_eq!(None, v.comments);
+    }
+
+    #[test]
+    fn peer_version_rejected() {
+        assert!(PeerVersion::parse(b"SSH-1.5-OldServer\r\n").is_err());
+        assert!(PeerVersion::parse(b"SSH-2.0-\r\n").is_err());
+        assert!(PeerVersion::parse(b"SSH-2.0-bad\x00version\r\n").is_err());
+        let mut long = b"SSH-2.0-".to_vec();
+        long.extend_from_slice(&[b'a'; 250]);
+        assert!(PeerVersion::parse(&long).is_err());
+    }
+
+    #[test]
+    fn version_after_pre_banner_lines() {
+        let input = b"Welcome\r\nSSH is not a prefix here\r\nSSH-2.0-srv\r\n\x00\x00";
+        let mut read = ReadVersion::new(AsyncBufReader::new(Cursor::new(input.to_vec())));
+        match read.poll().unwrap() {
+            Async::Ready((_, v)) => assert_eq!("SSH-2.0-srv", v.ident()),
+            Async::NotReady => panic!("version not read")
+        }
+    }
+
+    #[test]
+    fn too_many_pre_banner_lines() {
+        let input = b"x\n".iter().cycle().take(2 * 2000).cloned().collect::<Vec<u8>>();
+        let mut read = ReadVersion::new(AsyncBufReader::new(Cursor::new(input)));
+        match read.poll() {
+            Err(HandshakeError::InvalidVersionExchange) => (),
+            x => panic!("expected InvalidVersionExchange, got {:?}", x.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn client_and_server_derive_same_keys() {
+        let (client_ctx, server_ctx) = connect();
+        assert!(!client_ctx.is_server());
+        assert!(server_ctx.is_server());
+        assert_eq!(client_ctx.session_id(), server_ctx.session_id());
+        assert_eq!(client_ctx.algorithms().kex_algorithms, server_ctx.algorithms().kex_algorithms);
+
+        let (c, s) = (client_ctx.keys(), server_ctx.keys());
+        assert_eq!(c.iv_client_to_server, s.iv_client_to_server);
+        assert_eq!(c.iv_server_to_client, s.iv_server_to_client);
+        assert_eq!(c.enc_key_client_to_server, s.enc_key_client_to_server);
+        assert_eq!(c.enc_key_server_to_client, s.enc_key_server_to_client);
+        assert_eq!(c.mac_key_client_to_server, s.mac_key_client_to_server);
+        assert_eq!(c.mac_key_server_to_client, s.mac_key_server_to_client);
+        assert!(c.enc_key_client_to_server != c.enc_key_server_to_client);
+    }
+
+    #[test]
+    fn server_rekey_needs_server_context() {
+        let (client_ctx, server_ctx) = connect();
+        assert!(ServerKeyExchange::rekey(&client_ctx).is_err());
+
+        let mut client = ClientKeyExchange::rekey(&client_ctx).unwrap();
+        let mut server = ServerKeyExchange::rekey(&server_ctx).unwrap();
+        match run_pair(&mut client, &mut server).unwrap() {
+            (Some(client_ctx), Some(server_ctx)) => {
+                assert_eq!(client_ctx.session_id(), server_ctx.session_id());
+                assert_eq!(client_ctx.keys().enc_key_client_to_server, server_ctx.keys().enc_key_client_to_server);
+            },
+            _ => panic!("key exchange did not finish")
+        }
+    }
+
+    #[test]
+    fn nistp256_key_agreement() {
+        let client = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP256).unwrap();
+        let server = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP256).unwrap();
+        // Uncompressed point encoding
+        assert_eq!(65, client.public_key().len());
+        assert_eq!(0x04, client.public_key()[0]);
+
+        let q_c = client.public_key().to_vec();
+        let q_s = server.public_key().to_vec();
+        assert_eq!(client.agree(&q_s).unwrap(), server.agree(&q_c).unwrap());
+    }
+
+    #[test]
+    fn keyshare_rejects_other_curve() {
+        let client = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP256).unwrap();
+        let server = KeyShare::generate(&KexAlgorithm::CURVE25519_SHA256).unwrap();
+        assert!(client.agree(server.public_key()).is_err());
+        assert!(KeyShare::generate(&KexAlgorithm::EXT_INFO_C).is_err());
+    }
+
+    #[test]
+    fn nistp384_key_agreement() {
+        let client = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP384).unwrap();
+        let server = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP384).unwrap();
+        assert_eq!(97, client.public_key().len());
+
+        let q_c = client.public_key().to_vec();
+        let q_s = server.public_key().to_vec();
+        assert_eq!(client.agree(&q_s).unwrap(), server.agree(&q_c).unwrap());
+    }
+
+    #[test]
+    fn nistp521_key_agreement() {
+        let client = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP521).unwrap();
+        let server = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP521).unwrap();
+        assert_eq!(133, client.public_key().len());
+
+        let q_c = client.public_key().to_vec();
+        let q_s = server.public_key().to_vec();
+        assert_eq!(client.agree(&q_s).unwrap(), server.agree(&q_c).unwrap());
+
+        let client = KeyShare::generate(&KexAlgorithm::ECDH_SHA2_NISTP521).unwrap();
+        assert!(client.agree(&q_s[..132]).is_err());
+    }
+
+    #[test]
+    fn nistp521_key_exchange() {
+        let (mut client, mut server) = (client_algorithms(), server_algorithms());
+        client.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::SSH_ED25519];
+        client.kex_algorithms = vec![KexAlgorithm::ECDH_SHA2_NISTP521];
+        server.kex_algorithms = vec![KexAlgorithm::ECDH_SHA2_NISTP521];
+        let (client_ctx, server_ctx) = run_kex(client, server);
+        assert_eq!(client_ctx.session_id(), server_ctx.session_id());
+        assert_eq!(64, client_ctx.session_id().len());
+    }
+
+    #[test]
+    fn exchange_hash_follows_kex_method() {
+        assert_eq!(digest::SHA256.output_len, kex_hash(&KexAlgorithm::CURVE25519_SHA256).unwrap().output_len);
+        assert_eq!(digest::SHA256.output_len, kex_hash(&KexAlgorithm::ECDH_SHA2_NISTP256).unwrap().output_len);
+        assert_eq!(digest::SHA384.output_len, kex_hash(&KexAlgorithm::ECDH_SHA2_NISTP384).unwrap().output_len);
+        assert_eq!(digest::SHA512.output_len, kex_hash(&KexAlgorithm::ECDH_SHA2_NISTP521).unwrap().output_len);
+        assert_eq!(digest::SHA256.output_len, kex_hash(&KexAlgorithm::DH_GROUP14_SHA256).unwrap().output_len);
+        assert_eq!(digest::SHA512.output_len, kex_hash(&KexAlgorithm::DH_GROUP18_SHA512).unwrap().output_len);
+        assert_eq!(digest::SHA256.output_len, kex_hash(&KexAlgorithm::DH_GEX_SHA256).unwrap().output_len);
+        assert_eq!(digest::SHA256.output_len, kex_hash(&KexAlgorithm::MLKEM768_X25519_SHA256).unwrap().output_len);
+        assert_eq!(digest::SHA512.output_len, kex_hash(&KexAlgorithm::SNTRUP761_X25519_SHA512).unwrap().output_len);
+        assert!(kex_hash(&KexAlgorithm::KEX_STRICT_C).is_err());
+    }
+
+    #[test]
+    fn group14_key_agreement() {
+        let client = KeyShare::generate(&KexAlgorithm::DH_GROUP14_SHA256).unwrap();
+        let server = KeyShare::generate(&KexAlgorithm::DH_GROUP14_SHA256).unwrap();
+        // Public values are positive mpints
+        assert!(client.public_key()[0] & 0x80 == 0);
+
+        let e = client.public_key().to_vec();
+        let f = server.public_key().to_vec();
+        assert_eq!(client.agree(&f).unwrap(), server.agree(&e).unwrap());
+    }
+
+    #[test]
+    fn group14_rejects_invalid_public_value() {
+        assert!(KeyShare::generate(&KexAlgorithm::DH_GROUP14_SHA256).unwrap().agree(&[1]).is_err());
+        assert!(KeyShare::generate(&KexAlgorithm::DH_GROUP14_SHA256).unwrap().agree(&[0x80, 1]).is_err());
+    }
+
+    fn gex_group(group: &dh::Group) -> GexGroup {
+        GexGroup {
+            p: into_mpint(&group.p.to_bytes_be()),
+            g: into_mpint(&group.g.to_bytes_be())
+        }
+    }
+
+    #[test]
+    fn gex_group_checked() {
+        let req = GexRequest { min: 2048, n: 3072, max: 4096 };
+        assert_eq!(dh::group14(), check_gex_group(&req, &gex_group(&dh::group14())).unwrap());
+        assert!(check_gex_group(&req, &gex_group(&dh::group18())).is_err());
+
+        let mut group = gex_group(&dh::group14());
+        group.g = vec![1];
+        assert!(check_gex_group(&req, &group).is_err());
+    }
+
+    #[test]
+    fn group_exchange_requests_group() {
+        let mut client = client_algorithms();
+        client.kex_algorithms = vec![KexAlgorithm::DH_GEX_SHA256];
+        let server = client.clone();
+        client.first_kex_packet_follows = true;
+        let req = GexRequest { min: 2048, n: 2048, max: 4096 };
+        let mut kex = ClientKeyExchange::new(client, "SSH-2.0-client".into(), "SSH-2.0-server".into(), None, false).unwrap();
+        kex.set_gex_request(req);
+
+        // Group exchange is never guessed
+        let kexinit = kex.write_packet().unwrap();
+        assert!(kexinit.flush);
+        kex.on_flush().unwrap();
+        assert!(kex.write_packet().is_none());
+
+        let i_s = build_kexinit_payload(&server, &mut OsRng::new().unwrap()).unwrap();
+        kex.on_read(&i_s).unwrap();
+        assert!(kex.poll().unwrap().is_not_ready());
+
+        let request = kex.write_packet().unwrap();
+        assert_eq!(SSH_MSG_KEX_DH_GEX_REQUEST, request.payload[0]);
+        assert_eq!(req, deserialize::<GexRequest>(&request.payload[1..]).unwrap());
+        kex.on_flush().unwrap();
+
+        let group = serialize_msg(SSH_MSG_KEX_DH_GEX_GROUP, &gex_group(&dh::group14())).unwrap();
+        kex.on_read(&group).unwrap();
+        assert!(kex.poll().unwrap().is_not_ready());
+        assert_eq!(SSH_MSG_KEX_DH_GEX_INIT, kex.write_packet().unwrap().payload[0]);
+    }
+
+    fn run_gex(req: GexRequest, moduli: Vec<dh::Group>) -> Result<(Option<SecureContext>, Option<SecureContext>), HandshakeError> {
+        let mut neg = client_algorithms();
+        neg.kex_algorithms = vec![KexAlgorithm::DH_GEX_SHA256];
+        neg.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::SSH_ED25519];
+        let (v_c, v_s) = ("SSH-2.0-client".to_string(), "SSH-2.0-server".to_string());
+        let mut client = ClientKeyExchange::new(neg.clone(), v_c.clone(), v_s.clone(), None, false).unwrap();
+        client.set_gex_request(req);
+        let mut server = ServerKeyExchange::new(neg, test_host_key(), v_c, v_s, None, false).unwrap();
+        server.set_moduli(Arc::new(moduli));
+        run_pair(&mut client, &mut server)
+    }
+
+    #[test]
+    fn group_exchange_completes() {
+        // The client only accepts the signature and both sides only derive the same keys if they hash
+        // min || n || max || p || g the same way
+        let req = GexRequest { min: 2048, n: 2048, max: 3072 };
+        let (client_ctx, server_ctx) = match run_gex(req, vec![dh::group14()]).unwrap() {
+            (Some(client_ctx), Some(server_ctx)) => (client_ctx, server_ctx),
+            _ => panic!("key exchange did not finish")
+        };
+        assert_eq!(KexAlgorithm::DH_GEX_SHA256, client_ctx.algorithms().kex_algorithms);
+        assert_eq!(client_ctx.session_id(), server_ctx.session_id());
+        assert_eq!(client_ctx.keys().enc_key_client_to_server, server_ctx.keys().enc_key_client_to_server);
+        assert_eq!(client_ctx.keys().mac_key_server_to_client, server_ctx.keys().mac_key_server_to_client);
+
+        // The configured moduli are used again when the server re-keys
+        let mut client = ClientKeyExchange::rekey(&client_ctx).unwrap();
+        let mut server = ServerKeyExchange::rekey(&server_ctx).unwrap();
+        match run_pair(&mut client, &mut server).unwrap() {
+            (Some(client_ctx), Some(server_ctx)) => {
+                assert_eq!(client_ctx.keys().enc_key_server_to_client, server_ctx.keys().enc_key_server_to_client);
+                assert_eq!(vec![dh::group14()], *server_ctx.server.unwrap().moduli);
+            },
+            _ => panic!("key exchange did not finish")
+        }
+    }
+
+    #[test]
+    fn group_exchange_without_matching_group() {
+        let req = GexRequest { min: 2048, n: 2048, max: 3072 };
+        match run_gex(req, vec![dh::group16()]) {
+            Err(HandshakeError::KexFailed) => (),
+            x => panic!("expected KexFailed, got {:?}", x.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn kex_message_names() {
+        let name = |msg_type| match kex_message_not_received(msg_type) {
+            HandshakeError::InvalidAlgorithmNegotiation(msg) => msg,
+            _ => unreachable!()
+        };
+        assert_eq!("SSH_MSG_KEXDH_REPLY not received", name(kex_messages(&KexAlgorithm::CURVE25519_SHA256).1));
+        assert_eq!("SSH_MSG_KEX_DH_GEX_INIT not received", name(kex_messages(&KexAlgorithm::DH_GEX_SHA256).0));
+        assert_eq!("SSH_MSG_KEX_DH_GEX_REPLY not received", name(kex_messages(&KexAlgorithm::DH_GEX_SHA256).1));
+    }
+
+    #[test]
+    fn mlkem768_x25519_key_agreement() {
+        let alg = KexAlgorithm::MLKEM768_X25519_SHA256;
+        let client = KeyShare::generate(&alg).unwrap();
+        assert_eq!(mlkem::PUBLIC_KEY_LEN + 32, client.public_key().len());
+
+        let e = client.public_key().to_vec();
+        let (f, k) = KeyShare::respond(&alg, None, &e).unwrap();
+        assert_eq!(mlkem::CIPHERTEXT_LEN + 32, f.len());
+        // K is a SHA-256 hash, not an mpint
+        assert_eq!(32, k.len());
+        assert_eq!(k, client.agree(&f).unwrap());
+    }
+
+    #[test]
+    fn sntrup761_x25519_key_agreement() {
+        let alg = KexAlgorithm::SNTRUP761_X25519_SHA512;
+        let client = KeyShare::generate(&alg).unwrap();
+        assert_eq!(sntrup761::PUBLIC_KEY_LEN + 32, client.public_key().len());
+
+        let e = client.public_key().to_vec();
+        let (f, k) = KeyShare::respond(&alg, None, &e).unwrap();
+        assert_eq!(sntrup761::CIPHERTEXT_LEN + 32, f.len());
+        assert_eq!(64, k.len());
+        assert_eq!(k, client.agree(&f).unwrap());
+    }
+
+    #[test]
+    fn hybrid_rejects_truncated_public_key() {
+        let alg = KexAlgorithm::MLKEM768_X25519_SHA256;
+        let client = KeyShare::generate(&alg).unwrap();
+        let e = client.public_key().to_vec();
+        assert!(KeyShare::respond(&alg, None, &e[1..]).is_err());
+
+        let (f, _) = KeyShare::respond(&alg, None, &e).unwrap();
+        assert!(client.agree(&f[..f.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn ed25519_signature_verified() {
+        let key = HostKey::ed25519_from_seed(&[7; 32]).unwrap();
+        let alg = ServerHostKeyAlgorithm::SSH_ED25519;
+        let h = [1u8; 32];
+        let sgn = key.sign(&alg, &h).unwrap();
+        assert!(verify_server_signature(&alg, key.public_key(), &h, &sgn).is_ok());
+        assert!(verify_server_signature(&alg, key.public_key(), &[2u8; 32], &sgn).is_err());
+        // The host key has to match the negotiated algorithm
+        assert!(verify_server_signature(&ServerHostKeyAlgorithm::SSH_RSA, key.public_key(), &h, &sgn).is_err());
+    }
+
+    fn rsa_server_key() -> ServerKey {
+        ServerKey::SSH_RSA {
+            e: vec![1, 0, 1],
+            n: concat!(
+                "00",
+                "97c4f891e401bbe2b3173b9e231789503f4a20c9891ad7b67ac542ebde633038c1c9665cc0188d9ea0d799e96afdc02ff431cc9fb5322d48e52b97323f797ae9",
+                "686a3756655c1e98620418105164114ff37dc1d1c63a1952682c1aa6d16eed51e2854d51c67eb793812dd995f112e6332df6b19d65e4545c348c4cb3c1ed6776",
+                "e0660836ceb6b334afac1b7f814bc80194a1a71adf11e4b91e3ab39289c773426bb7832ca5f2dd934a4dd03acb8de8d3c978bc4f36f79dc6288ccb9d901d8935",
+                "49a7c3ec5af541ee276a951913a6562942caadf22b0fec99a2040ba2c974b51cac2066adf3b8aacdfc6532a6de8e355f35a08b9692db3858710dca26dc2d4525"
+            ).from_hex().unwrap()
+        }
+    }
+
+    #[test]
+    fn rsa_sha2_signature_verified() {
+        let h = [1u8; 32];
+        // openssl dgst -sha256 -sign
+        let sgn = concat!(
+            "00a529a3e8107e45e6468d8cabfbadc7ac3e77da610d163fce7b1b04364194a525169634069f184ba7f6b6aa8f7598b00f7e2c8b135a4ff0dbb25cdcc58f7c5f",
+            "c031d94eb8b14315cdba3d7c2ca3010502863e2a62a631fa1c6979090669fcf8fdae27b8cbc8f306bc494f5459e90e4396d2356d6b81265730acc07d338c504a",
+            "4f3977edd32f64d4829edc6fb9ed96d893f98d7da9cc30618019ebafe35ed4a43b88bcb224f100513981a5df0c6ab93c31158d82f53f2d97ed46ca79f135cd42",
+            "61f9a063f123b77e75630b2381ce2c31d10c657cdf89c374c5f841af8d53489eab9d709b20bb737e8b3dc3cff0befcef36cd72194827ac9d78f19e711b7bbf39"
+        ).from_hex().unwrap();
+        let alg = ServerHostKeyAlgorithm::RSA_SHA2_256;
+        let sha256 = Signature::RSA_SHA2_256 { signature: sgn.clone() };
+        assert!(verify_server_signature(&alg, &rsa_server_key(), &h, &sha256).is_ok());
+        assert!(verify_server_signature(&alg, &rsa_server_key(), &[2u8; 32], &sha256).is_err());
+        // The signature name has to match the negotiated algorithm
+        let sha512 = Signature::RSA_SHA2_512 { signature: sgn.clone() };
+        assert!(verify_server_signature(&alg, &rsa_server_key(), &h, &sha512).is_err());
+        let sha1 = Signature::SSH_RSA { signature: sgn };
+        assert!(verify_server_signature(&alg, &rsa_server_key(), &h, &sha1).is_err());
+    }
+
+    #[test]
+    fn ssh_rsa_needs_explicit_opt_in() {
+        let mut client = client_algorithms();
+        let mut server = server_algorithms();
+        client.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::RSA_SHA2_512, ServerHostKeyAlgorithm::RSA_SHA2_256];
+        server.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::SSH_RSA];
+        assert!(negotiate(&client, &server).is_err());
+
+        client.server_host_key_algorithms.push(ServerHostKeyAlgorithm::SSH_RSA);
+        assert_eq!(ServerHostKeyAlgorithm::SSH_RSA, negotiate(&client, &server).unwrap().server_host_key_algorithms);
+    }
+
+    fn ecdsa_server_key(curve: &str) -> ServerKey {
+        ServerKey::ECDSA_SHA2_NISTP256 {
+            curve: curve.to_string(),
+            q: concat!(
+                "04d2241031e46aa2d2f5d5db6e236876b1e0c9d227392a0525b94679b76a11ba3d1ddf7a0cdd347d9a1956a1f55bf2175f558ad05d96f688a5e7a92d0addb1e2",
+                "36"
+            ).from_hex().unwrap()
+        }
+    }
+
+    #[test]
+    fn ecdsa_signature_verified() {
+        let h = [1u8; 32];
+        // Signed by OpenSSL with SHA-256
+        let sgn = || Signature::ECDSA_SHA2_NISTP256 {
+            signature: EcdsaSignature {
+                r: "0096ad138f111ae162cdf53446b4dcd798df166ae9ab22e559e483dd32a4f066be".from_hex().unwrap(),
+                s: "36b6eb9ee1bd526eb1930651c73c8023e7dfa3844cdbde1b66635ee9091ff8e8".from_hex().unwrap()
+            }
+        };
+        let alg = ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256;
+        assert!(verify_server_signature(&alg, &ecdsa_server_key("nistp256"), &h, &sgn()).is_ok());
+        assert!(verify_server_signature(&alg, &ecdsa_server_key("nistp256"), &[2u8; 32], &sgn()).is_err());
+        assert!(verify_server_signature(&alg, &ecdsa_server_key("nistp384"), &h, &sgn()).is_err());
+        let other = ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384;
+        assert!(verify_server_signature(&other, &ecdsa_server_key("nistp256"), &h, &sgn()).is_err());
+    }
+
+    #[test]
+    fn ecdsa_signature_from_wire() {
+        let h = [1u8; 32];
+        // The signature of ecdsa_signature_verified() as it appears in SSH_MSG_KEXDH_REPLY: the name, then
+        // r and s as mpints inside a string
+        let blob = concat!(
+            "0000001365636473612d736861322d6e6973747032353600000049000000210096ad138f111ae162cdf53446b4dcd798",
+            "df166ae9ab22e559e483dd32a4f066be0000002036b6eb9ee1bd526eb1930651c73c8023e7dfa3844cdbde1b66635e",
+            "e9091ff8e8"
+        ).from_hex().unwrap();
+        let sgn = deserialize::<Signature>(&blob).unwrap();
+        let alg = ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256;
+        assert!(verify_server_signature(&alg, &ecdsa_server_key("nistp256"), &h, &sgn).is_ok());
+        assert!(verify_server_signature(&alg, &ecdsa_server_key("nistp256"), &[2u8; 32], &sgn).is_err());
+    }
+
+    #[test]
+    fn ecdsa_host_key() {
+        let der = concat!(
+            "30770201010420f23280f330b11763cbf6a1ecd3bc1c676096ba4739476121ca811bed593a167fa00a06082a8648ce3d030107a14403420004d2241031e46aa2",
+            "d2f5d5db6e236876b1e0c9d227392a0525b94679b76a11ba3d1ddf7a0cdd347d9a1956a1f55bf2175f558ad05d96f688a5e7a92d0addb1e236"
+        ).from_hex().unwrap();
+        let host_key = Arc::new(HostKey::ecdsa_from_der(&der).unwrap());
+        let (mut client, mut server) = (client_algorithms(), server_algorithms());
+        client.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256];
+        server.server_host_key_algorithms = vec![ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256];
+
+        let (v_c, v_s) = ("SSH-2.0-client".to_string(), "SSH-2.0-server".to_string());
+        let mut client = ClientKeyExchange::new(client, v_c.clone(), v_s.clone(), None, false).unwrap();
+        let mut server = ServerKeyExchange::new(server, host_key, v_c, v_s, None, false).unwrap();
+        match run_pair(&mut client, &mut server).unwrap() {
+            (Some(client_ctx), Some(server_ctx)) => assert_eq!(client_ctx.session_id(), server_ctx.session_id()),
+            _ => panic!("key exchange did not finish")
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use ecdsa;
+use handshake::into_mpint;
+use packet::types::{EcdsaSignature, ServerHostKeyAlgorithm, ServerKey, Signature};
+
+use std::fmt;
+use std::sync::Arc;
+
+use crypto::ed25519;
+use ring::{digest, rand, signature};
+use untrusted;
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_INTEGER: u8 = 0x02;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_OID: u8 = 0x06;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+// Named curves of RFC 5480 section 2.1.1.1
+const OID_SECP256R1: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_SECP384R1: &'static [u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_SECP521R1: &'static [u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+
+enum KeyPair {
+    Rsa(Arc<signature::RSAKeyPair>),
+    // The 64 byte secret key, the seed followed by the public key
+    Ed25519(Vec<u8>),
+    Ecdsa(ServerHostKeyAlgorithm, ecdsa::EcdsaPrivateKey)
+}
+
+// A server host key, which signs the exchange hash of each key exchange
+pub struct HostKey {
+    public: ServerKey,
+    key_pair: KeyPair
+}
+
+// Only the public half is shown
+impl fmt::Debug for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostKey {{ public: {:?} }}", self.public)
+    }
+}
+
+// Reads one DER element with the given tag, returning its contents and the bytes after it
+fn der_read(tag: u8, input: &[u8]) -> Result<(&[u8], &[u8]), ()> {
+    if input.len() < 2 || input[0] != tag {
+        return Err(());
+    }
+
+    let (len, header_len) = match input[1] {
+        n if n < 0x80 => (n as usize, 2),
+        0x81 if input.len() >= 3 && input[2] >= 0x80 => (input[2] as usize, 3),
+        0x82 if input.len() >= 4 && input[2] != 0 => (((input[2] as usize) << 8) + input[3] as usize, 4),
+        _ => return Err(())
+    };
+
+    if input.len() < header_len + len {
+        return Err(());
+    }
+    Ok((&input[header_len .. header_len + len], &input[header_len + len ..]))
+}
+
+// The curve of an ecdsa-sha2-* algorithm and the hash it signs with (RFC 5656 section 6.2.1)
+pub fn ecdsa_curve(alg: &ServerHostKeyAlgorithm) -> Option<(ecdsa::Curve, &'static digest::Algorithm)> {
+    match *alg {
+        ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256 => Some((ecdsa::nistp256(), &digest::SHA256)),
+        ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384 => Some((ecdsa::nistp384(), &digest::SHA384)),
+        ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP521 => Some((ecdsa::nistp521(), &digest::SHA512)),
+        _ => None
+    }
+}
+
+// A DER INTEGER is encoded the same way as an mpint, as long as it is not negative
+fn der_read_uint(input: &[u8]) -> Result<(&[u8], &[u8]), ()> {
+    let (n, rest) = try!(der_read(DER_INTEGER, input));
+    if n.is_empty() || n[0] & 0x80 != 0 {
+        return Err(());
+    }
+    Ok((n, rest))
+}
+
+impl HostKey {
+    // `der` is a PKCS#1 RSAPrivateKey, e.g. from `openssl rsa -outform DER`
+    pub fn rsa_from_der(der: &[u8]) -> Result<HostKey, ()> {
+        let (seq, rest) = try!(der_read(DER_SEQUENCE, der));
+        if !rest.is_empty() {
+            return Err(());
+        }
+
+        // RSAPrivateKey ::= SEQUENCE { version, modulus, publicExponent, ... }
+        let (_version, seq) = try!(der_read_uint(seq));
+        let (n, seq) = try!(der_read_uint(seq));
+        let (e, _) = try!(der_read_uint(seq));
+
+        let key_pair = match signature::RSAKeyPair::from_der(untrusted::Input::from(der)) {
+            Ok(key_pair) => key_pair,
+            Err(_) => return Err(())
+        };
+
+        Ok(HostKey {
+            public: ServerKey::SSH_RSA {
+                e: e.to_vec(),
+                n: n.to_vec()
+            },
+            key_pair: KeyPair::Rsa(Arc::new(key_pair))
+        })
+    }
+
+    // `seed` is the 32 byte private key of RFC 8032
+    pub fn ed25519_from_seed(seed: &[u8]) -> Result<HostKey, ()> {
+        if seed.len() != 32 {
+            return Err(());
+        }
+
+        let (secret, public) = ed25519::keypair(seed);
+        Ok(HostKey {
+            public: ServerKey::SSH_ED25519 {
+                key: public.to_vec()
+            },
+            key_pair: KeyPair::Ed25519(secret.to_vec())
+        })
+    }
+
+    // `der` is a SEC1 ECPrivateKey with a named curve, e.g. from `openssl ec -outform DER`
+    pub fn ecdsa_from_der(der: &[u8]) -> Result<HostKey, ()> {
+        let (seq, rest) = try!(der_read(DER_SEQUENCE, der));
+        if !rest.is_empty() {
+            return Err(());
+        }
+
+        // ECPrivateKey ::= SEQUENCE { version, privateKey, [0] parameters, [1] publicKey }
+        let (version, seq) = try!(der_read_uint(seq));
+        if version.len() != 1 || version[0] != 1 {
+            return Err(());
+        }
+        let (d, seq) = try!(der_read(DER_OCTET_STRING, seq));
+        let (params, _) = try!(der_read(DER_CONTEXT_0, seq));
+        let (oid, _) = try!(der_read(DER_OID, params));
+
+        let alg = if oid == OID_SECP256R1 {
+            ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256
+        } else if oid == OID_SECP384R1 {
+            ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384
+        } else if oid == OID_SECP521R1 {
+            ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP521
+        } else {
+            return Err(());
+        };
+        let (curve, _) = ecdsa_curve(&alg).unwrap();
+        let key = try!(ecdsa::EcdsaPrivateKey::from_bytes(curve, d));
+
+        let (name, q) = (key.curve().name().to_string(), key.public_key().to_vec());
+        let public = match alg {
+            ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256 => ServerKey::ECDSA_SHA2_NISTP256 { curve: name, q: q },
+            ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384 => ServerKey::ECDSA_SHA2_NISTP384 { curve: name, q: q },
+            _ => ServerKey::ECDSA_SHA2_NISTP521 { curve: name, q: q }
+        };
+        Ok(HostKey {
+            public: public,
+            key_pair: KeyPair::Ecdsa(alg, key)
+        })
+    }
+
+    // The signature algorithms this key can sign with, in order of preference
+    pub fn algorithms(&self) -> Vec<ServerHostKeyAlgorithm> {
+        match self.key_pair {
+            KeyPair::Rsa(_) => vec![ServerHostKeyAlgorithm::RSA_SHA2_512,
+                                    ServerHostKeyAlgorithm::RSA_SHA2_256,
+                                    ServerHostKeyAlgorithm::SSH_RSA],
+            KeyPair::Ed25519(_) => vec![ServerHostKeyAlgorithm::SSH_ED25519],
+            KeyPair::Ecdsa(ref alg, _) => vec![alg.clone()]
+        }
+    }
+
+    pub fn public_key(&self) -> &ServerKey {
+        &self.public
+    }
+
+    // Signs `msg` with the negotiated signature algorithm `alg`, one of `algorithms()`
+    pub fn sign(&self, alg: &ServerHostKeyAlgorithm, msg: &[u8]) -> Result<Signature, ()> {
+        let rng = rand::SystemRandom::new();
+        match self.key_pair {
+            KeyPair::Rsa(ref key_pair) => {
+                let mut signing_state = match signature::RSASigningState::new(key_pair.clone()) {
+                    Ok(st) => st,
+                    Err(_) => return Err(())
+                };
+                let mut sgn = vec![0u8; key_pair.public_modulus_len()];
+                let signed = match *alg {
+                    ServerHostKeyAlgorithm::SSH_RSA =>
+                        signing_state.sign(&signature::RSA_PKCS1_SHA1, &rng, msg, &mut sgn),
+                    ServerHostKeyAlgorithm::RSA_SHA2_256 =>
+                        signing_state.sign(&signature::RSA_PKCS1_SHA256, &rng, msg, &mut sgn),
+                    ServerHostKeyAlgorithm::RSA_SHA2_512 =>
+                        signing_state.sign(&signature::RSA_PKCS1_SHA512, &rng, msg, &mut sgn),
+                    _ => return Err(())
+                };
+                if signed.is_err() {
+                    return Err(());
+                }
+                Ok(match *alg {
+                    ServerHostKeyAlgorithm::SSH_RSA => Signature::SSH_RSA { signature: sgn },
+                    ServerHostKeyAlgorithm::RSA_SHA2_256 => Signature::RSA_SHA2_256 { signature: sgn },
+                    _ => Signature::RSA_SHA2_512 { signature: sgn }
+                })
+            },
+            KeyPair::Ed25519(ref secret) => {
+                if *alg != ServerHostKeyAlgorithm::SSH_ED25519 {
+                    return Err(());
+                }
+                Ok(Signature::SSH_ED25519 { signature: ed25519::signature(msg, secret).to_vec() })
+            },
+            KeyPair::Ecdsa(ref key_alg, ref key) => {
+                if alg != key_alg {
+                    return Err(());
+                }
+                let (_, digest_alg) = ecdsa_curve(alg).unwrap();
+                let (r, s) = key.sign(digest_alg, digest::digest(digest_alg, msg).as_ref());
+                let sgn = EcdsaSignature {
+                    r: into_mpint(&r),
+                    s: into_mpint(&s)
+                };
+                Ok(match *alg {
+                    ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256 => Signature::ECDSA_SHA2_NISTP256 { signature: sgn },
+                    ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384 => Signature::ECDSA_SHA2_NISTP384 { signature: sgn },
+                    _ => Signature::ECDSA_SHA2_NISTP521 { signature: sgn }
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{der_read, der_read_uint, ecdsa_curve, DER_INTEGER, HostKey};
+    use ecdsa;
+    use packet::types::{ServerHostKeyAlgorithm, ServerKey, Signature};
+    use ring::digest;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    #[test]
+    fn der_short_length() {
+        let input = [0x02, 0x02, 0x01, 0x00, 0xff];
+        assert_eq!(Ok((&input[2 .. 4], &input[4 ..])), der_read(DER_INTEGER, &input));
+    }
+
+    #[test]
+    fn der_long_length() {
+        let mut input = vec![0x02, 0x82, 0x01, 0x01, 0x00];
+        input.extend_from_slice(&[0xab; 256]);
+        let (n, rest) = der_read_uint(&input).unwrap();
+        assert_eq!(257, n.len());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn der_invalid() {
+        assert!(der_read(DER_INTEGER, &[0x30, 0x00]).is_err());
+        assert!(der_read(DER_INTEGER, &[0x02, 0x03, 0x01]).is_err());
+        // Lengths below 128 must use the short form
+        assert!(der_read(DER_INTEGER, &[0x02, 0x81, 0x01, 0x01]).is_err());
+        assert!(der_read_uint(&[0x02, 0x01, 0x80]).is_err());
+    }
+
+    // RFC 8032 section 7.1, test 1
+    #[test]
+    fn ed25519_sign() {
+        let seed = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60".from_hex().unwrap();
+        let key = HostKey::ed25519_from_seed(&seed).unwrap();
+        let alg = ServerHostKeyAlgorithm::SSH_ED25519;
+        assert_eq!(vec![alg.clone()], key.algorithms());
+        match *key.public_key() {
+            ServerKey::SSH_ED25519 { key: ref public } =>
+                assert_eq!("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a", public.to_hex()),
+            ref k => panic!("unexpected key {:?}", k)
+        }
+        match key.sign(&alg, b"").unwrap() {
+            Signature::SSH_ED25519 { signature } =>
+                assert_eq!(concat!("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555",
+                                   "fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"),
+                           signature.to_hex()),
+            s => panic!("unexpected signature {:?}", s)
+        }
+        assert!(key.sign(&ServerHostKeyAlgorithm::RSA_SHA2_256, b"").is_err());
+        assert!(HostKey::ed25519_from_seed(&seed[1..]).is_err());
+    }
+
+    #[test]
+    fn ecdsa_sign() {
+        let der = concat!(
+            "30770201010420f23280f330b11763cbf6a1ecd3bc1c676096ba4739476121ca811bed593a167fa00a06082a8648ce3d030107a14403420004d2241031e46aa2",
+            "d2f5d5db6e236876b1e0c9d227392a0525b94679b76a11ba3d1ddf7a0cdd347d9a1956a1f55bf2175f558ad05d96f688a5e7a92d0addb1e236"
+        ).from_hex().unwrap();
+        let key = HostKey::ecdsa_from_der(&der).unwrap();
+        let alg = ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP256;
+        assert_eq!(vec![alg.clone()], key.algorithms());
+        let q = match *key.public_key() {
+            ServerKey::ECDSA_SHA2_NISTP256 { ref curve, ref q } => {
+                assert_eq!("nistp256", curve);
+                q.clone()
+            },
+            ref k => panic!("unexpected key {:?}", k)
+        };
+        assert_eq!(concat!(
+            "04d2241031e46aa2d2f5d5db6e236876b1e0c9d227392a0525b94679b76a11ba3d1ddf7a0cdd347d9a1956a1f55bf2175f558ad05d96f688a5e7a92d0addb1e2",
+            "36"
+        ), q.to_hex());
+
+        let (curve, digest_alg) = ecdsa_curve(&alg).unwrap();
+        let digest = digest::digest(digest_alg, b"exchange hash");
+        match key.sign(&alg, b"exchange hash").unwrap() {
+            Signature::ECDSA_SHA2_NISTP256 { signature } => {
+                // Positive mpints
+                assert!(signature.r[0] & 0x80 == 0 && signature.s[0] & 0x80 == 0);
+                assert!(ecdsa::verify(&curve, &q, digest.as_ref(), &signature.r, &signature.s).is_ok());
+            },
+            s => panic!("unexpected signature {:?}", s)
+        }
+        assert!(key.sign(&ServerHostKeyAlgorithm::ECDSA_SHA2_NISTP384, b"").is_err());
+        assert!(HostKey::ecdsa_from_der(&der[..der.len() - 1]).is_err());
+    }
+}
@@ -1,7 +1,10 @@
 #![feature(proc_macro)]
 #![feature(try_from)]
 
+extern crate crypto;
+extern crate flate2;
 extern crate futures;
+extern crate num_bigint;
 extern crate rand;
 extern crate ring;
 #[macro_use]
@@ -16,11 +19,30 @@ extern crate untrusted;
 extern crate rustc_serialize;
 
 pub mod async;
+pub mod cipher;
+pub mod compression;
+pub mod dh;
+pub mod ecdsa;
 pub mod handshake;
+pub mod hostkey;
+pub mod mlkem;
+pub mod modular;
 pub mod packet;
+pub mod session;
+pub mod sntrup761;
 pub mod transport;
 
+pub const SSH_MSG_DISCONNECT: u8 = 1;
+pub const SSH_MSG_IGNORE: u8 = 2;
+pub const SSH_MSG_UNIMPLEMENTED: u8 = 3;
+pub const SSH_MSG_DEBUG: u8 = 4;
+pub const SSH_MSG_EXT_INFO: u8 = 7;
 pub const SSH_MSG_KEXINIT: u8 = 20;
 pub const SSH_MSG_NEWKEYS: u8 = 21;
 pub const SSH_MSG_KEXDH_INIT: u8 = 30;
 pub const SSH_MSG_KEXDH_REPLY: u8 = 31;
+pub const SSH_MSG_KEX_DH_GEX_GROUP: u8 = 31;
+pub const SSH_MSG_KEX_DH_GEX_INIT: u8 = 32;
+pub const SSH_MSG_KEX_DH_GEX_REPLY: u8 = 33;
+pub const SSH_MSG_KEX_DH_GEX_REQUEST: u8 = 34;
+pub const SSH_MSG_USERAUTH_SUCCESS: u8 = 52;
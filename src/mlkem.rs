@@ -0,0 +1,497 @@
+// ML-KEM-768 of FIPS 203, the post-quantum half of mlkem768x25519-sha256
+
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+use rand::Rng;
+
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 3;
+const ETA: usize = 2;
+const DU: usize = 10;
+const DV: usize = 4;
+
+const POLY_BYTES: usize = 384;
+pub const PUBLIC_KEY_LEN: usize = POLY_BYTES * K + 32;
+pub const PRIVATE_KEY_LEN: usize = POLY_BYTES * K * 2 + 96;
+pub const CIPHERTEXT_LEN: usize = 32 * (DU * K + DV);
+pub const SHARED_SECRET_LEN: usize = 32;
+
+// 17^BitRev7(i) mod q
+const ZETAS: [u32; 128] = [
+    1, 1729, 2580, 3289, 2642, 630, 1897, 848, 1062, 1919, 193, 797, 2786, 3260, 569, 1746,
+    296, 2447, 1339, 1476, 3046, 56, 2240, 1333, 1426, 2094, 535, 2882, 2393, 2879, 1974, 821,
+    289, 331, 3253, 1756, 1197, 2304, 2277, 2055, 650, 1977, 2513, 632, 2865, 33, 1320, 1915,
+    2319, 1435, 807, 452, 1438, 2868, 1534, 2402, 2647, 2617, 1481, 648, 2474, 3110, 1227, 910,
+    17, 2761, 583, 2649, 1637, 723, 2288, 1100, 1409, 2662, 3281, 233, 756, 2156, 3015, 3050,
+    1703, 1651, 2789, 1789, 1847, 952, 1461, 2687, 939, 2308, 2437, 2388, 733, 2337, 268, 641,
+    1584, 2298, 2037, 3220, 375, 2549, 2090, 1645, 1063, 319, 2773, 757, 2099, 561, 2466, 2594,
+    2804, 1092, 403, 1026, 1143, 2150, 2775, 886, 1722, 1212, 1874, 1029, 2110, 2935, 885, 2154
+];
+
+// 17^(2 BitRev7(i) + 1) mod q
+const GAMMAS: [u32; 128] = [
+    17, 3312, 2761, 568, 583, 2746, 2649, 680, 1637, 1692, 723, 2606, 2288, 1041, 1100, 2229,
+    1409, 1920, 2662, 667, 3281, 48, 233, 3096, 756, 2573, 2156, 1173, 3015, 314, 3050, 279,
+    1703, 1626, 1651, 1678, 2789, 540, 1789, 1540, 1847, 1482, 952, 2377, 1461, 1868, 2687, 642,
+    939, 2390, 2308, 1021, 2437, 892, 2388, 941, 733, 2596, 2337, 992, 268, 3061, 641, 2688,
+    1584, 1745, 2298, 1031, 2037, 1292, 3220, 109, 375, 2954, 2549, 780, 2090, 1239, 1645, 1684,
+    1063, 2266, 319, 3010, 2773, 556, 757, 2572, 2099, 1230, 561, 2768, 2466, 863, 2594, 735,
+    2804, 525, 1092, 2237, 403, 2926, 1026, 2303, 1143, 2186, 2150, 1179, 2775, 554, 886, 2443,
+    1722, 1607, 1212, 2117, 1874, 1455, 1029, 2300, 2110, 1219, 2935, 394, 885, 2444, 2154, 1175
+];
+
+type Poly = [u32; N];
+
+fn sha3(mut h: Sha3, parts: &[&[u8]], out: &mut [u8]) {
+    for part in parts {
+        h.input(part);
+    }
+    h.result(out);
+}
+
+// H
+fn hash_h(input: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    sha3(Sha3::sha3_256(), &[input], &mut out);
+    out
+}
+
+// G, split into its two halves
+fn hash_g(parts: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut out = [0u8; 64];
+    sha3(Sha3::sha3_512(), parts, &mut out);
+    let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+    a.copy_from_slice(&out[..32]);
+    b.copy_from_slice(&out[32..]);
+    (a, b)
+}
+
+// J
+fn hash_j(z: &[u8], c: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    sha3(Sha3::shake_256(), &[z, c], &mut out);
+    out
+}
+
+fn prf(seed: &[u8; 32], nonce: u8) -> [u8; 64 * ETA] {
+    let mut out = [0u8; 64 * ETA];
+    sha3(Sha3::shake_256(), &[seed, &[nonce]], &mut out);
+    out
+}
+
+// Rejection sampling of a polynomial in the NTT domain from SHAKE128(rho || j || i)
+fn sample_ntt(rho: &[u8], j: u8, i: u8) -> Poly {
+    let mut xof = Sha3::shake_128();
+    xof.input(rho);
+    xof.input(&[j, i]);
+
+    let mut f = [0u32; N];
+    let mut n = 0;
+    let mut buf = [0u8; 168];
+    while n < N {
+        xof.result(&mut buf);
+        for c in buf.chunks(3) {
+            let d1 = c[0] as u32 + ((c[1] as u32 & 0x0f) << 8);
+            let d2 = (c[1] as u32 >> 4) + ((c[2] as u32) << 4);
+            for &d in &[d1, d2] {
+                if d < Q && n < N {
+                    f[n] = d;
+                    n += 1;
+                }
+            }
+        }
+    }
+    f
+}
+
+// A[i][j] = SampleNTT(rho || j || i)
+fn sample_matrix(rho: &[u8]) -> [[Poly; K]; K] {
+    let mut a = [[[0u32; N]; K]; K];
+    for i in 0..K {
+        for j in 0..K {
+            a[i][j] = sample_ntt(rho, j as u8, i as u8);
+        }
+    }
+    a
+}
+
+// Centered binomial distribution with eta = 2
+fn sample_cbd(buf: &[u8]) -> Poly {
+    let mut f = [0u32; N];
+    for i in 0..N / 2 {
+        let b = buf[i];
+        let x0 = (b & 1) + ((b >> 1) & 1);
+        let y0 = ((b >> 2) & 1) + ((b >> 3) & 1);
+        let x1 = ((b >> 4) & 1) + ((b >> 5) & 1);
+        let y1 = ((b >> 6) & 1) + ((b >> 7) & 1);
+        f[2 * i] = (Q + x0 as u32 - y0 as u32) % Q;
+        f[2 * i + 1] = (Q + x1 as u32 - y1 as u32) % Q;
+    }
+    f
+}
+
+fn ntt(f: &mut Poly) {
+    let mut k = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).filter(|s| s % (2 * len) == 0) {
+            let zeta = ZETAS[k];
+            k += 1;
+            for j in start..start + len {
+                let t = zeta * f[j + len] % Q;
+                f[j + len] = (f[j] + Q - t) % Q;
+                f[j] = (f[j] + t) % Q;
+            }
+        }
+        len /= 2;
+    }
+}
+
+fn inv_ntt(f: &mut Poly) {
+    let mut k = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).filter(|s| s % (2 * len) == 0) {
+            let zeta = ZETAS[k];
+            k -= 1;
+            for j in start..start + len {
+                let t = f[j];
+                f[j] = (t + f[j + len]) % Q;
+                f[j + len] = zeta * ((f[j + len] + Q - t) % Q) % Q;
+            }
+        }
+        len *= 2;
+    }
+    // 128^-1 mod q
+    for c in f.iter_mut() {
+        *c = *c * 3303 % Q;
+    }
+}
+
+// Adds f * g, both in the NTT domain, to acc
+fn mul_acc(acc: &mut Poly, f: &Poly, g: &Poly) {
+    for i in 0..N / 2 {
+        let (a0, a1, b0, b1) = (f[2 * i], f[2 * i + 1], g[2 * i], g[2 * i + 1]);
+        let c0 = (a0 * b0 + a1 * b1 % Q * GAMMAS[i]) % Q;
+        let c1 = (a0 * b1 + a1 * b0) % Q;
+        acc[2 * i] = (acc[2 * i] + c0) % Q;
+        acc[2 * i + 1] = (acc[2 * i + 1] + c1) % Q;
+    }
+}
+
+fn add(f: &mut Poly, g: &Poly) {
+    for (a, b) in f.iter_mut().zip(g.iter()) {
+        *a = (*a + b) % Q;
+    }
+}
+
+// Packs d-bit coefficients, least significant bit first
+fn byte_encode(f: &Poly, d: usize, out: &mut Vec<u8>) {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &c in f.iter() {
+        acc |= c << bits;
+        bits += d;
+        while bits >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+}
+
+fn byte_decode(buf: &[u8], d: usize) -> Poly {
+    let mut f = [0u32; N];
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut bytes = buf.iter();
+    for c in f.iter_mut() {
+        while bits < d {
+            acc |= (*bytes.next().unwrap() as u32) << bits;
+            bits += 8;
+        }
+        *c = acc & ((1 << d) - 1);
+        acc >>= d;
+        bits -= d;
+    }
+    f
+}
+
+fn compress(f: &Poly, d: usize) -> Poly {
+    let mut out = [0u32; N];
+    for (o, &c) in out.iter_mut().zip(f.iter()) {
+        *o = ((c << d) + Q / 2) / Q & ((1 << d) - 1);
+    }
+    out
+}
+
+fn decompress(f: &Poly, d: usize) -> Poly {
+    let mut out = [0u32; N];
+    for (o, &c) in out.iter_mut().zip(f.iter()) {
+        *o = (c * Q + (1 << (d - 1))) >> d;
+    }
+    out
+}
+
+// The encapsulation key must hold coefficients below q only (FIPS 203 section 7.2)
+fn check_public_key(ek: &[u8]) -> bool {
+    if ek.len() != PUBLIC_KEY_LEN {
+        return false;
+    }
+    ek[..POLY_BYTES * K].chunks(POLY_BYTES).all(|buf| byte_decode(buf, 12).iter().all(|&c| c < Q))
+}
+
+// K-PKE.KeyGen, returning the encapsulation key and the encoded secret vector
+fn pke_keygen(d: &[u8; 32]) -> (Vec<u8>, Vec<u8>) {
+    let (rho, sigma) = hash_g(&[d, &[K as u8]]);
+    let a = sample_matrix(&rho);
+
+    let mut nonce = 0;
+    let mut s = [[0u32; N]; K];
+    for p in s.iter_mut() {
+        *p = sample_cbd(&prf(&sigma, nonce));
+        ntt(p);
+        nonce += 1;
+    }
+    let mut e = [[0u32; N]; K];
+    for p in e.iter_mut() {
+        *p = sample_cbd(&prf(&sigma, nonce));
+        ntt(p);
+        nonce += 1;
+    }
+
+    let mut ek = Vec::with_capacity(PUBLIC_KEY_LEN);
+    let mut dk = Vec::with_capacity(POLY_BYTES * K);
+    for i in 0..K {
+        let mut t = e[i];
+        for j in 0..K {
+            mul_acc(&mut t, &a[i][j], &s[j]);
+        }
+        byte_encode(&t, 12, &mut ek);
+        byte_encode(&s[i], 12, &mut dk);
+    }
+    ek.extend_from_slice(&rho);
+    (ek, dk)
+}
+
+// K-PKE.Encrypt
+fn pke_encrypt(ek: &[u8], m: &[u8; 32], r: &[u8; 32]) -> Vec<u8> {
+    let mut t = [[0u32; N]; K];
+    for (p, buf) in t.iter_mut().zip(ek.chunks(POLY_BYTES)) {
+        *p = byte_decode(buf, 12);
+    }
+    let a = sample_matrix(&ek[POLY_BYTES * K..]);
+
+    let mut nonce = 0;
+    let mut y = [[0u32; N]; K];
+    for p in y.iter_mut() {
+        *p = sample_cbd(&prf(r, nonce));
+        ntt(p);
+        nonce += 1;
+    }
+
+    let mut c = Vec::with_capacity(CIPHERTEXT_LEN);
+    for i in 0..K {
+        let mut u = [0u32; N];
+        for j in 0..K {
+            mul_acc(&mut u, &a[j][i], &y[j]);
+        }
+        inv_ntt(&mut u);
+        add(&mut u, &sample_cbd(&prf(r, nonce)));
+        nonce += 1;
+        byte_encode(&compress(&u, DU), DU, &mut c);
+    }
+
+    let mut v = [0u32; N];
+    for i in 0..K {
+        mul_acc(&mut v, &t[i], &y[i]);
+    }
+    inv_ntt(&mut v);
+    add(&mut v, &sample_cbd(&prf(r, nonce)));
+    add(&mut v, &decompress(&byte_decode(m, 1), 1));
+    byte_encode(&compress(&v, DV), DV, &mut c);
+    c
+}
+
+// K-PKE.Decrypt
+fn pke_decrypt(dk: &[u8], c: &[u8]) -> [u8; 32] {
+    let mut w = [0u32; N];
+    for (buf, s) in c.chunks(32 * DU).zip(dk.chunks(POLY_BYTES)) {
+        let mut u = decompress(&byte_decode(buf, DU), DU);
+        ntt(&mut u);
+        mul_acc(&mut w, &byte_decode(s, 12), &u);
+    }
+    inv_ntt(&mut w);
+
+    let mut v = decompress(&byte_decode(&c[32 * DU * K..], DV), DV);
+    for (a, b) in v.iter_mut().zip(w.iter()) {
+        *a = (*a + Q - b) % Q;
+    }
+
+    let mut m = Vec::with_capacity(32);
+    byte_encode(&compress(&v, 1), 1, &mut m);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&m);
+    out
+}
+
+pub struct PrivateKey {
+    // dk_pke || ek || H(ek) || z
+    dk: Vec<u8>
+}
+
+impl PrivateKey {
+    pub fn generate(rng: &mut Rng) -> PrivateKey {
+        let (mut d, mut z) = ([0u8; 32], [0u8; 32]);
+        rng.fill_bytes(&mut d);
+        rng.fill_bytes(&mut z);
+        PrivateKey::from_seed(&d, &z)
+    }
+
+    // The 64 byte seed d || z of ML-KEM.KeyGen_internal
+    pub fn from_seed(d: &[u8; 32], z: &[u8; 32]) -> PrivateKey {
+        let (ek, mut dk) = pke_keygen(d);
+        dk.extend_from_slice(&ek);
+        dk.extend_from_slice(&hash_h(&ek));
+        dk.extend_from_slice(z);
+        PrivateKey { dk: dk }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.dk[POLY_BYTES * K .. POLY_BYTES * K + PUBLIC_KEY_LEN]
+    }
+
+    // A wrong ciphertext of the right length yields a pseudorandom secret instead of an error
+    pub fn decapsulate(&self, c: &[u8]) -> Result<[u8; SHARED_SECRET_LEN], ()> {
+        if c.len() != CIPHERTEXT_LEN {
+            return Err(());
+        }
+
+        let dk_pke = &self.dk[.. POLY_BYTES * K];
+        let ek = self.public_key();
+        let h = &self.dk[POLY_BYTES * K + PUBLIC_KEY_LEN .. PRIVATE_KEY_LEN - 32];
+        let z = &self.dk[PRIVATE_KEY_LEN - 32 ..];
+
+        let m = pke_decrypt(dk_pke, c);
+        let (key, r) = hash_g(&[&m, h]);
+        let rejected = hash_j(z, c);
+        let c2 = pke_encrypt(ek, &m, &r);
+
+        // Constant time selection of the rejection key
+        let mask = c.iter().zip(c2.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        let mask = ((mask as u16).wrapping_sub(1) >> 8) as u8 ^ 0xff;
+        let mut out = [0u8; SHARED_SECRET_LEN];
+        for i in 0..SHARED_SECRET_LEN {
+            out[i] = key[i] ^ (mask & (key[i] ^ rejected[i]));
+        }
+        Ok(out)
+    }
+}
+
+fn encapsulate_with(ek: &[u8], m: &[u8; 32]) -> (Vec<u8>, [u8; SHARED_SECRET_LEN]) {
+    let (key, r) = hash_g(&[m, &hash_h(ek)]);
+    (pke_encrypt(ek, m, &r), key)
+}
+
+// Returns the ciphertext and the shared secret
+pub fn encapsulate(ek: &[u8], rng: &mut Rng) -> Result<(Vec<u8>, [u8; SHARED_SECRET_LEN]), ()> {
+    if !check_public_key(ek) {
+        return Err(());
+    }
+    let mut m = [0u8; 32];
+    rng.fill_bytes(&mut m);
+    Ok(encapsulate_with(ek, &m))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::OsRng;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    #[test]
+    fn round_trip() {
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng);
+        let (c, k) = encapsulate(key.public_key(), &mut rng).unwrap();
+        assert_eq!(CIPHERTEXT_LEN, c.len());
+        assert_eq!(k, key.decapsulate(&c).unwrap());
+    }
+
+    #[test]
+    fn implicit_rejection() {
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng);
+        let (mut c, k) = encapsulate(key.public_key(), &mut rng).unwrap();
+        c[0] ^= 1;
+        assert!(k != key.decapsulate(&c).unwrap());
+        assert!(key.decapsulate(&c[1..]).is_err());
+    }
+
+    #[test]
+    fn public_key_checked() {
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng);
+        let mut ek = key.public_key().to_vec();
+        // The first coefficient becomes 4095
+        ek[0] = 0xff;
+        ek[1] |= 0x0f;
+        assert!(encapsulate(&ek, &mut rng).is_err());
+        assert!(encapsulate(&ek[1..], &mut rng).is_err());
+    }
+
+    // Key from the seed 00 01 .. 3f, ciphertext and secret from OpenSSL's ML-KEM-768
+    #[test]
+    fn interop_vector() {
+        let (mut d, mut z) = ([0u8; 32], [0u8; 32]);
+        for i in 0..32 {
+            d[i] = i as u8;
+            z[i] = 32 + i as u8;
+        }
+        let key = PrivateKey::from_seed(&d, &z);
+        let ek = concat!(
+            "298aa10d423c8dda069d02bc59e6cdf03a096b8b3da4cab9b80ca4a14907672ccef1ec4faf234a0bc5b7e9d473f2b3133b3b26a1d175cb67a7805919699c02f7",
+            "6531b99c5f89180704bb4ca4535c5b8972679c660a07c5e514b87009c862eb8f5157695efb3fc40a9def6b81c1cc02a249ae4f094ad0d9bd3485c1c1c6808052",
+            "0a7c8c632032cee738154e5c5176c07da56024776a430fe76eacf665a3f7b832102215bc82f10939c8355704336a8fac1d81e4bb0485aa5d7c74d6b59bbe5c5e",
+            "972a0d8bac411b55b5d5557cd680a1a8f71b4eb86bc48c9a0509731a54bd9d7290b27963e4372dc9b199cfdcac0b01acd28a62395112e4c43648d622c48c8234",
+            "d01440e8cc376c927f23a5afc9ac0474c662274e424525c8552ece3b3fe26516de901bc7d515bde89558e626c95c80b93342f8010004f39e6c6c94871c5e344c",
+            "ab3966c835f9a96a59afd31c40286b38b1c1a78470bab947518934453ce86736a919f1f5a6d510a86f5454fc3980cb5c765bd2bd5f7b36b1410d6635c8ceb47c",
+            "4dda0d76a28eac939c71c3024804866c71626658442163c2c22117e50acefce6378a985652302a4ef0c2ce0cc716b7796e2b6b2e3777dfa1ac3da259a31b5a9b",
+            "530f8cb638a81a62ac301849abaf95a7301bda30068909bfdb7e67dbccbb38a5551a25b1a3a0f685748ad5753d8880f0016c627486166384c5571fe236590036",
+            "4d038311e2d875db366686932b5ec602430a369e87a6ef5c338786657825bd4c057aceb923eb0935e6905e63b4ced7f80857a773dd64b150d26612ea9ac12052",
+            "db2017bf1843ccb4b3281b690dc728adfa85c00281b8e3c09287335f856b4fc2892f69a2f57921ada01914c40988662d57769662a786351b9b66493dab79594d",
+            "986de2100d65ba0ff4ea58b81538d24a4435a258fac25404aa7f41f658b1385065e158dcb60115732720f40459aaac15e406953a90ac52997d1ccd070060efc6",
+            "5db9e653354467fad56ec713c86e7540c423acf2669f52fa6f4ac6888d871ef3e847c029a8aafbb92e17b24aa079b1f419ba6175b442afb11909d4a56b70a033",
+            "5b28739218aa7c9348e2c3c2f3eb3d15a41e6417c0dd94bfeb21419b311a7bb13a180bbe833218a9a6b17447cc85f225859587a73077049acbcfd44d0f025438",
+            "e15d1538270d586e1bf83192a9459cf63c0e972f85297679831ecf121509851cb8340f6f107b0fa1a0efd1b36a8189bc085c4f5cb784e553f41b918f80397ce1",
+            "956f785bee377ca9aa8be6998ada30c26b7c3d8c6b55254cc96203b20c42aee0ac4e1ebb408e49a9e3f879d0ab0785eb7025425d1305a2299c015e120d163b0e",
+            "19494ce57253d0246d182745cb8197ab7438b3c1bb7972bec5a306eba3567855c014699fef65ae54c770a0d85c18400cf642aedc660777ba4b138502bd5a7812",
+            "f621f84a48296b98dd4322b6f15828b8a8f0e00a8ba44a53c3a8b143571b0740abd567daf1cde9c79c204b6d5e259d1766a31bbbcb4e6a05cf4502176b301c1c",
+            "2f41247750157bcec85e809b30a4d60d7747cdd0f5b99aa8c826987517793aaa8080a0b124a8558df72bbe37b75f4edbb6be8216d6c633fb2b2280e25113d869",
+            "5e43481c3eeb397eb192505229b67a201ea893c3e2cb32da8bc342fa4dea0578"
+        );
+        assert_eq!(ek, key.public_key().to_hex());
+
+        let c = concat!(
+            "79066a3865c2e3d9d2de93654819a9d57b3e91ef4f5b14086db9dd4844ec3230439bd67757a1388177d180edfe2702b8356df549a7a992ebcddc7621952e9eee",
+            "b60cc7bcd3d1b34b033291b14e7e312626dd3cafbd2dff6e0a8b81eda8c5094224bd426ef6a0b87fbf5d37010bcb0be723f1fb9a33ff3218bcf81aed111720b5",
+            "6cfb02bcd27a5a9b614c5cb781b549c9ad5bf99f44ab7a780bdbe36d43621332877fff7f583408f5de26c3c038e0c291c26647523d88903a7daa7f7bfdf18355",
+            "6a451d1739075dfa00d2b89ee89570ba234c7c3e62f294c41635ae9970a4058e8adf7202652ce6ab1c376c7fe52bba803fef776ebc3dea84f48e2b53e0353aed",
+            "5a7beca86911a5439294361f198ab2048ad8ec14451eb706a013081388dfcd9cf9f8a2800ee1cc022c7705aab3f65229301437f57f28bb2ccb2fe30ce85a1b57",
+            "47738b67335856808e0a6d78edb2f4266fcfce34d777aea9fd5372c9f3c5d9a21cbba4dd5d590cb5876bd9d2baaa26cfbb0e4634935cc7f8de11adc20ecce282",
+            "d0e3576cf96e3528cad868d27f2285979005cde5a439c955d5c6be3a881f02facb327572ef71c13af0f80915a0bf95a25beb37619aa7c6309933a4672adc01ce",
+            "68686f6fb57c056a554877e29427a60a9b7dc509c9abed98f84a56f0862fc0ad56982fdaea76c8479fac10c3a74ef7b33a0abb4e4d86489dc634bc07ccc3eeee",
+            "9a028db07327c12682aa14f8272b0b366cb64ff5568b9a07848c04bce96057b06c1f49612fc833e16e84381a8d4aadfd9eb3aadfc99aec4a1ded20ca50c03d29",
+            "bc8d196087025895e22b2cba95fc1de9a5078763d7927debc85572cd4163177534fb19fea0359ba13f08a74d12f3e83c711821e12e68142aaecaa28e07c119c9",
+            "c68a6b23ecc67f94222ae7bb89d8f52bfc608085cd26114d8f4b7b393277546fe5064753575d350fa28d7a8b2aff709cc6a490bb4d75f0949c3a713b5ab4d377",
+            "be4284b6b4e38bb80b07b46cc26d1a6d9ea51a2cb0eca082bd13e456c575575ccc88a6935d78f9541b16f85eef5c8fbe6a82ec1b294f1c3b2c305f82ded47024",
+            "1a344b56e503ab29fd6010d83391d212112c766e620d40e13aa749e42f2b0c0fc493029851c398b04c7b8f8e8f53f9f41383997861cb81409cbb5f62c5405ab7",
+            "b8ae749dbbef9342c31adf6731b920772262f56023ee4f38fe20e7ee96eef924f5ebd993970139276672bdc5f3db0fc1fd24d0c96f8392bd25632267bbd349d0",
+            "ab36cff9cfba9d922f19fda91e57b6ae2c2b678f598fa34ca9d1b6eff2a56519d6d1a4652ce77cc6ad5d146179639c399fb4165cd48ab27adff91d668ac401c3",
+            "528546bd0e755a9c9d8528831b6d5917fffef4682e3967a6330c34d2b8bb00591d97fd02be86a6eb02de4aef9fba53a1aba0f7c4740ef492e805944126cd5dc7",
+            "a3e55e84c78a7589feb30c2c5f3248355ebbc271bbcda5627bea58b68d8266d1711ab0a0848a1b8722fba37d9313b3dd2e1b1cfc39de4637d24f0473f568f705"
+        ).from_hex().unwrap();
+        assert_eq!("467378a3742def3fb01a037d1b05aa7bb6063557d820b881cb40001cd2e68692", key.decapsulate(&c).unwrap().to_hex());
+    }
+}
@@ -0,0 +1,288 @@
+use num_bigint::BigUint;
+
+// Arithmetic modulo an odd number in Montgomery form, on fixed-width little-endian 32-bit limbs.
+// Which instructions run and which memory they touch depends on the width of the modulus only,
+// never on the values, so secret exponents and scalars don't show in the timing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Modulus {
+    m: Vec<u32>,
+    // Length of the modulus in bytes
+    len: usize,
+    // -m^-1 mod 2^32
+    m0_inv: u32,
+    // R^2 mod m, where R = 2^(32 * limbs)
+    rr: Vec<u32>
+}
+
+// All ones if a == b, else zero
+pub fn ct_eq(a: u32, b: u32) -> u32 {
+    let x = a ^ b;
+    // The top bit of x | -x is set unless x is zero
+    (((x | x.wrapping_neg()) >> 31) ^ 1).wrapping_neg()
+}
+
+// a where the mask is set, b where it is not
+pub fn select(mask: u32, a: &[u32], b: &[u32]) -> Vec<u32> {
+    a.iter().zip(b).map(|(&a, &b)| (a & mask) | (b & !mask)).collect()
+}
+
+// The entry at `idx`, read by going through the whole table
+pub fn lookup(table: &[Vec<u32>], idx: u32) -> Vec<u32> {
+    let mut out = vec![0u32; table[0].len()];
+    for (i, entry) in table.iter().enumerate() {
+        let mask = ct_eq(i as u32, idx);
+        for (o, &e) in out.iter_mut().zip(entry) {
+            *o |= e & mask;
+        }
+    }
+    out
+}
+
+// An unsigned big-endian integer in n limbs. Leading bytes past the n limbs have to be zero.
+fn limbs_from_be(bytes: &[u8], n: usize) -> Vec<u32> {
+    let mut limbs = vec![0u32; n];
+    for (i, &b) in bytes.iter().rev().enumerate() {
+        if i < 4 * n {
+            limbs[i / 4] |= (b as u32) << (8 * (i % 4));
+        }
+    }
+    limbs
+}
+
+// out = a - b, returning the borrow
+fn sub_limbs(a: &[u32], b: &[u32], out: &mut [u32]) -> u32 {
+    let mut borrow = 0u64;
+    for i in 0..a.len() {
+        let d = (a[i] as u64).wrapping_sub(b[i] as u64).wrapping_sub(borrow);
+        out[i] = d as u32;
+        borrow = d >> 63;
+    }
+    borrow as u32
+}
+
+// out = a + b, returning the carry
+fn add_limbs(a: &[u32], b: &[u32], out: &mut [u32]) -> u32 {
+    let mut carry = 0u64;
+    for i in 0..a.len() {
+        let s = a[i] as u64 + b[i] as u64 + carry;
+        out[i] = s as u32;
+        carry = s >> 32;
+    }
+    carry as u32
+}
+
+impl Modulus {
+    // `m` has to be odd and greater than one
+    pub fn new(m: &BigUint) -> Modulus {
+        let n = (m.bits() + 31) / 32;
+        let limbs = limbs_from_be(&m.to_bytes_be(), n);
+        // Newton's iteration doubles the number of correct low bits each time
+        let mut inv = 1u32;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(limbs[0].wrapping_mul(inv)));
+        }
+        let rr = (BigUint::from(1u32) << (64 * n)) % m;
+        Modulus {
+            m: limbs,
+            len: (m.bits() + 7) / 8,
+            m0_inv: inv.wrapping_neg(),
+            rr: limbs_from_be(&rr.to_bytes_be(), n)
+        }
+    }
+
+    // The length of the modulus in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn limbs(&self) -> usize {
+        self.m.len()
+    }
+
+    // Subtracts m once if t, with the extra top limb `hi`, is not below it
+    fn reduce_once(&self, t: &[u32], hi: u32) -> Vec<u32> {
+        let mut d = vec![0u32; self.limbs()];
+        let borrow = sub_limbs(t, &self.m, &mut d);
+        // t < m exactly when the borrow is not absorbed by the top limb
+        let below = ((hi as u64).wrapping_sub(borrow as u64) >> 63) as u32;
+        select(below.wrapping_neg(), t, &d)
+    }
+
+    // a * b / R mod m, for a and b below m
+    pub fn mul(&self, a: &[u32], b: &[u32]) -> Vec<u32> {
+        let n = self.limbs();
+        let mut t = vec![0u32; n + 2];
+        for i in 0..n {
+            let mut carry = 0u64;
+            for j in 0..n {
+                let s = t[j] as u64 + a[j] as u64 * b[i] as u64 + carry;
+                t[j] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[n] as u64 + carry;
+            t[n] = s as u32;
+            t[n + 1] = (s >> 32) as u32;
+
+            // Adding a multiple of m clears the lowest limb, which is then shifted out
+            let q = t[0].wrapping_mul(self.m0_inv);
+            let s = t[0] as u64 + q as u64 * self.m[0] as u64;
+            let mut carry = s >> 32;
+            for j in 1..n {
+                let s = t[j] as u64 + q as u64 * self.m[j] as u64 + carry;
+                t[j - 1] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[n] as u64 + carry;
+            t[n - 1] = s as u32;
+            t[n] = t[n + 1] + (s >> 32) as u32;
+        }
+        self.reduce_once(&t[..n], t[n])
+    }
+
+    pub fn add(&self, a: &[u32], b: &[u32]) -> Vec<u32> {
+        let mut s = vec![0u32; self.limbs()];
+        let carry = add_limbs(a, b, &mut s);
+        self.reduce_once(&s, carry)
+    }
+
+    pub fn sub(&self, a: &[u32], b: &[u32]) -> Vec<u32> {
+        let n = self.limbs();
+        let mut d = vec![0u32; n];
+        let borrow = sub_limbs(a, b, &mut d);
+        let m = select(borrow.wrapping_neg(), &self.m, &vec![0u32; n]);
+        let mut out = vec![0u32; n];
+        add_limbs(&d, &m, &mut out);
+        out
+    }
+
+    // 1 in Montgomery form
+    pub fn one(&self) -> Vec<u32> {
+        self.to_mont(&limbs_from_be(&[1], self.limbs()))
+    }
+
+    fn to_mont(&self, a: &[u32]) -> Vec<u32> {
+        self.mul(a, &self.rr)
+    }
+
+    // An unsigned big-endian integer in Montgomery form, or None unless it is below m
+    pub fn from_be(&self, bytes: &[u8]) -> Option<Vec<u32>> {
+        if bytes.len() > 4 * self.limbs() {
+            return None;
+        }
+        let a = limbs_from_be(bytes, self.limbs());
+        let mut d = vec![0u32; self.limbs()];
+        if sub_limbs(&a, &self.m, &mut d) == 0 {
+            return None;
+        }
+        Some(self.to_mont(&a))
+    }
+
+    // Like from_be(), for a value below 2m, which is reduced first
+    pub fn from_be_reduced(&self, bytes: &[u8]) -> Vec<u32> {
+        let a = self.reduce_once(&limbs_from_be(bytes, self.limbs()), 0);
+        self.to_mont(&a)
+    }
+
+    // Out of Montgomery form, as an unsigned big-endian integer as long as m
+    pub fn to_be(&self, a: &[u32]) -> Vec<u8> {
+        let x = self.mul(a, &limbs_from_be(&[1], self.limbs()));
+        (0..self.len).rev().map(|i| (x[i / 4] >> (8 * (i % 4))) as u8).collect()
+    }
+
+    // Whether a is zero, without giving away anything else about it
+    pub fn is_zero(&self, a: &[u32]) -> bool {
+        a.iter().fold(0, |acc, &x| acc | x) == 0
+    }
+
+    // base^exp for an unsigned big-endian exponent, in fixed 4-bit windows. Only the length of
+    // the exponent decides how long it takes.
+    pub fn pow(&self, base: &[u32], exp: &[u8]) -> Vec<u32> {
+        let mut table = vec![self.one(), base.to_vec()];
+        for i in 2..16 {
+            let entry = self.mul(&table[i - 1], base);
+            table.push(entry);
+        }
+
+        let mut acc = self.one();
+        for &byte in exp {
+            for &window in &[byte >> 4, byte & 0x0f] {
+                for _ in 0..4 {
+                    acc = self.mul(&acc, &acc);
+                }
+                acc = self.mul(&acc, &lookup(&table, window as u32));
+            }
+        }
+        acc
+    }
+
+    // a^-1 as a^(m-2), for a prime m. Zero stays zero.
+    pub fn inv(&self, a: &[u32]) -> Vec<u32> {
+        let mut e = vec![0u32; self.limbs()];
+        sub_limbs(&self.m, &limbs_from_be(&[2], self.limbs()), &mut e);
+        let exp: Vec<u8> = (0..4 * self.limbs()).rev().map(|i| (e[i / 4] >> (8 * (i % 4))) as u8).collect();
+        self.pow(a, &exp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use num_bigint::BigUint;
+    use rand::{OsRng, Rng};
+
+    fn random_below(m: &BigUint, rng: &mut OsRng) -> BigUint {
+        let mut buf = vec![0u8; (m.bits() + 7) / 8 + 8];
+        rng.fill_bytes(&mut buf);
+        BigUint::from_bytes_be(&buf) % m
+    }
+
+    #[test]
+    fn matches_biguint() {
+        let mut rng = OsRng::new().unwrap();
+        let moduli = [
+            "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff",
+            "1ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "c90fdaa22168c234c4c6628b80dc1cd1",
+            "3"
+        ];
+        for m in moduli.iter() {
+            let m = BigUint::parse_bytes(m.as_bytes(), 16).unwrap();
+            let modulus = Modulus::new(&m);
+            for _ in 0..20 {
+                let (a, b) = (random_below(&m, &mut rng), random_below(&m, &mut rng));
+                let (am, bm) = (modulus.from_be(&a.to_bytes_be()).unwrap(), modulus.from_be(&b.to_bytes_be()).unwrap());
+                let value = |x: &[u32]| BigUint::from_bytes_be(&modulus.to_be(x));
+                assert_eq!(a, value(&am));
+                assert_eq!((&a * &b) % &m, value(&modulus.mul(&am, &bm)));
+                assert_eq!((&a + &b) % &m, value(&modulus.add(&am, &bm)));
+                assert_eq!((&a + &m - &b) % &m, value(&modulus.sub(&am, &bm)));
+                let e = [0x5a, 0x00, 0xff];
+                assert_eq!(a.modpow(&BigUint::from_bytes_be(&e), &m), value(&modulus.pow(&am, &e)));
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_and_range() {
+        let m = BigUint::parse_bytes(b"ffffffff00000001000000000000000000000000ffffffffffffffffffffffff", 16).unwrap();
+        let modulus = Modulus::new(&m);
+        let a = modulus.from_be(&[7]).unwrap();
+        assert_eq!(modulus.one(), modulus.mul(&a, &modulus.inv(&a)));
+        assert!(modulus.is_zero(&modulus.sub(&a, &a)));
+        assert_eq!(32, modulus.len());
+
+        assert!(modulus.from_be(&m.to_bytes_be()).is_none());
+        assert!(modulus.from_be(&[1; 33]).is_none());
+        let m_plus_one = (&m + BigUint::from(1u32)).to_bytes_be();
+        assert_eq!(modulus.one(), modulus.from_be_reduced(&m_plus_one));
+    }
+
+    #[test]
+    fn table_lookup() {
+        let table = vec![vec![1, 2], vec![3, 4], vec![5, 6]];
+        assert_eq!(vec![3, 4], lookup(&table, 1));
+        assert_eq!(vec![0, 0], lookup(&table, 3));
+        assert_eq!(vec![1, 2], select(ct_eq(7, 7), &[1, 2], &[3, 4]));
+        assert_eq!(vec![3, 4], select(ct_eq(7, 8), &[1, 2], &[3, 4]));
+    }
+}
@@ -178,6 +178,35 @@ pub fn de_name_list<D: de::Deserializer, T: Name>(d: &mut D) -> Result<Vec<T>, D
     d.deserialize_str(visitor)
 }
 
+struct SeqVisitor<'a, 'b: 'a> {
+    deserializer: &'a mut BinaryDecoder<'b>,
+    len: usize,
+}
+
+impl<'a, 'b: 'a> de::SeqVisitor for SeqVisitor<'a, 'b> {
+    type Error = DecoderError;
+
+    fn visit<T>(&mut self) -> Result<Option<T>, Self::Error>
+        where T: de::Deserialize,
+    {
+        if self.len > 0 {
+            self.len -= 1;
+            let value = try!(de::Deserialize::deserialize(self.deserializer));
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn end(&mut self) -> Result<(), Self::Error> {
+        if self.len == 0 {
+            Ok(())
+        } else {
+            Err(DecoderError::Serde(de::value::Error::Custom("expected end".into())))
+        }
+    }
+}
+
 macro_rules! impl_error {
     ($func:ident($($arg:ty),*), $errtype:expr) => {
         #[inline]
@@ -246,35 +275,14 @@ impl<'a> de::Deserializer for BinaryDecoder<'a> {
             -> Result<V::Value, DecoderError>
         where V: de::Visitor
     {
-        struct SeqVisitor<'a, 'b: 'a> {
-            deserializer: &'a mut BinaryDecoder<'b>,
-            len: usize,
-        }
-
-        impl<'a, 'b: 'a> de::SeqVisitor for SeqVisitor<'a, 'b> {
-            type Error = DecoderError;
-
-            fn visit<T>(&mut self) -> Result<Option<T>, Self::Error>
-                where T: de::Deserialize,
-            {
-                if self.len > 0 {
-                    self.len -= 1;
-                    let value = try!(de::Deserialize::deserialize(self.deserializer));
-                    Ok(Some(value))
-                } else {
-                    Ok(None)
-                }
-            }
-
-            fn end(&mut self) -> Result<(), Self::Error> {
-                if self.len == 0 {
-                    Ok(())
-                } else {
-                    Err(DecoderError::Serde(de::value::Error::Custom("expected end".into())))
-                }
-            }
-        }
+        visitor.visit_seq(SeqVisitor { deserializer: self, len: len })
+    }
 
+    // A sequence is encoded as a uint32 count followed by its elements
+    fn deserialize_seq<V>(&mut self, mut visitor: V) -> Result<V::Value, DecoderError>
+        where V: de::Visitor
+    {
+        let len = try!(self.parse_u32()) as usize;
         visitor.visit_seq(SeqVisitor { deserializer: self, len: len })
     }
 
@@ -354,7 +362,6 @@ impl<'a> de::Deserializer for BinaryDecoder<'a> {
     impl_error!(deserialize_option(), "option");
     impl_error!(deserialize_unit(), "unit");
     impl_error!(deserialize_map(), "map");
-    impl_error!(deserialize_seq(), "seq");
     impl_error!(deserialize_seq_fixed_size(usize), "seq_fixed_size");
     impl_error!(deserialize_unit_struct(&'static str), "unit_struct");
     impl_error!(deserialize_newtype_struct(&'static str), "newtype_struct");
@@ -143,6 +143,32 @@ impl ser::Serializer for BinaryEncoder {
         self.serialize_tuple_variant_end(st)
     }
 
+    #[inline]
+    fn serialize_seq(&mut self, len: Option<usize>) -> Result<(), EncoderError>
+    {
+        // A sequence is encoded as a uint32 count followed by its elements
+        match len {
+            Some(n) if n <= 0xffffffff => self.serialize_u32(n as u32),
+            Some(n) => Err(EncoderError::DataTooLarge(n)),
+            None => Err(EncoderError::UnsupportedType("seq without length"))
+        }
+    }
+
+    #[inline]
+    fn serialize_seq_elt<T>(&mut self,
+                            _st: &mut (),
+                            elt: T)
+            -> Result<(), EncoderError>
+        where T: ser::Serialize
+    {
+        elt.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_seq_end(&mut self, _st: ()) -> Result<(), EncoderError> {
+        Ok(())
+    }
+
     #[inline]
     fn serialize_tuple(&mut self, _len: usize) -> Result<(), EncoderError>
     {
@@ -285,9 +311,6 @@ impl ser::Serializer for BinaryEncoder {
     impl_error!(serialize_newtype_struct<T>(&'static str, T), "newtype_struct");
     impl_error!(serialize_none(), "none");
     impl_error!(serialize_some<T>(T), "some");
-    impl_error!(serialize_seq(Option<usize>), "seq");
-    impl_error!(serialize_seq_elt<T>(&mut Self::SeqState, T), "seq_elt");
-    impl_error!(serialize_seq_end(Self::SeqState), "seq_end");
     impl_error!(serialize_seq_fixed_size(usize) -> Self::SeqState, "seq_fixed_size");
     impl_error!(serialize_map(Option<usize>) -> Self::MapState, "map");
     impl_error!(serialize_map_key<T>(&mut Self::MapState, T), "map_key");
@@ -224,6 +224,15 @@ mod test {
         ]
     );
 
+    test_codec!(
+        struct_seq,
+        vec![
+            TestStruct { pkt_len: 0x010203, pad_len: 30 },
+            TestStruct { pkt_len: 4, pad_len: 5 }
+        ],
+        &[0, 0, 0, 2, 0, 1, 2, 3, 30, 0, 0, 0, 4, 5]
+    );
+
     test_codec!(
         empty_name_list,
         NameListWrapper {
@@ -1,5 +1,6 @@
+use std::str;
 use std::marker::PhantomData;
-use super::decoder::{Name, de_bytes, de_inner, de_name_list};
+use super::decoder::{DecoderError, Name, de_bytes, de_inner, de_name_list, deserialize};
 use super::encoder::{ser_bytes, ser_inner, ser_name_list};
 
 use serde::{de, ser};
@@ -58,32 +59,117 @@ macro_rules! impl_name_enum {
 
 impl_name_enum!(KexAlgorithm {
     ECDH_SHA2_NISTP256 => "ecdh-sha2-nistp256",
-    CURVE25519_SHA256 => "curve25519-sha256@libssh.org"
+    ECDH_SHA2_NISTP384 => "ecdh-sha2-nistp384",
+    ECDH_SHA2_NISTP521 => "ecdh-sha2-nistp521",
+    DH_GROUP14_SHA256 => "diffie-hellman-group14-sha256",
+    DH_GROUP16_SHA512 => "diffie-hellman-group16-sha512",
+    DH_GROUP18_SHA512 => "diffie-hellman-group18-sha512",
+    DH_GEX_SHA256 => "diffie-hellman-group-exchange-sha256",
+    CURVE25519_SHA256 => "curve25519-sha256@libssh.org",
+    MLKEM768_X25519_SHA256 => "mlkem768x25519-sha256",
+    SNTRUP761_X25519_SHA512 => "sntrup761x25519-sha512@openssh.com",
+    KEX_STRICT_C => "kex-strict-c-v00@openssh.com",
+    KEX_STRICT_S => "kex-strict-s-v00@openssh.com",
+    EXT_INFO_C => "ext-info-c",
+    EXT_INFO_S => "ext-info-s"
 });
 
+impl KexAlgorithm {
+    // Pseudo algorithms only signal protocol extensions and are never negotiated
+    pub fn is_pseudo(&self) -> bool {
+        match *self {
+            KexAlgorithm::KEX_STRICT_C => true,
+            KexAlgorithm::KEX_STRICT_S => true,
+            KexAlgorithm::EXT_INFO_C => true,
+            KexAlgorithm::EXT_INFO_S => true,
+            _ => false
+        }
+    }
+}
+
 impl_name_enum!(ServerHostKeyAlgorithm {
-    SSH_RSA => "ssh-rsa"
+    SSH_RSA => "ssh-rsa",
+    RSA_SHA2_256 => "rsa-sha2-256",
+    RSA_SHA2_512 => "rsa-sha2-512",
+    SSH_ED25519 => "ssh-ed25519",
+    ECDSA_SHA2_NISTP256 => "ecdsa-sha2-nistp256",
+    ECDSA_SHA2_NISTP384 => "ecdsa-sha2-nistp384",
+    ECDSA_SHA2_NISTP521 => "ecdsa-sha2-nistp521"
 });
 
 impl_name_enum!(EncryptionAlgorithm {
     AES256_CBC => "aes256-cbc",
     AES256_CTR => "aes256-ctr",
-    AES256_GCM => "aes256-gcm@openssh.com"
+    AES256_GCM => "aes256-gcm@openssh.com",
+    CHACHA20_POLY1305 => "chacha20-poly1305@openssh.com"
 });
 
+impl EncryptionAlgorithm {
+    pub fn is_aead(&self) -> bool {
+        match *self {
+            EncryptionAlgorithm::AES256_GCM => true,
+            EncryptionAlgorithm::CHACHA20_POLY1305 => true,
+            _ => false
+        }
+    }
+
+    pub fn key_len(&self) -> Option<usize> {
+        match *self {
+            EncryptionAlgorithm::AES256_CBC => Some(32),
+            EncryptionAlgorithm::AES256_CTR => Some(32),
+            EncryptionAlgorithm::AES256_GCM => Some(32),
+            EncryptionAlgorithm::CHACHA20_POLY1305 => Some(64),
+            EncryptionAlgorithm::Unknown(_) => None
+        }
+    }
+
+    pub fn iv_len(&self) -> Option<usize> {
+        match *self {
+            EncryptionAlgorithm::AES256_CBC => Some(16),
+            EncryptionAlgorithm::AES256_CTR => Some(16),
+            EncryptionAlgorithm::AES256_GCM => Some(12),
+            EncryptionAlgorithm::CHACHA20_POLY1305 => Some(0),
+            EncryptionAlgorithm::Unknown(_) => None
+        }
+    }
+}
+
 impl_name_enum!(MacAlgorithm {
-    HMAC_SHA2_256 => "hmac-sha2-256"
+    HMAC_SHA2_256 => "hmac-sha2-256",
+    HMAC_SHA2_256_ETM => "hmac-sha2-256-etm@openssh.com",
+    HMAC_SHA2_512_ETM => "hmac-sha2-512-etm@openssh.com"
 });
 
+impl MacAlgorithm {
+    pub fn key_len(&self) -> Option<usize> {
+        match *self {
+            MacAlgorithm::HMAC_SHA2_256 => Some(32),
+            MacAlgorithm::HMAC_SHA2_256_ETM => Some(32),
+            MacAlgorithm::HMAC_SHA2_512_ETM => Some(64),
+            MacAlgorithm::Unknown(_) => None
+        }
+    }
+
+    pub fn is_etm(&self) -> bool {
+        match *self {
+            MacAlgorithm::HMAC_SHA2_256_ETM => true,
+            MacAlgorithm::HMAC_SHA2_512_ETM => true,
+            _ => false
+        }
+    }
+}
+
 impl_name_enum!(CompressionAlgorithm {
-    NONE => "none"
+    NONE => "none",
+    ZLIB => "zlib",
+    ZLIB_OPENSSH => "zlib@openssh.com"
 });
 
 impl_name_enum!(Language {
     EN => "en"
 });
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AlgorithmNegotiation {
     #[serde(deserialize_with = "de_name_list", serialize_with = "ser_name_list")]
     pub kex_algorithms: Vec<KexAlgorithm>,
@@ -115,6 +201,32 @@ pub struct KexInit {
     pub e: Vec<u8>
 }
 
+// Modulus sizes in bits the client asks for in diffie-hellman-group-exchange (RFC 4419 section 3)
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GexRequest {
+    pub min: u32,
+    pub n: u32,
+    pub max: u32
+}
+
+impl Default for GexRequest {
+    fn default() -> GexRequest {
+        GexRequest {
+            min: 2048,
+            n: 3072,
+            max: 8192
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GexGroup {
+    #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+    pub p: Vec<u8>,
+    #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+    pub g: Vec<u8>
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct KexReply {
     #[serde(deserialize_with = "de_inner", serialize_with = "ser_inner")]
@@ -134,6 +246,31 @@ pub enum ServerKey {
         e: Vec<u8>,
         #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
         n: Vec<u8>
+    },
+    // RFC 8709 section 4
+    #[serde(rename="ssh-ed25519")]
+    SSH_ED25519 {
+        #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+        key: Vec<u8>
+    },
+    // RFC 5656 section 3.1, the curve name and the uncompressed point Q
+    #[serde(rename="ecdsa-sha2-nistp256")]
+    ECDSA_SHA2_NISTP256 {
+        curve: String,
+        #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+        q: Vec<u8>
+    },
+    #[serde(rename="ecdsa-sha2-nistp384")]
+    ECDSA_SHA2_NISTP384 {
+        curve: String,
+        #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+        q: Vec<u8>
+    },
+    #[serde(rename="ecdsa-sha2-nistp521")]
+    ECDSA_SHA2_NISTP521 {
+        curve: String,
+        #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+        q: Vec<u8>
     }
 }
 
@@ -144,5 +281,169 @@ pub enum Signature {
     SSH_RSA {
         #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
         signature: Vec<u8>
+    },
+    // RFC 8332 section 3, signed with an ssh-rsa key
+    #[serde(rename="rsa-sha2-256")]
+    RSA_SHA2_256 {
+        #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+        signature: Vec<u8>
+    },
+    #[serde(rename="rsa-sha2-512")]
+    RSA_SHA2_512 {
+        #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+        signature: Vec<u8>
+    },
+    #[serde(rename="ssh-ed25519")]
+    SSH_ED25519 {
+        #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+        signature: Vec<u8>
+    },
+    #[serde(rename="ecdsa-sha2-nistp256")]
+    ECDSA_SHA2_NISTP256 {
+        #[serde(deserialize_with = "de_inner", serialize_with = "ser_inner")]
+        signature: EcdsaSignature
+    },
+    #[serde(rename="ecdsa-sha2-nistp384")]
+    ECDSA_SHA2_NISTP384 {
+        #[serde(deserialize_with = "de_inner", serialize_with = "ser_inner")]
+        signature: EcdsaSignature
+    },
+    #[serde(rename="ecdsa-sha2-nistp521")]
+    ECDSA_SHA2_NISTP521 {
+        #[serde(deserialize_with = "de_inner", serialize_with = "ser_inner")]
+        signature: EcdsaSignature
+    }
+}
+
+// RFC 5656 section 3.1.2, r and s as mpints
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EcdsaSignature {
+    #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+    pub r: Vec<u8>,
+    #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+    pub s: Vec<u8>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExtensionPair {
+    pub name: String,
+    #[serde(deserialize_with = "de_bytes", serialize_with = "ser_bytes")]
+    pub value: Vec<u8>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExtInfo {
+    pub extensions: Vec<ExtensionPair>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DelayCompression {
+    #[serde(deserialize_with = "de_name_list", serialize_with = "ser_name_list")]
+    compression_algorithms_client_to_server: Vec<CompressionAlgorithm>,
+    #[serde(deserialize_with = "de_name_list", serialize_with = "ser_name_list")]
+    compression_algorithms_server_to_client: Vec<CompressionAlgorithm>
+}
+
+// RFC 8308 section 3
+#[derive(Clone, Debug, PartialEq)]
+pub enum Extension {
+    ServerSigAlgs(Vec<ServerHostKeyAlgorithm>),
+    DelayCompression(Vec<CompressionAlgorithm>, Vec<CompressionAlgorithm>),
+    NoFlowControl(String),
+    Elevation(String),
+    Unknown(Vec<u8>)
+}
+
+fn name_list<T: Name>(value: &[u8]) -> Result<Vec<T>, DecoderError> {
+    let s = try!(str::from_utf8(value));
+    if s.len() == 0 {
+        Ok(Vec::new())
+    } else {
+        Ok(s.split(',').map(|name| name.into()).collect())
+    }
+}
+
+impl Extension {
+    pub fn parse(name: &str, value: &[u8]) -> Result<Extension, DecoderError> {
+        match name {
+            "server-sig-algs" =>
+                Ok(Extension::ServerSigAlgs(try!(name_list(value)))),
+            "delay-compression" => {
+                let comp = try!(deserialize::<DelayCompression>(value));
+                Ok(Extension::DelayCompression(comp.compression_algorithms_client_to_server,
+                                               comp.compression_algorithms_server_to_client))
+            },
+            "no-flow-control" =>
+                Ok(Extension::NoFlowControl(try!(str::from_utf8(value)).into())),
+            "elevation" =>
+                Ok(Extension::Elevation(try!(str::from_utf8(value)).into())),
+            _ => Ok(Extension::Unknown(value.into()))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Disconnect {
+    pub reason_code: u32,
+    pub description: String,
+    pub language: String
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DebugMessage {
+    pub always_display: bool,
+    pub message: String,
+    pub language: String
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Unimplemented {
+    pub seq: u32
+}
+
+macro_rules! impl_code_enum {
+    ($ty:ident {$($variant:ident => $code:expr),*}) => {
+        #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+        pub enum $ty {
+            $($variant),*,
+            Unknown(u32)
+        }
+
+        impl $ty {
+            pub fn code(&self) -> u32 {
+                match *self {
+                    $($ty::$variant => $code),*,
+                    $ty::Unknown(code) => code
+                }
+            }
+        }
+
+        impl From<u32> for $ty {
+            fn from(code: u32) -> $ty {
+                match code {
+                    $($code => $ty::$variant),*,
+                    _ => $ty::Unknown(code)
+                }
+            }
+        }
     }
 }
+
+// RFC 4253 section 11.1
+impl_code_enum!(DisconnectReason {
+    HostNotAllowedToConnect => 1,
+    ProtocolError => 2,
+    KeyExchangeFailed => 3,
+    Reserved => 4,
+    MacError => 5,
+    CompressionError => 6,
+    ServiceNotAvailable => 7,
+    ProtocolVersionNotSupported => 8,
+    HostKeyNotVerifiable => 9,
+    ConnectionLost => 10,
+    ByApplication => 11,
+    TooManyConnections => 12,
+    AuthCancelledByUser => 13,
+    NoMoreAuthMethodsAvailable => 14,
+    IllegalUserName => 15
+});
@@ -0,0 +1,511 @@
+use cipher::{PacketOpener, PacketSealer};
+use handshake::{ClientKeyExchange, HandshakeError, SecureContext, ServerKeyExchange};
+use transport::{AsyncPacketState, NewKeys, PacketWriteRequest, SequenceNumbers};
+
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+
+use ::SSH_MSG_EXT_INFO;
+
+#[derive(Clone, Debug)]
+pub struct RekeyLimits {
+    pub bytes: u64,
+    pub packets: u64,
+    pub interval: Duration
+}
+
+impl Default for RekeyLimits {
+    // RFC 4253 section 9 recommends re-keying after each gigabyte or each hour
+    fn default() -> RekeyLimits {
+        RekeyLimits {
+            bytes: 1 << 30,
+            packets: 1 << 31,
+            interval: Duration::from_secs(3600)
+        }
+    }
+}
+
+// A key epoch must not see a sequence number again, so re-keying starts well before one wraps around
+const MAX_EPOCH_PACKETS: u32 = 1 << 31;
+
+// The client or the server side of a key exchange, whichever side the session is on
+type KeyExchange = AsyncPacketState<Item=SecureContext, Error=HandshakeError>;
+
+#[derive(Clone, Copy)]
+enum Source {
+    App,
+    Kex
+}
+
+// Drives an application state over an established session, running a key exchange whenever
+// a limit is reached, `rekey()` is called or the peer sends SSH_MSG_KEXINIT. A session over a server
+// context re-keys as the server.
+pub struct Session<T> {
+    app: T,
+    ctx: SecureContext,
+    kex: Option<Box<KeyExchange>>,
+    limits: RekeyLimits,
+    rekey_requested: bool,
+    bytes: u64,
+    packets: u64,
+    last_kex: Instant,
+    // Fires when the interval runs out, so an idle session re-keys as well
+    timer: Option<Timeout>,
+    handle: Handle,
+    seq: SequenceNumbers,
+    // Sequence numbers when the current keys took effect, taken on the first update after a key exchange
+    epoch_seq: Option<SequenceNumbers>,
+    // Origin and payload length of the packet being written
+    writing: Option<(Source, usize)>
+}
+
+fn is_kex_msg(msg: &[u8]) -> bool {
+    // Message numbers 20 to 49 belong to the key exchange (RFC 4250 section 4.1.2)
+    msg.len() > 0 && msg[0] >= 20 && msg[0] <= 49
+}
+
+fn seq_exhausted(epoch: SequenceNumbers, seq: SequenceNumbers) -> bool {
+    seq.read.wrapping_sub(epoch.read) >= MAX_EPOCH_PACKETS || seq.write.wrapping_sub(epoch.write) >= MAX_EPOCH_PACKETS
+}
+
+impl <T> Session<T> {
+    pub fn new(ctx: SecureContext, app: T, handle: &Handle) -> Session<T> {
+        Session::with_limits(ctx, app, RekeyLimits::default(), handle)
+    }
+
+    pub fn with_limits(ctx: SecureContext, app: T, limits: RekeyLimits, handle: &Handle) -> Session<T> {
+        Session {
+            app: app,
+            ctx: ctx,
+            kex: None,
+            limits: limits,
+            rekey_requested: false,
+            bytes: 0,
+            packets: 0,
+            last_kex: Instant::now(),
+            timer: None,
+            handle: handle.clone(),
+            seq: SequenceNumbers::default(),
+            epoch_seq: None,
+            writing: None
+        }
+    }
+
+    pub fn context(&self) -> &SecureContext {
+        &self.ctx
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.app
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.app
+    }
+
+    pub fn is_rekeying(&self) -> bool {
+        self.kex.is_some()
+    }
+
+    // The key exchange starts on the next poll, unless one is already running
+    pub fn rekey(&mut self) {
+        self.rekey_requested = true;
+    }
+
+    fn limits_exceeded(&self) -> bool {
+        self.bytes >= self.limits.bytes
+            || self.packets >= self.limits.packets
+            || self.last_kex.elapsed() >= self.limits.interval
+            || self.epoch_seq.map_or(false, |epoch| seq_exhausted(epoch, self.seq))
+    }
+
+    fn start_kex(&mut self) -> Result<(), HandshakeError> {
+        let kex: Box<KeyExchange> = if self.ctx.is_server() {
+            Box::new(try!(ServerKeyExchange::rekey(&self.ctx)))
+        } else {
+            Box::new(try!(ClientKeyExchange::rekey(&self.ctx)))
+        };
+        self.kex = Some(kex);
+        self.rekey_requested = false;
+        self.timer = None;
+        Ok(())
+    }
+
+    // Registers the task to be woken up when the interval of the current keys runs out
+    fn poll_timer(&mut self) -> Result<(), HandshakeError> {
+        if self.timer.is_none() {
+            self.timer = Some(try!(Timeout::new_at(self.last_kex + self.limits.interval, &self.handle)));
+        }
+        if let Some(ref mut timer) = self.timer {
+            try!(timer.poll());
+        }
+        Ok(())
+    }
+
+    fn count(&mut self, len: usize) {
+        self.bytes = self.bytes.saturating_add(len as u64);
+        self.packets = self.packets.saturating_add(1);
+    }
+}
+
+impl <T> Future for Session<T>
+    where T: AsyncPacketState, T::Error: From<HandshakeError>
+{
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<T::Item, T::Error> {
+        let new_ctx = match self.kex {
+            Some(ref mut kex) => match try!(kex.poll()) {
+                Async::Ready(ctx) => Some(ctx),
+                Async::NotReady => return Ok(Async::NotReady)
+            },
+            None => None
+        };
+
+        if let Some(ctx) = new_ctx {
+            self.ctx = ctx;
+            self.kex = None;
+            self.bytes = 0;
+            self.packets = 0;
+            self.last_kex = Instant::now();
+            self.epoch_seq = None;
+        }
+
+        if self.kex.is_none() {
+            try!(self.poll_timer());
+        }
+
+        if self.rekey_requested || self.limits_exceeded() {
+            try!(self.start_kex());
+            return Ok(Async::NotReady);
+        }
+
+        self.app.poll()
+    }
+}
+
+impl <T> AsyncPacketState for Session<T>
+    where T: AsyncPacketState, T::Error: From<HandshakeError>
+{
+    fn wants_read(&self) -> bool {
+        // Each key exchange step reads only what it expects, so the next step sees the packets after it
+        match self.kex {
+            Some(ref kex) => kex.wants_read(),
+            None => self.app.wants_read()
+        }
+    }
+
+    fn on_read(&mut self, msg: &[u8]) -> Result<(), T::Error> {
+        self.count(msg.len());
+
+        if msg.first() == Some(&SSH_MSG_EXT_INFO) {
+            return Ok(try!(self.ctx.set_ext_info(msg)));
+        }
+
+        if !is_kex_msg(msg) {
+            return self.app.on_read(msg);
+        }
+
+        if self.kex.is_none() {
+            // The peer started a key exchange
+            try!(self.start_kex());
+        }
+
+        match self.kex {
+            Some(ref mut kex) => Ok(try!(kex.on_read(msg))),
+            None => Ok(())
+        }
+    }
+
+    fn write_packet(&self) -> Option<PacketWriteRequest> {
+        // Application packets are held back until the key exchange finishes
+        match self.kex {
+            Some(ref kex) => kex.write_packet(),
+            None => self.app.write_packet()
+        }
+    }
+
+    fn on_write(&mut self, req: &PacketWriteRequest) {
+        // The key exchange may start before the packet is flushed, so its origin is remembered
+        let source = match self.kex {
+            Some(ref mut kex) => {
+                kex.on_write(req);
+                Source::Kex
+            },
+            None => {
+                self.app.on_write(req);
+                Source::App
+            }
+        };
+        self.writing = Some((source, req.payload.len()));
+    }
+
+    fn on_flush(&mut self) -> Result<(), T::Error> {
+        match self.writing.take() {
+            Some((source, len)) => {
+                self.count(len);
+                match (source, &mut self.kex) {
+                    (Source::Kex, &mut Some(ref mut kex)) => Ok(try!(kex.on_flush())),
+                    (Source::Kex, &mut None) => Ok(()),
+                    (Source::App, _) => self.app.on_flush()
+                }
+            },
+            None => Ok(())
+        }
+    }
+
+    fn take_sealer(&mut self) -> Option<NewKeys<Box<PacketSealer>>> {
+        self.kex.as_mut().and_then(|kex| kex.take_sealer())
+    }
+
+    fn take_opener(&mut self) -> Option<NewKeys<Box<PacketOpener>>> {
+        self.kex.as_mut().and_then(|kex| kex.take_opener())
+    }
+
+    fn set_sequence_numbers(&mut self, seq: SequenceNumbers) {
+        self.seq = seq;
+        if self.kex.is_none() && self.epoch_seq.is_none() {
+            self.epoch_seq = Some(seq);
+        }
+        self.app.set_sequence_numbers(seq);
+    }
+
+    fn is_known(&self, msg_type: u8) -> bool {
+        msg_type == SSH_MSG_EXT_INFO || is_kex_msg(&[msg_type]) || self.app.is_known(msg_type)
+    }
+
+    fn on_transport_msg(&mut self, msg_type: u8) -> Result<(), T::Error> {
+        self.app.on_transport_msg(msg_type)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_kex_msg, seq_exhausted, RekeyLimits, Session};
+    use handshake::{HandshakeError, SecureContext, ServerKeyExchange};
+    use handshake::test::{connect, run_pair};
+    use transport::{AsyncPacketState, PacketWriteRequest, SequenceNumbers};
+
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    use futures::{future, Async, Future, Poll};
+    use tokio_core::reactor::{Core, Handle, Timeout};
+
+    use ::SSH_MSG_KEXINIT;
+
+    // SSH_MSG_CHANNEL_DATA, as an application message
+    const APP_MSG: u8 = 94;
+
+    // Sends its queued packets and ignores whatever it reads
+    struct App {
+        outgoing: VecDeque<Vec<u8>>
+    }
+
+    impl Future for App {
+        type Item = ();
+        type Error = HandshakeError;
+
+        fn poll(&mut self) -> Poll<(), HandshakeError> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl AsyncPacketState for App {
+        fn wants_read(&self) -> bool {
+            true
+        }
+
+        fn write_packet(&self) -> Option<PacketWriteRequest> {
+            self.outgoing.front().map(|payload| PacketWriteRequest {
+                payload: payload.clone(),
+                flush: true
+            })
+        }
+
+        fn on_flush(&mut self) -> Result<(), HandshakeError> {
+            self.outgoing.pop_front();
+            Ok(())
+        }
+    }
+
+    fn limits(bytes: u64, packets: u64) -> RekeyLimits {
+        RekeyLimits {
+            bytes: bytes,
+            packets: packets,
+            interval: Duration::from_secs(3600)
+        }
+    }
+
+    // A client session with `count` application packets of 40 bytes to send, and the server's context
+    fn session(limits: RekeyLimits, count: usize, handle: &Handle) -> (Session<App>, SecureContext) {
+        let (client_ctx, server_ctx) = connect();
+        let app = App {
+            outgoing: (0 .. count).map(|_| vec![APP_MSG; 40]).collect()
+        };
+        (Session::with_limits(client_ctx, app, limits, handle), server_ctx)
+    }
+
+    // The session registers a timeout when polled, so it has to run inside a task
+    fn run<F: FnOnce(&Handle)>(f: F) {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        core.run(future::lazy(|| {
+            f(&handle);
+            Ok::<(), ()>(())
+        })).unwrap();
+    }
+
+    // Writes packets until the session holds back, polling it in between like the transport does
+    fn drain(session: &mut Session<App>) -> Vec<Vec<u8>> {
+        let mut written = Vec::new();
+        loop {
+            assert!(session.poll().unwrap().is_not_ready());
+            match session.write_packet() {
+                Some(req) => {
+                    session.on_write(&req);
+                    session.on_flush().unwrap();
+                    written.push(req.payload);
+                },
+                None => return written
+            }
+        }
+    }
+
+    fn msg_types(packets: &[Vec<u8>]) -> Vec<u8> {
+        packets.iter().map(|payload| payload[0]).collect()
+    }
+
+    #[test]
+    fn rekey_at_byte_limit() {
+        run(|handle| {
+            let (mut session, _) = session(limits(100, 1000), 4, handle);
+            let written = drain(&mut session);
+            assert_eq!(vec![APP_MSG, APP_MSG, APP_MSG, SSH_MSG_KEXINIT],
+                       msg_types(&written));
+            assert!(session.is_rekeying());
+        });
+    }
+
+    #[test]
+    fn rekey_at_packet_limit() {
+        run(|handle| {
+            let (mut session, _) = session(limits(1 << 30, 2), 4, handle);
+            let written = drain(&mut session);
+            assert_eq!(vec![APP_MSG, APP_MSG, SSH_MSG_KEXINIT], msg_types(&written));
+            assert!(session.is_rekeying());
+        });
+    }
+
+    #[test]
+    fn app_packets_held_back_during_rekey() {
+        run(|handle| {
+            let (mut session, server_ctx) = session(limits(1 << 30, 1000), 1, handle);
+            let old_key = session.context().keys().enc_key_client_to_server.clone();
+            session.rekey();
+            let written = drain(&mut session);
+            assert_eq!(vec![SSH_MSG_KEXINIT], msg_types(&written));
+            assert_eq!(1, session.get_ref().outgoing.len());
+
+            let mut server = ServerKeyExchange::rekey(&server_ctx).unwrap();
+            server.on_read(&written[0]).unwrap();
+            let new_ctx = match run_pair(&mut session, &mut server).unwrap() {
+                (None, Some(ctx)) => ctx,
+                _ => panic!("key exchange did not finish")
+            };
+
+            // The held back packet goes out once the new keys are in use
+            assert!(!session.is_rekeying());
+            assert!(session.get_ref().outgoing.is_empty());
+            assert_eq!(server_ctx.session_id(), session.context().session_id());
+            assert_eq!(new_ctx.keys().enc_key_client_to_server, session.context().keys().enc_key_client_to_server);
+            assert!(old_key != session.context().keys().enc_key_client_to_server);
+        });
+    }
+
+    #[test]
+    fn peer_kexinit_starts_rekey() {
+        run(|handle| {
+            let (mut session, server_ctx) = session(limits(1 << 30, 1000), 0, handle);
+            let mut server = ServerKeyExchange::rekey(&server_ctx).unwrap();
+            let new_ctx = match run_pair(&mut session, &mut server).unwrap() {
+                (None, Some(ctx)) => ctx,
+                _ => panic!("key exchange did not finish")
+            };
+
+            assert!(!session.is_rekeying());
+            assert_eq!(new_ctx.keys().enc_key_server_to_client, session.context().keys().enc_key_server_to_client);
+        });
+    }
+
+    #[test]
+    fn server_session_rekeys() {
+        run(|handle| {
+            let (mut client, server_ctx) = session(limits(1 << 30, 1000), 0, handle);
+            let old_key = client.context().keys().enc_key_server_to_client.clone();
+            let mut server = Session::new(server_ctx, App { outgoing: VecDeque::new() }, handle);
+            client.rekey();
+            let written = drain(&mut client);
+            assert_eq!(vec![SSH_MSG_KEXINIT], msg_types(&written));
+
+            // The client's KEXINIT starts a key exchange on the server side
+            server.on_read(&written[0]).unwrap();
+            assert!(server.is_rekeying());
+            run_pair(&mut client, &mut server).unwrap();
+
+            assert!(!client.is_rekeying());
+            assert!(!server.is_rekeying());
+            assert!(server.context().is_server());
+            assert_eq!(client.context().session_id(), server.context().session_id());
+            assert_eq!(client.context().keys().enc_key_server_to_client,
+                       server.context().keys().enc_key_server_to_client);
+            assert!(old_key != server.context().keys().enc_key_server_to_client);
+        });
+    }
+
+    #[test]
+    fn idle_session_rekeys_after_interval() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut limits = limits(1 << 30, 1000);
+        limits.interval = Duration::from_millis(50);
+        let (mut session, _) = session(limits, 0, &handle);
+
+        // Nothing but the timer wakes the task up
+        let rekeying = future::poll_fn(|| -> Poll<(), HandshakeError> {
+            try!(session.poll());
+            Ok(if session.is_rekeying() { Async::Ready(()) } else { Async::NotReady })
+        });
+        let deadline = Timeout::new(Duration::from_secs(10), &handle).unwrap()
+            .map_err(HandshakeError::from)
+            .and_then(|_| Err(HandshakeError::Panic("no key exchange after the interval".to_string())));
+        // The deadline is polled first, so it wins if only it wakes the task up
+        if let Err((e, _)) = core.run(deadline.select(rekeying)) {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn kex_message_range() {
+        assert!(!is_kex_msg(&[]));
+        assert!(!is_kex_msg(&[2]));
+        assert!(is_kex_msg(&[20, 0]));
+        assert!(is_kex_msg(&[31]));
+        assert!(is_kex_msg(&[49]));
+        assert!(!is_kex_msg(&[50]));
+        assert!(!is_kex_msg(&[94]));
+    }
+
+    #[test]
+    fn seq_exhausted_across_wrap() {
+        let seq = |read, write| SequenceNumbers { read: read, write: write };
+        assert!(!seq_exhausted(seq(0, 0), seq(1000, 1000)));
+        assert!(seq_exhausted(seq(0, 0), seq(1 << 31, 5)));
+        assert!(seq_exhausted(seq(10, 0), seq(10, 1 << 31)));
+        assert!(!seq_exhausted(seq(0xffff_fff0, 3), seq(0x10, 3)));
+        assert!(seq_exhausted(seq(0xffff_fff0, 3), seq(0x7fff_fff0, 3)));
+    }
+}
@@ -0,0 +1,530 @@
+// Streamlined NTRU Prime sntrup761, the post-quantum half of sntrup761x25519-sha512@openssh.com.
+// Follows the reference implementation shipped with OpenSSH.
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha512;
+use rand::Rng;
+
+const P: usize = 761;
+const Q: i32 = 4591;
+const W: usize = 286;
+const Q12: i32 = (Q - 1) / 2;
+
+const SMALL_BYTES: usize = (P + 3) / 4;
+const ROUNDED_BYTES: usize = 1007;
+const HASH_BYTES: usize = 32;
+pub const PUBLIC_KEY_LEN: usize = 1158;
+pub const CIPHERTEXT_LEN: usize = ROUNDED_BYTES + HASH_BYTES;
+pub const SHARED_SECRET_LEN: usize = HASH_BYTES;
+
+// Elements of Z/q, centered around zero
+type Fq = i16;
+// Elements of Z/3 in {-1, 0, 1}
+type Small = i8;
+
+fn fq_freeze(x: i32) -> Fq {
+    let r = ((x % Q) + Q) % Q;
+    (if r > Q12 { r - Q } else { r }) as Fq
+}
+
+fn f3_freeze(x: i32) -> Small {
+    let r = ((x % 3) + 3) % 3;
+    (if r == 2 { -1 } else { r }) as Small
+}
+
+fn fq_recip(a: Fq) -> Fq {
+    // a^(q-2)
+    let mut out: i32 = 1;
+    let mut base = a as i32;
+    let mut e = Q - 2;
+    while e > 0 {
+        if e & 1 == 1 {
+            out = fq_freeze(out * base) as i32;
+        }
+        base = fq_freeze(base * base) as i32;
+        e >>= 1;
+    }
+    out as Fq
+}
+
+// 0 if x is zero, -1 otherwise
+fn nonzero_mask(x: i32) -> i32 {
+    -((x != 0) as i32)
+}
+
+// -1 if x is negative, 0 otherwise
+fn negative_mask(x: i32) -> i32 {
+    x >> 31
+}
+
+// Multiplication in R = Z[x]/(x^p - x - 1), with coefficients reduced by `freeze`
+fn mult<F: Fn(i32) -> i32>(f: &[i32], g: &[i32], freeze: F) -> Vec<i32> {
+    let mut fg = vec![0i32; 2 * P - 1];
+    for i in 0..P {
+        for j in 0..P {
+            fg[i + j] = freeze(fg[i + j] + f[i] * g[j]);
+        }
+    }
+    // x^p = x + 1
+    for i in (P .. 2 * P - 1).rev() {
+        fg[i - P] = freeze(fg[i - P] + fg[i]);
+        fg[i - P + 1] = freeze(fg[i - P + 1] + fg[i]);
+    }
+    fg.truncate(P);
+    fg
+}
+
+fn rq_mult_small(f: &[Fq], g: &[Small]) -> Vec<Fq> {
+    let f: Vec<i32> = f.iter().map(|&x| x as i32).collect();
+    let g: Vec<i32> = g.iter().map(|&x| x as i32).collect();
+    mult(&f, &g, |x| fq_freeze(x) as i32).into_iter().map(|x| x as Fq).collect()
+}
+
+fn r3_mult(f: &[Small], g: &[Small]) -> Vec<Small> {
+    let f: Vec<i32> = f.iter().map(|&x| x as i32).collect();
+    let g: Vec<i32> = g.iter().map(|&x| x as i32).collect();
+    mult(&f, &g, |x| f3_freeze(x) as i32).into_iter().map(|x| x as Small).collect()
+}
+
+// Constant time inversion in R/3 by divsteps. Returns None if `g` is not invertible.
+fn r3_recip(g_in: &[Small]) -> Option<Vec<Small>> {
+    let mut f = vec![0i32; P + 1];
+    let mut g = vec![0i32; P + 1];
+    let mut v = vec![0i32; P + 1];
+    let mut r = vec![0i32; P + 1];
+    r[0] = 1;
+    f[0] = 1;
+    f[P - 1] = -1;
+    f[P] = -1;
+    for i in 0..P {
+        g[P - 1 - i] = g_in[i] as i32;
+    }
+
+    let mut delta: i32 = 1;
+    for _ in 0 .. 2 * P - 1 {
+        for i in (1 .. P + 1).rev() {
+            v[i] = v[i - 1];
+        }
+        v[0] = 0;
+
+        let sign = -g[0] * f[0];
+        let swap = negative_mask(-delta) & nonzero_mask(g[0]);
+        delta ^= swap & (delta ^ -delta);
+        delta += 1;
+
+        for i in 0 .. P + 1 {
+            let t = swap & (f[i] ^ g[i]);
+            f[i] ^= t;
+            g[i] ^= t;
+            let t = swap & (v[i] ^ r[i]);
+            v[i] ^= t;
+            r[i] ^= t;
+        }
+
+        for i in 0 .. P + 1 {
+            g[i] = f3_freeze(g[i] + sign * f[i]) as i32;
+            r[i] = f3_freeze(r[i] + sign * v[i]) as i32;
+        }
+
+        for i in 0..P {
+            g[i] = g[i + 1];
+        }
+        g[P] = 0;
+    }
+
+    if delta != 0 {
+        return None;
+    }
+    let sign = f[0];
+    Some((0..P).map(|i| (sign * v[P - 1 - i]) as Small).collect())
+}
+
+// Constant time inversion of 3 g in R/q. Always succeeds for a short g.
+fn rq_recip3(g_in: &[Small]) -> Vec<Fq> {
+    let mut f = vec![0i32; P + 1];
+    let mut g = vec![0i32; P + 1];
+    let mut v = vec![0i32; P + 1];
+    let mut r = vec![0i32; P + 1];
+    r[0] = fq_recip(3) as i32;
+    f[0] = 1;
+    f[P - 1] = -1;
+    f[P] = -1;
+    for i in 0..P {
+        g[P - 1 - i] = g_in[i] as i32;
+    }
+
+    let mut delta: i32 = 1;
+    for _ in 0 .. 2 * P - 1 {
+        for i in (1 .. P + 1).rev() {
+            v[i] = v[i - 1];
+        }
+        v[0] = 0;
+
+        let swap = negative_mask(-delta) & nonzero_mask(g[0]);
+        delta ^= swap & (delta ^ -delta);
+        delta += 1;
+
+        for i in 0 .. P + 1 {
+            let t = swap & (f[i] ^ g[i]);
+            f[i] ^= t;
+            g[i] ^= t;
+            let t = swap & (v[i] ^ r[i]);
+            v[i] ^= t;
+            r[i] ^= t;
+        }
+
+        let (f0, g0) = (f[0], g[0]);
+        for i in 0 .. P + 1 {
+            g[i] = fq_freeze(f0 * g[i] - g0 * f[i]) as i32;
+            r[i] = fq_freeze(f0 * r[i] - g0 * v[i]) as i32;
+        }
+
+        for i in 0..P {
+            g[i] = g[i + 1];
+        }
+        g[P] = 0;
+    }
+
+    let scale = fq_recip(f[0] as Fq) as i32;
+    (0..P).map(|i| fq_freeze(scale * v[P - 1 - i])).collect()
+}
+
+fn urandom32(rng: &mut Rng) -> u32 {
+    let mut buf = [0u8; 4];
+    rng.fill_bytes(&mut buf);
+    buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+fn small_random(rng: &mut Rng) -> Vec<Small> {
+    (0..P).map(|_| ((((urandom32(rng) & 0x3fffffff) as u64 * 3) >> 30) as i32 - 1) as Small).collect()
+}
+
+// A random polynomial with exactly w nonzero coefficients, by sorting tagged random values
+fn short_random(rng: &mut Rng) -> Vec<Small> {
+    let mut l: Vec<u32> = (0..P).map(|i| {
+        let x = urandom32(rng);
+        if i < W { x & !1 } else { (x & !3) | 1 }
+    }).collect();
+    l.sort();
+    l.iter().map(|&x| (x & 3) as Small - 1).collect()
+}
+
+// Mixed radix encoding of R[i] in 0..M[i], as in the reference implementation
+fn encode(out: &mut Vec<u8>, r: &[u16], m: &[u16]) {
+    if r.len() == 1 {
+        let (mut r, mut m) = (r[0] as u32, m[0] as u32);
+        while m > 1 {
+            out.push(r as u8);
+            r >>= 8;
+            m = (m + 255) >> 8;
+        }
+        return;
+    }
+
+    let mut r2 = Vec::with_capacity((r.len() + 1) / 2);
+    let mut m2 = Vec::with_capacity((r.len() + 1) / 2);
+    let mut i = 0;
+    while i + 1 < r.len() {
+        let m0 = m[i] as u32;
+        let mut x = r[i] as u32 + r[i + 1] as u32 * m0;
+        let mut mm = m[i + 1] as u32 * m0;
+        while mm >= 16384 {
+            out.push(x as u8);
+            x >>= 8;
+            mm = (mm + 255) >> 8;
+        }
+        r2.push(x as u16);
+        m2.push(mm as u16);
+        i += 2;
+    }
+    if i < r.len() {
+        r2.push(r[i]);
+        m2.push(m[i]);
+    }
+    encode(out, &r2, &m2);
+}
+
+fn decode(s: &[u8], m: &[u16]) -> Vec<u16> {
+    if m.len() == 1 {
+        return vec![if m[0] == 1 {
+            0
+        } else if m[0] <= 256 {
+            (s[0] as u32 % m[0] as u32) as u16
+        } else {
+            ((s[0] as u32 + ((s[1] as u32) << 8)) % m[0] as u32) as u16
+        }];
+    }
+
+    let len = m.len();
+    let mut m2 = Vec::with_capacity((len + 1) / 2);
+    let mut bottom = Vec::with_capacity(len / 2);
+    let mut s = s;
+    let mut i = 0;
+    while i + 1 < len {
+        let mm = m[i] as u32 * m[i + 1] as u32;
+        if mm > 256 * 16383 {
+            bottom.push((s[0] as u32 + 256 * s[1] as u32, 256 * 256));
+            s = &s[2..];
+            m2.push(((((mm + 255) >> 8) + 255) >> 8) as u16);
+        } else if mm >= 16384 {
+            bottom.push((s[0] as u32, 256));
+            s = &s[1..];
+            m2.push(((mm + 255) >> 8) as u16);
+        } else {
+            bottom.push((0, 1));
+            m2.push(mm as u16);
+        }
+        i += 2;
+    }
+    if i < len {
+        m2.push(m[i]);
+    }
+
+    let r2 = decode(s, &m2);
+    let mut out = Vec::with_capacity(len);
+    for (j, &(r, t)) in bottom.iter().enumerate() {
+        let x = r + t * r2[j] as u32;
+        out.push((x % m[2 * j] as u32) as u16);
+        // The reduction only matters for invalid input
+        out.push((x / m[2 * j] as u32 % m[2 * j + 1] as u32) as u16);
+    }
+    if i < len {
+        out.push(r2[i / 2]);
+    }
+    out
+}
+
+fn rq_encode(r: &[Fq]) -> Vec<u8> {
+    let vals: Vec<u16> = r.iter().map(|&x| (x as i32 + Q12) as u16).collect();
+    let mut out = Vec::with_capacity(PUBLIC_KEY_LEN);
+    encode(&mut out, &vals, &[Q as u16; P]);
+    out
+}
+
+fn rq_decode(s: &[u8]) -> Vec<Fq> {
+    decode(s, &[Q as u16; P]).into_iter().map(|x| (x as i32 - Q12) as Fq).collect()
+}
+
+fn rounded_encode(r: &[Fq]) -> Vec<u8> {
+    let vals: Vec<u16> = r.iter().map(|&x| (((x as i32 + Q12) * 10923) >> 15) as u16).collect();
+    let mut out = Vec::with_capacity(ROUNDED_BYTES);
+    encode(&mut out, &vals, &[((Q + 2) / 3) as u16; P]);
+    out
+}
+
+fn rounded_decode(s: &[u8]) -> Vec<Fq> {
+    decode(s, &[((Q + 2) / 3) as u16; P]).into_iter().map(|x| fq_freeze(x as i32 * 3 - Q12)).collect()
+}
+
+fn small_encode(f: &[Small]) -> Vec<u8> {
+    let mut out: Vec<u8> = f.chunks(4).map(|c| {
+        c.iter().enumerate().fold(0u8, |acc, (i, &x)| acc | (((x + 1) as u8) << (2 * i)))
+    }).collect();
+    out.truncate(SMALL_BYTES);
+    out
+}
+
+// The first 32 bytes of SHA-512(b || input)
+fn hash_prefix(b: u8, parts: &[&[u8]]) -> [u8; HASH_BYTES] {
+    let mut h = Sha512::new();
+    h.input(&[b]);
+    for part in parts {
+        h.input(part);
+    }
+    let mut digest = [0u8; 64];
+    h.result(&mut digest);
+    let mut out = [0u8; HASH_BYTES];
+    out.copy_from_slice(&digest[..HASH_BYTES]);
+    out
+}
+
+// Round(h r), encoded, followed by the hash confirming r
+fn hide(r: &[Small], h: &[Fq], cache: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let r_enc = small_encode(r);
+    let hr = rq_mult_small(h, r);
+    let rounded: Vec<Fq> = hr.iter().map(|&x| x - f3_freeze(x as i32) as Fq).collect();
+
+    let mut c = rounded_encode(&rounded);
+    let confirm = hash_prefix(2, &[&hash_prefix(3, &[&r_enc]), cache]);
+    c.extend_from_slice(&confirm);
+    (c, r_enc)
+}
+
+fn hash_session(b: u8, r_enc: &[u8], c: &[u8]) -> [u8; SHARED_SECRET_LEN] {
+    hash_prefix(b, &[&hash_prefix(3, &[r_enc]), c])
+}
+
+pub struct PrivateKey {
+    f: Vec<Small>,
+    ginv: Vec<Small>,
+    pk: Vec<u8>,
+    // Used in place of r when the ciphertext is rejected
+    rho: Vec<u8>,
+    cache: [u8; HASH_BYTES]
+}
+
+impl PrivateKey {
+    pub fn generate(rng: &mut Rng) -> PrivateKey {
+        let mut g = small_random(rng);
+        let mut ginv = r3_recip(&g);
+        while ginv.is_none() {
+            g = small_random(rng);
+            ginv = r3_recip(&g);
+        }
+        let f = short_random(rng);
+        // h = g / (3 f)
+        let h = rq_mult_small(&rq_recip3(&f), &g);
+        let pk = rq_encode(&h);
+
+        let mut rho = vec![0u8; SMALL_BYTES];
+        rng.fill_bytes(&mut rho);
+        let cache = hash_prefix(4, &[&pk]);
+        PrivateKey {
+            f: f,
+            ginv: ginv.unwrap(),
+            pk: pk,
+            rho: rho,
+            cache: cache
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.pk
+    }
+
+    // A wrong ciphertext of the right length yields a pseudorandom secret instead of an error
+    pub fn decapsulate(&self, c: &[u8]) -> Result<[u8; SHARED_SECRET_LEN], ()> {
+        if c.len() != CIPHERTEXT_LEN {
+            return Err(());
+        }
+
+        // e = 3 c f mod 3, then r = e / g
+        let cf = rq_mult_small(&rounded_decode(&c[..ROUNDED_BYTES]), &self.f);
+        let e: Vec<Small> = cf.iter().map(|&x| f3_freeze(fq_freeze(3 * x as i32) as i32)).collect();
+        let ev = r3_mult(&e, &self.ginv);
+
+        // r must have weight w, otherwise it is replaced by a fixed short polynomial
+        let weight = ev.iter().filter(|&&x| x != 0).count();
+        let mask = nonzero_mask(weight as i32 - W as i32) as i8;
+        let r: Vec<Small> = ev.iter().enumerate().map(|(i, &x)| {
+            if i < W { ((x ^ 1) & !mask) ^ 1 } else { x & !mask }
+        }).collect();
+
+        let h = rq_decode(&self.pk);
+        let (c2, mut r_enc) = hide(&r, &h, &self.cache);
+        let diff = c.iter().zip(c2.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        let mask = ((diff as u16).wrapping_sub(1) >> 8) as u8 ^ 0xff;
+        for (x, y) in r_enc.iter_mut().zip(self.rho.iter()) {
+            *x ^= mask & (*x ^ y);
+        }
+        Ok(hash_session(1 - (mask & 1), &r_enc, c))
+    }
+}
+
+// Returns the ciphertext and the shared secret
+pub fn encapsulate(pk: &[u8], rng: &mut Rng) -> Result<(Vec<u8>, [u8; SHARED_SECRET_LEN]), ()> {
+    if pk.len() != PUBLIC_KEY_LEN {
+        return Err(());
+    }
+    Ok(encapsulate_with(pk, &short_random(rng)))
+}
+
+fn encapsulate_with(pk: &[u8], r: &[Small]) -> (Vec<u8>, [u8; SHARED_SECRET_LEN]) {
+    let h = rq_decode(pk);
+    let (c, r_enc) = hide(r, &h, &hash_prefix(4, &[pk]));
+    let k = hash_session(1, &r_enc, &c);
+    (c, k)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{OsRng, Rng};
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    #[test]
+    fn round_trip() {
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng);
+        assert_eq!(PUBLIC_KEY_LEN, key.public_key().len());
+        let (c, k) = encapsulate(key.public_key(), &mut rng).unwrap();
+        assert_eq!(CIPHERTEXT_LEN, c.len());
+        assert_eq!(k, key.decapsulate(&c).unwrap());
+    }
+
+    #[test]
+    fn implicit_rejection() {
+        let mut rng = OsRng::new().unwrap();
+        let key = PrivateKey::generate(&mut rng);
+        let (mut c, k) = encapsulate(key.public_key(), &mut rng).unwrap();
+        c[CIPHERTEXT_LEN - 1] ^= 1;
+        assert!(k != key.decapsulate(&c).unwrap());
+        assert!(key.decapsulate(&c[1..]).is_err());
+    }
+
+    #[test]
+    fn r3_inverse() {
+        let mut rng = OsRng::new().unwrap();
+        let g = small_random(&mut rng);
+        if let Some(ginv) = r3_recip(&g) {
+            let one = r3_mult(&g, &ginv);
+            assert_eq!(1, one[0]);
+            assert!(one[1..].iter().all(|&x| x == 0));
+        }
+        assert!(r3_recip(&vec![0; 761]).is_none());
+    }
+
+    #[test]
+    fn short_weight() {
+        let f = short_random(&mut OsRng::new().unwrap());
+        assert_eq!(286, f.iter().filter(|&&x| x != 0).count());
+    }
+
+    #[test]
+    fn encoding() {
+        let mut rng = OsRng::new().unwrap();
+        let h: Vec<i16> = (0..761).map(|_| (rng.gen::<u16>() % 4591) as i16 - 2295).collect();
+        let enc = rq_encode(&h);
+        assert_eq!(PUBLIC_KEY_LEN, enc.len());
+        assert_eq!(h, rq_decode(&enc));
+
+        let mut out = Vec::new();
+        encode(&mut out, &[5, 1000, 3], &[7, 1531, 4]);
+        assert_eq!(vec![5, 1000, 3], decode(&out, &[7, 1531, 4]));
+    }
+
+    // Public key generated by OpenSSH 9.2, which accepted the resulting shared secret
+    #[test]
+    fn interop_vector() {
+        let pk = concat!(
+            "fad6efd844f0c309bf8d1be7b128ccd5df9b416be63a854ebcda259ee9f250ecd22150625cdb836e3fdcccf9257518520e7c8f7e9df35b4c674539d0f0b39da1",
+            "ff99b90dcb35ff6c41276d607b7a9003bb96c238fb34c844b023fb026bc96698c1f9901df2aa62b5235838215a04e29c37bf72fbb4b673ce6111abec3d508a24",
+            "798c42cbe32b01bb171025064f3221839bb1100a8e93b91694dc0c82a4eee881822a92d14b2dd93a4abf2e158a4eca1e0299bc71280e5007dbb576a9857937f5",
+            "092a130e0a86d9242d1f7c1fff07a4e791e52955df119899481db09a4336c207dbce2a22fd1af1fb05fa9ea44cd390cb64c111ab204c03b61a15bf0041cf42fc",
+            "61e862658c682573a531b92c73424507642a89dba9be09cbe426822bb5cd3025a3c3fdd7751f4b103c66c471b31f39052c4ed93654aa88f7996dfeb7085aacbb",
+            "d908fb9ba6c9d66831731ba7654992969968c8274d4356a29f17685e7f5d3ae5ccb477fe41d68cd1b79853484ff074a1ddcc6272958a44bc702cc1852c3ea368",
+            "2684f4bd1bd20298d19fee24e1a1cdf61d160dc3716457de2a7a2e04d1a5dfcd7f719eeaad0fc47f4eacc04d0eefda4193e0b21df5a37eae21890ae3faa2fc4b",
+            "f659653c92d4b67076868c9add35f8fbdc0f4090093c3b2667fc7a2b82b925bd7245354593bb692de34b2ab5f11fa7f6d24f4b2b9366735529a1adf0a7df6b6f",
+            "2b6ed5963619a040404bad7a1c575cb404f5364a36742491bb910d5e709761f755698c7d66980b8d7314ddbb1faed396d2ce27a1c47442e8ce79f1921460cdee",
+            "485c1a47fc6883c735db732b3d171b0bfd2e1382d41c190289d3e65051ae954dfc4a99e4e9e65f9db4090316dc031f4e57ce363baee74cf67b5960946ab46cb7",
+            "491cafd536e43372d1fce9b257ca5b4bafa81c86c41f54f18d4fc3c684b51f2ad39260a787884a47efcbabf97fff66070104f6b25078a2d06d8434a060e5b030",
+            "3c9f7dbc71cefb12355fb9802dd80254f6dc61d4cc71280c0b80fbb077902afafc075e83814beb44ace4965931d747e121a353b79e7ae7507a005150a9058cff",
+            "2e8b3dee14700517409d65d9eb5ca76ceb6f1218ffd72bc5445db073abd4ab039ebf0596b65bfa98d981b6f4d0c77a8b37760a33c7e962945d0a3d6e70f2523b",
+            "92890698488dbbe76ed07da42b80d0cac37ce4b16cf3575ce3571b537d680aa22f789979fc341f7534fbb648a4418f1c3e24fc786dfd06adb65fc377f4b2a294",
+            "cddb7269b2eb6de00cc0a84e2030384009191e24acc6b79660a4b4858c3cebf6a8950e731977a815887678f12cef954de8d85c9164ada52dcc40c8c77dcdd012",
+            "9dbf286b8ee83d93494ca1160d0ca30bed75c732c562eef6fa382edc75b3c2845988f7a8fe25939881b77a6c783bed64ef20c0cece7b2be18ecb9add5740edef",
+            "81273512cb5361ab7bec6bb935f3456a1fde242b1ed308801f1ea0e3e6f24f7da93678020456b9273affe46f40552ba6b4ae7020dd2e4befc4e8f09cae9123dc",
+            "d1ee342083859fd054d2e6adbeeb14736111b724438246018ce0ff5dbf18e73bd4127928fdd19a0b58d07ac789e4216b65cdc57dcbfb0f95234218a9b9950b90",
+            "579b6cd9ba01"
+        ).from_hex().unwrap();
+        let r: Vec<i8> = (0..761).map(|i| match (i % 8 < 3, i % 3 == 0) {
+            (true, true) => 1,
+            (true, false) => -1,
+            _ => 0
+        }).collect();
+        let (c, k) = encapsulate_with(&pk, &r);
+        assert_eq!(CIPHERTEXT_LEN, c.len());
+        assert_eq!("8fda0a9d30a4f04b97cb66346bff989bb9f3cc479c1491c50dd4272fb7f75790", c[ROUNDED_BYTES..].to_hex());
+        assert_eq!("e49df72a7926d391ecc7b06ce3e9a196be4115b5797e20c1097e50ad9fdf206c", k.to_hex());
+    }
+}
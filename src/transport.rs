@@ -1,12 +1,20 @@
 use async::bufreader::AsyncBufReader;
 use async::bufwriter::AsyncBufWriter;
-
-use std::{cmp, io};
+use cipher::{NoCipher, PacketOpener, PacketSealer};
+use compression::{Compressor, Decompressor};
+use packet::{deserialize_msg, serialize_msg};
+use packet::types::{CompressionAlgorithm, DebugMessage, Disconnect, DisconnectReason, Unimplemented};
+
+use std::{cmp, fmt, io};
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::io::{Read, Write};
 
 use futures::{Async, Future, Poll};
 use rand::{Rng, thread_rng};
 
+use ::{SSH_MSG_DISCONNECT, SSH_MSG_IGNORE, SSH_MSG_UNIMPLEMENTED, SSH_MSG_DEBUG, SSH_MSG_USERAUTH_SUCCESS};
+
 pub fn ntoh(buf: &[u8]) -> u32 {
     ((buf[0] as u32) << 24) + ((buf[1] as u32) << 16) + ((buf[2] as u32) << 8) + (buf[3] as u32)
 }
@@ -21,11 +29,31 @@ pub fn hton(n: u32) -> [u8; 4] {
     buf
 }
 
+// RFC 4253 section 6.1 requires handling packets of up to 35000 bytes, including the length field and the MAC
+pub const MIN_MAX_PACKET_LEN: u32 = 35000;
+pub const DEFAULT_MAX_PACKET_LEN: u32 = 256 * 1024;
+
 pub struct PacketWriteRequest {
     pub payload: Vec<u8>,
     pub flush: bool
 }
 
+// Sequence numbers of the next packet in each direction. They wrap around modulo 2^32 and carry over
+// re-keying, except that strict key exchange restarts them at NEWKEYS
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SequenceNumbers {
+    pub read: u32,
+    pub write: u32
+}
+
+// Packet protection and compression for one direction, taking effect after NEWKEYS
+pub struct NewKeys<C> {
+    pub cipher: C,
+    pub compression: CompressionAlgorithm,
+    // Strict key exchange restarts the sequence number from zero
+    pub reset_seq: bool
+}
+
 pub trait AsyncPacketState: Future {
     fn wants_read(&self) -> bool {
         false
@@ -35,18 +63,50 @@ pub trait AsyncPacketState: Future {
         Ok(())
     }
 
+    // Only a query, which may be called any number of times before the packet is taken
     fn write_packet(&self) -> Option<PacketWriteRequest> {
         None
     }
 
+    // Called once the transport takes the packet returned by `write_packet()`. `on_flush()` follows
+    // when it is written
+    fn on_write(&mut self, _req: &PacketWriteRequest) {
+    }
+
     fn on_flush(&mut self) -> Result<(), Self::Error> {
         Ok(())
     }
+
+    // Polled after each `on_flush()`. Returned keys apply to every packet written afterwards
+    fn take_sealer(&mut self) -> Option<NewKeys<Box<PacketSealer>>> {
+        None
+    }
+
+    // Polled after each `on_read()`. Returned keys apply to every packet read afterwards
+    fn take_opener(&mut self) -> Option<NewKeys<Box<PacketOpener>>> {
+        None
+    }
+
+    // Called before each `on_read()` and `poll()` with the current sequence numbers
+    fn set_sequence_numbers(&mut self, _seq: SequenceNumbers) {
+    }
+
+    // Message numbers the state does not know are answered with SSH_MSG_UNIMPLEMENTED
+    fn is_known(&self, _msg_type: u8) -> bool {
+        true
+    }
+
+    // Called for each IGNORE, DEBUG, UNIMPLEMENTED or unknown message the transport handles by itself
+    fn on_transport_msg(&mut self, _msg_type: u8) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
+pub type DebugHandler = Box<FnMut(&DebugMessage) + Send>;
+
 enum PacketReadState {
     Idle,
-    ReadPayload(u32, u8),
+    ReadPacket(u32, Vec<u8>),
 }
 
 impl PacketReadState {
@@ -58,10 +118,11 @@ impl PacketReadState {
     }
 }
 
+// The bool flag is set for packets the transport writes by itself, which the state is not notified of
 enum PacketWriteState {
     Idle,
-    WritePayload(PacketWriteRequest, u32, u8),
-    Flush
+    WritePayload(PacketWriteRequest, u32, u8, bool),
+    Flush(bool)
 }
 
 impl PacketWriteState {
@@ -73,7 +134,171 @@ impl PacketWriteState {
     }
 }
 
-pub struct ClearTransport<R: Read, W: Write, RNG, T>(Option<UnencryptedTransport<R, W, RNG, T>>);
+pub struct PacketStream<R: Read, W: Write> {
+    rd: AsyncBufReader<R>,
+    wr: AsyncBufWriter<W>,
+    opener: Box<PacketOpener>,
+    sealer: Box<PacketSealer>,
+    rd_seq: u32,
+    wr_seq: u32,
+    compressor: Option<Compressor>,
+    decompressor: Option<Decompressor>,
+    // zlib@openssh.com is negotiated but waits for SSH_MSG_USERAUTH_SUCCESS
+    compressor_delayed: bool,
+    decompressor_delayed: bool,
+    authenticated: bool,
+    max_packet_len: u32
+}
+
+impl <R: Read, W: Write> PacketStream<R, W> {
+    pub fn new(rd: AsyncBufReader<R>, wr: AsyncBufWriter<W>) -> PacketStream<R, W> {
+        PacketStream {
+            rd: rd,
+            wr: wr,
+            opener: Box::new(NoCipher),
+            sealer: Box::new(NoCipher),
+            rd_seq: 0,
+            wr_seq: 0,
+            compressor: None,
+            decompressor: None,
+            compressor_delayed: false,
+            decompressor_delayed: false,
+            authenticated: false,
+            max_packet_len: DEFAULT_MAX_PACKET_LEN
+        }
+    }
+
+    // Larger packets from the peer fail with `TransportError::packet_too_large()`
+    pub fn set_max_packet_len(&mut self, len: u32) {
+        self.max_packet_len = cmp::max(len, MIN_MAX_PACKET_LEN);
+    }
+
+    pub fn max_packet_len(&self) -> u32 {
+        self.max_packet_len
+    }
+
+    pub fn sequence_numbers(&self) -> SequenceNumbers {
+        SequenceNumbers {
+            read: self.rd_seq,
+            write: self.wr_seq
+        }
+    }
+
+    pub fn into_inner(self) -> (AsyncBufReader<R>, AsyncBufWriter<W>) {
+        (self.rd, self.wr)
+    }
+
+    fn set_sealer(&mut self, keys: NewKeys<Box<PacketSealer>>) {
+        self.sealer = keys.cipher;
+        if keys.reset_seq {
+            self.wr_seq = 0;
+        }
+        self.compressor_delayed = false;
+        match keys.compression {
+            CompressionAlgorithm::ZLIB => self.enable_compressor(),
+            CompressionAlgorithm::ZLIB_OPENSSH if self.authenticated => self.enable_compressor(),
+            CompressionAlgorithm::ZLIB_OPENSSH => self.compressor_delayed = true,
+            _ => self.compressor = None
+        }
+    }
+
+    fn set_opener(&mut self, keys: NewKeys<Box<PacketOpener>>) {
+        self.opener = keys.cipher;
+        if keys.reset_seq {
+            self.rd_seq = 0;
+        }
+        self.decompressor_delayed = false;
+        match keys.compression {
+            CompressionAlgorithm::ZLIB => self.enable_decompressor(),
+            CompressionAlgorithm::ZLIB_OPENSSH if self.authenticated => self.enable_decompressor(),
+            CompressionAlgorithm::ZLIB_OPENSSH => self.decompressor_delayed = true,
+            _ => self.decompressor = None
+        }
+    }
+
+    // A zlib context lives as long as the algorithm stays negotiated, even across re-keying
+    fn enable_compressor(&mut self) {
+        if self.compressor.is_none() {
+            self.compressor = Some(Compressor::new());
+        }
+    }
+
+    fn enable_decompressor(&mut self) {
+        if self.decompressor.is_none() {
+            self.decompressor = Some(Decompressor::new());
+        }
+    }
+
+    fn on_authenticated(&mut self) {
+        self.authenticated = true;
+        if self.compressor_delayed {
+            self.compressor_delayed = false;
+            self.enable_compressor();
+        }
+        if self.decompressor_delayed {
+            self.decompressor_delayed = false;
+            self.enable_decompressor();
+        }
+    }
+
+    fn compress(&mut self, req: PacketWriteRequest) -> Result<PacketWriteRequest, ()> {
+        let authenticated = req.payload.first() == Some(&SSH_MSG_USERAUTH_SUCCESS);
+        let req = match self.compressor {
+            Some(ref mut compressor) => PacketWriteRequest {
+                payload: try!(compressor.compress(&req.payload)),
+                flush: req.flush
+            },
+            None => req
+        };
+
+        // Delayed compression starts from the packet after SSH_MSG_USERAUTH_SUCCESS
+        if authenticated {
+            self.on_authenticated();
+        }
+        Ok(req)
+    }
+
+    // Seals one packet into the write buffer. The whole packet is buffered at once or not at all
+    fn nb_write_packet(&mut self, req: &PacketWriteRequest, pkt_len: u32, pad_len: u8) -> Poll<(), io::Error> {
+        let seq = self.wr_seq;
+        let tag_len = self.sealer.tag_len();
+        let async_res = {
+            let sealer = &mut self.sealer;
+            try!(self.wr.nb_write(pkt_len as usize + 4 + tag_len, |buf| {
+                buf[0] = ((pkt_len >> 24) & 0xff) as u8;
+                buf[1] = ((pkt_len >> 16) & 0xff) as u8;
+                buf[2] = ((pkt_len >> 8) & 0xff) as u8;
+                buf[3] = (pkt_len & 0xff) as u8;
+                buf[4] = pad_len;
+                buf[5 .. 5 + req.payload.len()].copy_from_slice(&req.payload);
+
+                let mut rng = thread_rng();
+                rng.fill_bytes(&mut buf[5 + req.payload.len() .. pkt_len as usize + 4]);
+
+                sealer.seal(seq, buf);
+            }))
+        };
+
+        if let Async::Ready(()) = async_res {
+            self.wr_seq = seq.wrapping_add(1);
+        }
+        Ok(async_res)
+    }
+
+    fn decompress<'a>(&mut self, payload: &'a [u8]) -> Result<Cow<'a, [u8]>, ()> {
+        let msg = match self.decompressor {
+            Some(ref mut decompressor) => Cow::Owned(try!(decompressor.decompress(payload))),
+            None => Cow::Borrowed(payload)
+        };
+
+        if msg.first() == Some(&SSH_MSG_USERAUTH_SUCCESS) {
+            self.on_authenticated();
+        }
+        Ok(msg)
+    }
+}
+
+pub struct ClearTransport<R: Read, W: Write, RNG, T>(Option<PacketTransport<R, W, RNG, T>>);
 
 impl <R: Read, W: Write, RNG, T> ClearTransport<R, W, RNG, T> {
     pub fn new(rd: AsyncBufReader<R>,
@@ -81,39 +306,150 @@ impl <R: Read, W: Write, RNG, T> ClearTransport<R, W, RNG, T> {
                rng: RNG,
                st: T) -> ClearTransport<R, W, RNG, T>
     {
-        ClearTransport(Some(UnencryptedTransport::new(rd, wr, rng, st)))
+        ClearTransport(Some(PacketTransport::new(PacketStream::new(rd, wr), rng, st)))
+    }
+
+    pub fn state_mut(&mut self) -> &mut T {
+        match self.0 {
+            Some(ref mut inner) => &mut inner.st,
+            None => panic!("ClearTransport already finished")
+        }
+    }
+
+    pub fn set_debug_handler<F: FnMut(&DebugMessage) + Send + 'static>(&mut self, handler: F) {
+        if let Some(ref mut inner) = self.0 {
+            inner.debug_handler = Some(Box::new(handler));
+        }
+    }
+
+    // Sends SSH_MSG_DISCONNECT after the packet being written, then fails with `TransportError::closed()`
+    pub fn disconnect(&mut self, reason: DisconnectReason, description: &str) {
+        if let Some(ref mut inner) = self.0 {
+            inner.disconnect(reason, description);
+        }
+    }
+
+    pub fn set_max_packet_len(&mut self, len: u32) {
+        if let Some(ref mut inner) = self.0 {
+            inner.io.set_max_packet_len(len);
+        }
+    }
+
+    pub fn sequence_numbers(&self) -> SequenceNumbers {
+        match self.0 {
+            Some(ref inner) => inner.io.sequence_numbers(),
+            None => panic!("ClearTransport already finished")
+        }
     }
 }
 
-struct UnencryptedTransport<R: Read, W: Write, RNG, T> {
-    rd: AsyncBufReader<R>,
+pub struct EncryptedTransport<R: Read, W: Write, RNG, T>(Option<PacketTransport<R, W, RNG, T>>);
+
+impl <R: Read, W: Write, RNG, T> EncryptedTransport<R, W, RNG, T> {
+    pub fn new(io: PacketStream<R, W>,
+               rng: RNG,
+               st: T) -> EncryptedTransport<R, W, RNG, T>
+    {
+        EncryptedTransport(Some(PacketTransport::new(io, rng, st)))
+    }
+
+    pub fn state_mut(&mut self) -> &mut T {
+        match self.0 {
+            Some(ref mut inner) => &mut inner.st,
+            None => panic!("EncryptedTransport already finished")
+        }
+    }
+
+    pub fn set_debug_handler<F: FnMut(&DebugMessage) + Send + 'static>(&mut self, handler: F) {
+        if let Some(ref mut inner) = self.0 {
+            inner.debug_handler = Some(Box::new(handler));
+        }
+    }
+
+    // Sends SSH_MSG_DISCONNECT after the packet being written, then fails with `TransportError::closed()`
+    pub fn disconnect(&mut self, reason: DisconnectReason, description: &str) {
+        if let Some(ref mut inner) = self.0 {
+            inner.disconnect(reason, description);
+        }
+    }
+
+    pub fn set_max_packet_len(&mut self, len: u32) {
+        if let Some(ref mut inner) = self.0 {
+            inner.io.set_max_packet_len(len);
+        }
+    }
+
+    pub fn sequence_numbers(&self) -> SequenceNumbers {
+        match self.0 {
+            Some(ref inner) => inner.io.sequence_numbers(),
+            None => panic!("EncryptedTransport already finished")
+        }
+    }
+}
+
+struct PacketTransport<R: Read, W: Write, RNG, T> {
+    io: PacketStream<R, W>,
     rd_st: PacketReadState,
-    wr: AsyncBufWriter<W>,
     wr_st: PacketWriteState,
     rng: RNG,
     st: T,
+    // Packets the transport answers with by itself, e.g. SSH_MSG_UNIMPLEMENTED
+    replies: VecDeque<PacketWriteRequest>,
+    debug_handler: Option<DebugHandler>,
+    // Set once SSH_MSG_DISCONNECT is queued. Nothing else is read or written afterwards
+    closing: Option<DisconnectReason>
 }
 
-impl <R: Read, W: Write, RNG, T> UnencryptedTransport<R, W, RNG, T> {
-    fn new(rd: AsyncBufReader<R>,
-           wr: AsyncBufWriter<W>,
+impl <R: Read, W: Write, RNG, T> PacketTransport<R, W, RNG, T> {
+    fn new(io: PacketStream<R, W>,
            rng: RNG,
-           st: T) -> UnencryptedTransport<R, W, RNG, T>
+           st: T) -> PacketTransport<R, W, RNG, T>
     {
-        UnencryptedTransport {
-            rd: rd,
+        PacketTransport {
+            io: io,
             rd_st: PacketReadState::Idle,
-            wr: wr,
             wr_st: PacketWriteState::Idle,
             rng: rng,
-            st: st
+            st: st,
+            replies: VecDeque::new(),
+            debug_handler: None,
+            closing: None
+        }
+    }
+
+    fn disconnect(&mut self, reason: DisconnectReason, description: &str) {
+        if self.closing.is_none() {
+            self.replies.push_back(disconnect_request(reason, description));
+            self.closing = Some(reason);
         }
     }
 }
 
-pub trait TransportError : From<io::Error> + From<()> {
+fn disconnect_request(reason: DisconnectReason, description: &str) -> PacketWriteRequest {
+    let msg = Disconnect {
+        reason_code: reason.code(),
+        description: description.to_string(),
+        language: String::new()
+    };
+    PacketWriteRequest {
+        payload: serialize_msg(SSH_MSG_DISCONNECT, &msg).unwrap(),
+        flush: true
+    }
+}
+
+pub trait TransportError : From<io::Error> + From<()> + fmt::Display {
     fn invalid_header() -> Self;
+    fn invalid_mac() -> Self;
+    fn compression_error() -> Self;
+    fn packet_too_large(pkt_len: u32) -> Self;
+    fn invalid_message(msg_type: u8) -> Self;
+    fn disconnected(reason: DisconnectReason, description: String) -> Self;
+    // The local side sent SSH_MSG_DISCONNECT through `disconnect()`
+    fn closed(reason: DisconnectReason) -> Self;
     fn panic(&'static str) -> Self;
+
+    // The reason sent to the peer before closing, or None if no SSH_MSG_DISCONNECT should be sent
+    fn disconnect_reason(&self) -> Option<DisconnectReason>;
 }
 
 macro_rules! try_add {
@@ -126,45 +462,97 @@ macro_rules! try_add {
     }
 }
 
-macro_rules! try_sub {
-    ($a:expr, $b:expr) => {
-        if let Some(x) = $a.checked_sub($b) {
-            x
-        } else {
-            return Err(().into());
-        }
+// Everything after the first aad_len bytes is a multiple of the cipher block size
+fn is_aligned(pkt_len: u32, blk_size: usize, aad_len: usize) -> bool {
+    let min_unit = cmp::max(blk_size, 8);
+    match (pkt_len as usize + 4).checked_sub(aad_len) {
+        Some(len) => len % min_unit == 0,
+        None => false
     }
 }
 
-pub fn compute_pad_len<R: Rng>(payload_len: usize, blk_size: usize, rng: &mut R) -> Result<(u32, u8), ()> {
+pub fn compute_pad_len<R: Rng>(payload_len: usize, blk_size: usize, aad_len: usize, rng: &mut R) -> Result<(u32, u8), ()> {
     let min_unit = cmp::max(blk_size, 8);
+    if aad_len > min_unit {
+        return Err(());
+    }
 
-    // maximum possible pkt_len = 5 byte header + payload_len + 255
-    let pkt_upperbound = cmp::min(try_add!(payload_len, 5 + 255), try_add!(::std::u32::MAX as usize, 4));
-
-    // pkt_len = 5 byte header + payload_len + pad_len
-    // pad_len must be 4 bytes or larger
-    // pkt_len must be 16 bytes or larger, and it must be multiple of max(blk_size, 8)
-    let pkt_lowerbound = cmp::max(try_add!(payload_len, 5 + 4), cmp::max(min_unit, 16));
+    // 5 byte header + payload_len, of which the first aad_len bytes are excluded from the block alignment
+    let except_pad = try_add!(payload_len, 5);
+    let misalign = (try_add!(except_pad, min_unit) - aad_len) % min_unit;
 
-    let max_pkt_len = pkt_upperbound - (pkt_upperbound % min_unit);
-    let min_pkt_len = try_add!(pkt_lowerbound, min_unit - 1) / min_unit * min_unit;
+    // pad_len must be 4 bytes or larger, and the packet must be 16 bytes or larger
+    let mut min_pad_len = (min_unit - misalign) % min_unit;
+    while min_pad_len < 4 || except_pad + min_pad_len < 16 {
+        min_pad_len += min_unit;
+    }
 
-    let except_pad = try_add!(payload_len, 5);
-    let max_pad_len = try_sub!(max_pkt_len, except_pad);
-    let min_pad_len = try_sub!(min_pkt_len, except_pad);
+    if min_pad_len > 255 {
+        return Err(());
+    }
 
-    if 4 <= min_pad_len && min_pad_len <= max_pad_len && max_pad_len <= 255 {
-        let pad_len = 
-            rng.gen_range(0, (max_pad_len - min_pad_len) / min_unit + 1) * min_unit + min_pad_len;
-        let pkt_len = try_add!(pad_len, except_pad) - 4;
-        Ok((pkt_len as u32, pad_len as u8))
-    } else {
+    let pad_len = rng.gen_range(0, (255 - min_pad_len) / min_unit + 1) * min_unit + min_pad_len;
+    let pkt_len = try_add!(pad_len, except_pad) - 4;
+    if pkt_len > ::std::u32::MAX as usize {
         Err(())
+    } else {
+        Ok((pkt_len as u32, pad_len as u8))
+    }
+}
+
+fn flushed<R: Read, W: Write, T: AsyncPacketState>(st: &mut T, io: &mut PacketStream<R, W>) -> Result<(), T::Error> {
+    try!(st.on_flush());
+    if let Some(keys) = st.take_sealer() {
+        io.set_sealer(keys);
     }
+    Ok(())
 }
 
-impl <R, W, RNG, T> UnencryptedTransport<R, W, RNG, T>
+// Handles the generic messages of RFC 4253 section 11. Returns true if the state should read the message.
+fn handle_transport_msg<T>(st: &mut T,
+                           replies: &mut VecDeque<PacketWriteRequest>,
+                           debug_handler: &mut Option<DebugHandler>,
+                           seq: u32,
+                           msg: &[u8]) -> Result<bool, T::Error>
+    where T: AsyncPacketState, T::Error: TransportError
+{
+    let msg_type = match msg.first() {
+        Some(&t) => t,
+        None => return Err(T::Error::invalid_message(0))
+    };
+
+    match msg_type {
+        SSH_MSG_DISCONNECT => match deserialize_msg::<Disconnect>(msg) {
+            Ok((_, disconnect)) => Err(T::Error::disconnected(disconnect.reason_code.into(), disconnect.description)),
+            Err(_) => Err(T::Error::invalid_message(msg_type))
+        },
+        SSH_MSG_IGNORE | SSH_MSG_UNIMPLEMENTED => {
+            try!(st.on_transport_msg(msg_type));
+            Ok(false)
+        },
+        SSH_MSG_DEBUG => {
+            try!(st.on_transport_msg(msg_type));
+            match deserialize_msg::<DebugMessage>(msg) {
+                Ok((_, debug)) => if let Some(ref mut handler) = *debug_handler {
+                    handler(&debug);
+                },
+                Err(_) => return Err(T::Error::invalid_message(msg_type))
+            }
+            Ok(false)
+        },
+        _ if !st.is_known(msg_type) => {
+            try!(st.on_transport_msg(msg_type));
+            replies.push_back(PacketWriteRequest {
+                payload: serialize_msg(SSH_MSG_UNIMPLEMENTED, &Unimplemented { seq: seq }).unwrap(),
+                flush: true
+            });
+            Ok(false)
+        },
+        _ => Ok(true)
+    }
+}
+
+impl <R, W, RNG, T> PacketTransport<R, W, RNG, T>
     where R: Read, W: Write, RNG: Rng, T: AsyncPacketState, T::Error: TransportError
 {
     fn try_write(&mut self) -> Result<bool, T::Error> {
@@ -172,44 +560,52 @@ impl <R, W, RNG, T> UnencryptedTransport<R, W, RNG, T>
         loop {
             let next_state = match self.wr_st {
                 PacketWriteState::Idle => {
-                    if let Some(req) = self.st.write_packet() {
-                        let (pkt_len, pad_len) = try!(compute_pad_len(req.payload.len(), 0, &mut self.rng));
-                        PacketWriteState::WritePayload(req, pkt_len, pad_len)
+                    let next = match self.replies.pop_front() {
+                        Some(req) => Some((req, true)),
+                        None if self.closing.is_some() => None,
+                        None => self.st.write_packet().map(|req| (req, false))
+                    };
+                    if let Some((req, own)) = next {
+                        if !own {
+                            self.st.on_write(&req);
+                        }
+                        let req = match self.io.compress(req) {
+                            Ok(req) => req,
+                            Err(_) => return Err(T::Error::compression_error())
+                        };
+                        let blk_size = self.io.sealer.block_size();
+                        let aad_len = self.io.sealer.aad_len();
+                        let (pkt_len, pad_len) = try!(compute_pad_len(req.payload.len(), blk_size, aad_len, &mut self.rng));
+                        PacketWriteState::WritePayload(req, pkt_len, pad_len, own)
                     } else {
                         return Ok(callback_called);
                     }
                 },
-                PacketWriteState::WritePayload(ref req, pkt_len, pad_len) => {
+                PacketWriteState::WritePayload(ref req, pkt_len, pad_len, own) => {
                     if pkt_len as usize != req.payload.len() + 1 + pad_len as usize {
                         return Err(T::Error::panic("pkt_len does not match"));
                     }
 
-                    let async_res = try!(self.wr.nb_write(pkt_len as usize + 4, |buf| {
-                        buf[0] = ((pkt_len >> 24) & 0xff) as u8;
-                        buf[1] = ((pkt_len >> 16) & 0xff) as u8;
-                        buf[2] = ((pkt_len >> 8) & 0xff) as u8;
-                        buf[3] = (pkt_len & 0xff) as u8;
-                        buf[4] = pad_len;
-                        buf[5 .. 5 + req.payload.len()].copy_from_slice(&req.payload);
-
-                        let mut rng = thread_rng();
-                        rng.fill_bytes(&mut buf[5 + req.payload.len() ..]);
-                    }));
-
-                    if let Async::NotReady = async_res {
+                    if let Async::NotReady = try!(self.io.nb_write_packet(req, pkt_len, pad_len)) {
                         return Ok(callback_called);
-                    } else if req.flush {
-                        PacketWriteState::Flush
+                    }
+
+                    if req.flush {
+                        PacketWriteState::Flush(own)
                     } else {
                         callback_called = true;
-                        try!(self.st.on_flush());
+                        if !own {
+                            try!(flushed(&mut self.st, &mut self.io));
+                        }
                         PacketWriteState::Idle
                     }
                 },
-                PacketWriteState::Flush => {
-                    if let Async::Ready(()) = try!(self.wr.nb_flush()) {
+                PacketWriteState::Flush(own) => {
+                    if let Async::Ready(()) = try!(self.io.wr.nb_flush()) {
                         callback_called = true;
-                        try!(self.st.on_flush());
+                        if !own {
+                            try!(flushed(&mut self.st, &mut self.io));
+                        }
                         PacketWriteState::Idle
                     } else {
                         return Ok(callback_called);
@@ -231,26 +627,58 @@ impl <R, W, RNG, T> UnencryptedTransport<R, W, RNG, T>
                         return Ok(callback_called);
                     }
 
-                    if let Async::Ready(buf) = try!(self.rd.nb_read_exact(5)) {
-                        let pkt_len = ntoh(&buf[.. 4]);
-                        let pad_len = buf[4];
-                        if pkt_len < 12 || pkt_len < (pad_len as u32) + 1 {
+                    let header_len = self.io.opener.header_len();
+                    if let Async::Ready(buf) = try!(self.io.rd.nb_read_exact(header_len)) {
+                        let mut pkt = buf.to_vec();
+                        let pkt_len = self.io.opener.decrypt_length(self.io.rd_seq, &mut pkt);
+                        // Checked before anything is buffered for the rest of the packet
+                        let total_len = pkt_len as u64 + 4 + self.io.opener.tag_len() as u64;
+                        if total_len > self.io.max_packet_len as u64 {
+                            return Err(T::Error::packet_too_large(pkt_len));
+                        }
+                        let blk_size = self.io.opener.block_size();
+                        let aad_len = self.io.opener.aad_len();
+                        if pkt_len < 12 || (pkt_len as usize) + 4 < header_len || !is_aligned(pkt_len, blk_size, aad_len) {
                             return Err(T::Error::invalid_header());
                         }
-                        PacketReadState::ReadPayload(pkt_len, pad_len)
+                        PacketReadState::ReadPacket(pkt_len, pkt)
                     } else {
                         return Ok(callback_called);
                     }
                 },
-                PacketReadState::ReadPayload(pkt_len, pad_len) => {
-                    if let Async::Ready(buf) = try!(self.rd.nb_read_exact(pkt_len as usize - 1)) {
-                        let payload_len = pkt_len as usize - pad_len as usize - 1;
-                        try!(self.st.on_read(&buf[..payload_len]));
-                        callback_called = true;
-                        PacketReadState::Idle
+                PacketReadState::ReadPacket(pkt_len, ref mut pkt) => {
+                    let total_len = pkt_len as usize + 4 + self.io.opener.tag_len();
+                    if let Async::Ready(buf) = try!(self.io.rd.nb_read_exact(total_len - pkt.len())) {
+                        pkt.extend_from_slice(buf);
                     } else {
                         return Ok(callback_called);
                     }
+
+                    let seq = self.io.rd_seq;
+                    if self.io.opener.open(seq, pkt).is_err() {
+                        return Err(T::Error::invalid_mac());
+                    }
+                    self.io.rd_seq = seq.wrapping_add(1);
+
+                    let pad_len = pkt[4];
+                    if pad_len < 4 || pkt_len < (pad_len as u32) + 1 {
+                        return Err(T::Error::invalid_header());
+                    }
+
+                    let payload_len = pkt_len as usize - pad_len as usize - 1;
+                    let msg = match self.io.decompress(&pkt[5 .. 5 + payload_len]) {
+                        Ok(msg) => msg,
+                        Err(_) => return Err(T::Error::compression_error())
+                    };
+                    callback_called = true;
+                    self.st.set_sequence_numbers(self.io.sequence_numbers());
+                    if try!(handle_transport_msg(&mut self.st, &mut self.replies, &mut self.debug_handler, seq, &msg)) {
+                        try!(self.st.on_read(&msg));
+                        if let Some(keys) = self.st.take_opener() {
+                            self.io.set_opener(keys);
+                        }
+                    }
+                    PacketReadState::Idle
                 }
             };
 
@@ -259,21 +687,28 @@ impl <R, W, RNG, T> UnencryptedTransport<R, W, RNG, T>
     }
 }
 
-impl <R, W, RNG, T, V, E> Future for UnencryptedTransport<R, W, RNG, T>
-    where R: Read, W: Write, RNG: Rng, T: AsyncPacketState + Future<Item=V, Error=E>, E: TransportError
+impl <R, W, RNG, T> PacketTransport<R, W, RNG, T>
+    where R: Read, W: Write, RNG: Rng, T: AsyncPacketState, T::Error: TransportError
 {
-    type Item = V;
-    type Error = E;
+    fn poll_packets(&mut self) -> Poll<T::Item, T::Error> {
+        if let Some(reason) = self.closing {
+            try!(self.try_write());
+            if self.wr_st.is_idle() && self.replies.is_empty() {
+                return Err(T::Error::closed(reason));
+            }
+            return Ok(Async::NotReady);
+        }
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
             let w = try!(self.try_write());
             let r = try!(self.try_read());
 
             if self.wr_st.is_idle() && self.rd_st.is_idle() {
+                self.st.set_sequence_numbers(self.io.sequence_numbers());
                 match try!(self.st.poll()) {
                     Async::Ready(x) => return Ok(Async::Ready(x)),
-                    Async::NotReady => if !w && !r {
+                    // poll() itself may queue a packet, e.g. when it starts a key exchange
+                    Async::NotReady => if !w && !r && self.replies.is_empty() && self.st.write_packet().is_none() {
                         return Ok(Async::NotReady);
                     }
                 }
@@ -282,12 +717,53 @@ impl <R, W, RNG, T, V, E> Future for UnencryptedTransport<R, W, RNG, T>
             }
         }
     }
+
+    // Best effort on a fatal error: the packet goes after whatever is buffered, and is flushed without waiting
+    fn send_disconnect(&mut self, reason: DisconnectReason, description: &str) {
+        if self.closing.is_some() {
+            return;
+        }
+        self.closing = Some(reason);
+
+        let req = match self.io.compress(disconnect_request(reason, description)) {
+            Ok(req) => req,
+            Err(_) => return
+        };
+        let blk_size = self.io.sealer.block_size();
+        let aad_len = self.io.sealer.aad_len();
+        let (pkt_len, pad_len) = match compute_pad_len(req.payload.len(), blk_size, aad_len, &mut self.rng) {
+            Ok(lens) => lens,
+            Err(_) => return
+        };
+        if let Ok(Async::Ready(())) = self.io.nb_write_packet(&req, pkt_len, pad_len) {
+            let _ = self.io.wr.nb_flush();
+        }
+    }
+}
+
+impl <R, W, RNG, T, V, E> Future for PacketTransport<R, W, RNG, T>
+    where R: Read, W: Write, RNG: Rng, T: AsyncPacketState + Future<Item=V, Error=E>, E: TransportError
+{
+    type Item = V;
+    type Error = E;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.poll_packets() {
+            Err(e) => {
+                if let Some(reason) = e.disconnect_reason() {
+                    self.send_disconnect(reason, &e.to_string());
+                }
+                Err(e)
+            },
+            res => res
+        }
+    }
 }
 
 impl <R, W, RNG, T, V, E> Future for ClearTransport<R, W, RNG, T>
     where R: Read, W: Write, RNG: Rng, T: AsyncPacketState + Future<Item=V, Error=E>, E: TransportError
 {
-    type Item = (AsyncBufReader<R>, AsyncBufWriter<W>, V);
+    type Item = (PacketStream<R, W>, V);
     type Error = E;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -300,20 +776,71 @@ impl <R, W, RNG, T, V, E> Future for ClearTransport<R, W, RNG, T>
         };
 
         let inner = self.0.take().unwrap();
-        Ok(Async::Ready((inner.rd, inner.wr, retval)))
+        Ok(Async::Ready((inner.io, retval)))
+    }
+}
+
+impl <R, W, RNG, T, V, E> Future for EncryptedTransport<R, W, RNG, T>
+    where R: Read, W: Write, RNG: Rng, T: AsyncPacketState + Future<Item=V, Error=E>, E: TransportError
+{
+    type Item = (PacketStream<R, W>, V);
+    type Error = E;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let retval = match *self {
+            EncryptedTransport(Some(ref mut inner)) => match try!(inner.poll()) {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(x) => x
+            },
+            _ => panic!("Called the same EncryptedTransport twice")
+        };
+
+        let inner = self.0.take().unwrap();
+        Ok(Async::Ready((inner.io, retval)))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use handshake::HandshakeError;
+    use packet::{deserialize_msg, serialize_msg};
+    use packet::types::{DebugMessage, Disconnect, DisconnectReason, Unimplemented};
     use rand::thread_rng;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use futures::{Async, Future, Poll};
+
+    use ::{SSH_MSG_DEBUG, SSH_MSG_DISCONNECT, SSH_MSG_IGNORE, SSH_MSG_UNIMPLEMENTED};
+
+    struct KnownOnly(u8);
+
+    impl Future for KnownOnly {
+        type Item = ();
+        type Error = HandshakeError;
+
+        fn poll(&mut self) -> Poll<(), HandshakeError> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl AsyncPacketState for KnownOnly {
+        fn wants_read(&self) -> bool {
+            true
+        }
+
+        fn is_known(&self, msg_type: u8) -> bool {
+            msg_type == self.0
+        }
+    }
 
     #[test]
     fn test_compute_pad_len() {
         let mut rng = thread_rng();
         for payload_len in 1 .. 257 {
-            if let Ok((pkt_len, pad_len)) = compute_pad_len(payload_len, 0, &mut rng) {
+            if let Ok((pkt_len, pad_len)) = compute_pad_len(payload_len, 0, 0, &mut rng) {
                 assert!(pkt_len >= 12);
                 assert_eq!(pkt_len % 8, 4);
                 assert_eq!(pkt_len as usize, pad_len as usize + payload_len + 1);
@@ -322,4 +849,118 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_compute_pad_len_with_aad() {
+        let mut rng = thread_rng();
+        for payload_len in 1 .. 257 {
+            if let Ok((pkt_len, pad_len)) = compute_pad_len(payload_len, 16, 4, &mut rng) {
+                assert!(pkt_len >= 16);
+                assert!(pad_len >= 4);
+                assert_eq!(pkt_len % 16, 0);
+                assert_eq!(pkt_len as usize, pad_len as usize + payload_len + 1);
+            } else {
+                panic!("compute_pad_len failed at payload_len = {}", payload_len);
+            }
+        }
+    }
+
+    #[test]
+    fn transport_msg_ignore() {
+        let mut replies = VecDeque::new();
+        let res = handle_transport_msg(&mut KnownOnly(50), &mut replies, &mut None, 3, &[SSH_MSG_IGNORE, 0, 0, 0, 0]);
+        assert_eq!(Some(false), res.ok());
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn transport_msg_known() {
+        let mut replies = VecDeque::new();
+        let res = handle_transport_msg(&mut KnownOnly(50), &mut replies, &mut None, 3, &[50]);
+        assert_eq!(Some(true), res.ok());
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn transport_msg_unimplemented() {
+        let mut replies = VecDeque::new();
+        let res = handle_transport_msg(&mut KnownOnly(50), &mut replies, &mut None, 7, &[60]);
+        assert_eq!(Some(false), res.ok());
+        assert_eq!(1, replies.len());
+        let (msg_type, reply) = deserialize_msg::<Unimplemented>(&replies[0].payload).unwrap();
+        assert_eq!(SSH_MSG_UNIMPLEMENTED, msg_type);
+        assert_eq!(7, reply.seq);
+    }
+
+    #[test]
+    fn transport_msg_disconnect() {
+        let msg = serialize_msg(SSH_MSG_DISCONNECT, &Disconnect {
+            reason_code: 11,
+            description: "bye".to_string(),
+            language: "".to_string()
+        }).unwrap();
+        match handle_transport_msg(&mut KnownOnly(50), &mut VecDeque::new(), &mut None, 0, &msg) {
+            Err(HandshakeError::Disconnected(DisconnectReason::ByApplication, ref description)) => assert_eq!("bye", description),
+            res => panic!("unexpected result: {:?}", res)
+        }
+    }
+
+    #[test]
+    fn debug_handler_called() {
+        let payload = serialize_msg(SSH_MSG_DEBUG, &DebugMessage {
+            always_display: true,
+            message: "hello".to_string(),
+            language: "".to_string()
+        }).unwrap();
+        let (pkt_len, pad_len) = compute_pad_len(payload.len(), 0, 0, &mut thread_rng()).unwrap();
+        let mut pkt = hton(pkt_len).to_vec();
+        pkt.push(pad_len);
+        pkt.extend_from_slice(&payload);
+        pkt.extend_from_slice(&vec![0u8; pad_len as usize]);
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let handler_messages = messages.clone();
+        let mut transport = ClearTransport::new(AsyncBufReader::new(Cursor::new(pkt)),
+                                                AsyncBufWriter::new(io::sink()),
+                                                thread_rng(),
+                                                KnownOnly(50));
+        transport.set_debug_handler(move |debug: &DebugMessage| {
+            handler_messages.lock().unwrap().push((debug.always_display, debug.message.clone()));
+        });
+
+        // The read after the packet hits the end of the input
+        assert!(transport.poll().is_err());
+        assert_eq!(vec![(true, "hello".to_string())], *messages.lock().unwrap());
+    }
+
+    #[test]
+    fn disconnect_reason_code() {
+        let req = disconnect_request(DisconnectReason::HostKeyNotVerifiable, "ServerKeyNotVerified");
+        let (msg_type, msg) = deserialize_msg::<Disconnect>(&req.payload).unwrap();
+        assert_eq!(SSH_MSG_DISCONNECT, msg_type);
+        assert_eq!(9, msg.reason_code);
+        assert_eq!(DisconnectReason::HostKeyNotVerifiable, msg.reason_code.into());
+        assert_eq!(DisconnectReason::Unknown(99), DisconnectReason::from(99));
+        assert!(req.flush);
+    }
+
+    #[test]
+    fn packet_alignment() {
+        assert!(is_aligned(12, 8, 0));
+        assert!(!is_aligned(16, 8, 0));
+        assert!(is_aligned(16, 16, 4));
+        assert!(!is_aligned(28, 16, 4));
+        assert!(is_aligned(32, 8, 4));
+        assert!(!is_aligned(36, 16, 0));
+    }
+
+    #[test]
+    fn max_packet_len_floor() {
+        let mut io = PacketStream::new(AsyncBufReader::new(io::empty()), AsyncBufWriter::new(io::sink()));
+        assert_eq!(DEFAULT_MAX_PACKET_LEN, io.max_packet_len());
+        io.set_max_packet_len(1024);
+        assert_eq!(MIN_MAX_PACKET_LEN, io.max_packet_len());
+        io.set_max_packet_len(1 << 20);
+        assert_eq!(1 << 20, io.max_packet_len());
+    }
 }